@@ -1,12 +1,34 @@
 use actix_web::web;
 
+mod admin;
+mod audit;
+mod cache;
 mod file;
+mod health;
+mod metrics;
 mod report;
+mod usage;
 
 pub fn init(cfg: &mut web::ServiceConfig) {
     cfg.service(file::upload);
     cfg.service(file::upload_image);
+    cfg.service(file::upload_dataset);
+    cfg.service(file::get_image);
+    cfg.service(file::get_legacy_image);
     cfg.service(report::site_to_pdf);
     cfg.service(report::process_report);
+    cfg.service(report::sign_report_url);
     cfg.service(report::get_report);
+    cfg.service(cache::invalidate_cache);
+    cfg.service(usage::get_usage);
+    cfg.service(admin::create_api_key);
+    cfg.service(admin::list_api_keys);
+    cfg.service(admin::revoke_api_key);
+    cfg.service(admin::save_tenant);
+    cfg.service(admin::list_tenants);
+    cfg.service(audit::list_audit_log);
+    cfg.service(audit::export_audit_log);
+    cfg.service(metrics::get_metrics);
+    cfg.service(health::healthz);
+    cfg.service(health::readyz);
 }
@@ -0,0 +1,242 @@
+use actix_web::{delete, get, post, web, HttpResponse};
+use chrono::{DateTime, Utc};
+use deadpool_postgres::Pool;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::utils::{
+    auth::{generate_api_key, hash_api_key, Principal, ALL_SCOPES, SCOPE_ADMIN},
+    common_struct::{BaseResponse, DataResponse},
+    error::AppError,
+    tenant::{get_tenant, is_valid_tenant_id, Tenant},
+};
+
+#[derive(Deserialize)]
+pub struct CreateApiKeyRequest {
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub tenant_id: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ApiKeyInfo {
+    pub id: Uuid,
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub tenant_id: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+pub struct CreatedApiKey {
+    pub id: Uuid,
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub tenant_id: Option<String>,
+    // Only returned once; the database keeps a hash of it
+    pub key: String,
+}
+
+#[post("/api/admin/api-keys")]
+pub async fn create_api_key(
+    principal: web::ReqData<Principal>,
+    body: web::Json<CreateApiKeyRequest>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, AppError> {
+    principal.require_scope(SCOPE_ADMIN)?;
+
+    if let Some(scope) = body
+        .scopes
+        .iter()
+        .find(|scope| !ALL_SCOPES.contains(&scope.as_str()))
+    {
+        return Err(AppError::InvalidRequest(format!(
+            "Unknown scope: {}",
+            scope
+        )));
+    }
+    if let Some(tenant_id) = &body.tenant_id {
+        let tenant = get_tenant(&pool, tenant_id)
+            .await
+            .map_err(AppError::internal)?;
+        if tenant.is_none() {
+            return Err(AppError::InvalidRequest(format!(
+                "Unknown tenant: {}",
+                tenant_id
+            )));
+        }
+    }
+
+    let id = Uuid::new_v4();
+    let key = generate_api_key();
+    let client = pool.get().await.map_err(AppError::internal)?;
+    client
+        .execute(
+            "INSERT INTO api_keys (id, name, key_hash, scopes, tenant_id)
+             VALUES ($1, $2, $3, $4, $5)",
+            &[
+                &id,
+                &body.name,
+                &hash_api_key(&key),
+                &body.scopes,
+                &body.tenant_id,
+            ],
+        )
+        .await
+        .map_err(AppError::internal)?;
+
+    Ok(HttpResponse::Ok().json(DataResponse {
+        code: 200,
+        message: String::from("API key created"),
+        data: Some(CreatedApiKey {
+            id,
+            name: body.name.clone(),
+            scopes: body.scopes.clone(),
+            tenant_id: body.tenant_id.clone(),
+            key,
+        }),
+    }))
+}
+
+#[get("/api/admin/api-keys")]
+pub async fn list_api_keys(
+    principal: web::ReqData<Principal>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, AppError> {
+    principal.require_scope(SCOPE_ADMIN)?;
+
+    let client = pool.get().await.map_err(AppError::internal)?;
+    let rows = client
+        .query(
+            "SELECT id, name, scopes, tenant_id, created_at, revoked_at
+             FROM api_keys ORDER BY created_at",
+            &[],
+        )
+        .await
+        .map_err(AppError::internal)?;
+
+    Ok(HttpResponse::Ok().json(DataResponse {
+        code: 200,
+        message: String::from(""),
+        data: Some(
+            rows.iter()
+                .map(|row| ApiKeyInfo {
+                    id: row.get("id"),
+                    name: row.get("name"),
+                    scopes: row.get("scopes"),
+                    tenant_id: row.get("tenant_id"),
+                    created_at: row.get("created_at"),
+                    revoked_at: row.get("revoked_at"),
+                })
+                .collect::<Vec<_>>(),
+        ),
+    }))
+}
+
+#[delete("/api/admin/api-keys/{id}")]
+pub async fn revoke_api_key(
+    principal: web::ReqData<Principal>,
+    path: web::Path<Uuid>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, AppError> {
+    principal.require_scope(SCOPE_ADMIN)?;
+
+    let id = path.into_inner();
+    let client = pool.get().await.map_err(AppError::internal)?;
+    let revoked = client
+        .execute(
+            "UPDATE api_keys SET revoked_at = now() WHERE id = $1 AND revoked_at IS NULL",
+            &[&id],
+        )
+        .await
+        .map_err(AppError::internal)?;
+    if revoked == 0 {
+        return Err(AppError::NotFound(String::from("API key not found!")));
+    }
+
+    Ok(HttpResponse::Ok().json(BaseResponse {
+        code: 200,
+        message: String::from("API key revoked"),
+    }))
+}
+
+#[post("/api/admin/tenants")]
+pub async fn save_tenant(
+    principal: web::ReqData<Principal>,
+    body: web::Json<Tenant>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, AppError> {
+    principal.require_scope(SCOPE_ADMIN)?;
+
+    if !is_valid_tenant_id(&body.id) || !is_valid_tenant_id(&body.storage_prefix) {
+        return Err(AppError::InvalidRequest(String::from(
+            "Tenant id and storage prefix may only contain letters, numbers, '_' and '-'!",
+        )));
+    }
+
+    let client = pool.get().await.map_err(AppError::internal)?;
+    client
+        .execute(
+            "INSERT INTO tenants
+                (id, name, storage_prefix, db_connection, max_templates, max_storage_bytes)
+             VALUES ($1, $2, $3, $4, $5, $6)
+             ON CONFLICT (id) DO UPDATE SET
+                name = EXCLUDED.name,
+                storage_prefix = EXCLUDED.storage_prefix,
+                db_connection = EXCLUDED.db_connection,
+                max_templates = EXCLUDED.max_templates,
+                max_storage_bytes = EXCLUDED.max_storage_bytes",
+            &[
+                &body.id,
+                &body.name,
+                &body.storage_prefix,
+                &body.db_connection,
+                &body.max_templates,
+                &body.max_storage_bytes,
+            ],
+        )
+        .await
+        .map_err(AppError::internal)?;
+
+    Ok(HttpResponse::Ok().json(DataResponse {
+        code: 200,
+        message: String::from("Tenant saved"),
+        data: Some(body.into_inner()),
+    }))
+}
+
+#[get("/api/admin/tenants")]
+pub async fn list_tenants(
+    principal: web::ReqData<Principal>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, AppError> {
+    principal.require_scope(SCOPE_ADMIN)?;
+
+    let client = pool.get().await.map_err(AppError::internal)?;
+    let rows = client
+        .query(
+            "SELECT id, name, storage_prefix, db_connection, max_templates, max_storage_bytes
+             FROM tenants ORDER BY id",
+            &[],
+        )
+        .await
+        .map_err(AppError::internal)?;
+
+    Ok(HttpResponse::Ok().json(DataResponse {
+        code: 200,
+        message: String::from(""),
+        data: Some(
+            rows.iter()
+                .map(|row| Tenant {
+                    id: row.get("id"),
+                    name: row.get("name"),
+                    storage_prefix: row.get("storage_prefix"),
+                    db_connection: row.get("db_connection"),
+                    max_templates: row.get("max_templates"),
+                    max_storage_bytes: row.get("max_storage_bytes"),
+                })
+                .collect::<Vec<_>>(),
+        ),
+    }))
+}
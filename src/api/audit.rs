@@ -0,0 +1,131 @@
+use actix_web::{get, web, HttpResponse};
+use deadpool_postgres::Pool;
+use futures::{channel::mpsc, SinkExt, StreamExt};
+use serde::Deserialize;
+use tokio_postgres::types::ToSql;
+use tracing::error;
+
+use crate::utils::{
+    audit::{AuditEntry, AuditFilter, AUDIT_COLUMNS, FILTERED_AUDIT_LOG},
+    auth::{Principal, SCOPE_ADMIN},
+    common_struct::PaginationResponse,
+    error::AppError,
+};
+
+const MAX_PER_PAGE: usize = 500;
+
+#[derive(Deserialize)]
+pub struct PageInfo {
+    page: Option<usize>,
+    per_page: Option<usize>,
+}
+
+fn filter_params(filter: &AuditFilter) -> [&(dyn ToSql + Sync); 5] {
+    [
+        &filter.actor,
+        &filter.action,
+        &filter.tenant_id,
+        &filter.from,
+        &filter.to,
+    ]
+}
+
+#[get("/api/audit")]
+pub async fn list_audit_log(
+    principal: web::ReqData<Principal>,
+    web::Query(page_info): web::Query<PageInfo>,
+    web::Query(filter): web::Query<AuditFilter>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, AppError> {
+    principal.require_scope(SCOPE_ADMIN)?;
+
+    let page = page_info.page.unwrap_or(1).max(1);
+    let per_page = page_info.per_page.unwrap_or(50).clamp(1, MAX_PER_PAGE);
+    let (total, rows) = async {
+        let client = pool.get().await?;
+        let params = filter_params(&filter);
+        let total: i64 = client
+            .query_one(&format!("SELECT count(*) {}", FILTERED_AUDIT_LOG), &params)
+            .await?
+            .get(0);
+        let rows = client
+            .query(
+                &format!(
+                    "SELECT {} {} ORDER BY id DESC LIMIT {} OFFSET {}",
+                    AUDIT_COLUMNS,
+                    FILTERED_AUDIT_LOG,
+                    per_page,
+                    (page - 1) * per_page
+                ),
+                &params,
+            )
+            .await?;
+        Ok::<_, Box<dyn std::error::Error>>((total, rows))
+    }
+    .await
+    .map_err(AppError::internal)?;
+
+    Ok(HttpResponse::Ok().json(PaginationResponse {
+        code: 200,
+        message: String::from(""),
+        data: rows.iter().map(AuditEntry::from).collect(),
+        total,
+        page,
+        per_page,
+        page_counts: (total as usize).div_ceil(per_page),
+    }))
+}
+
+/// Streams the matching audit entries, oldest first, as JSON lines.
+#[get("/api/audit/export")]
+pub async fn export_audit_log(
+    principal: web::ReqData<Principal>,
+    web::Query(filter): web::Query<AuditFilter>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, AppError> {
+    principal.require_scope(SCOPE_ADMIN)?;
+
+    let client = pool.get().await.map_err(AppError::internal)?;
+
+    let (mut sender, receiver) = mpsc::channel::<Result<web::Bytes, actix_web::Error>>(64);
+    actix_web::rt::spawn(async move {
+        let query = format!(
+            "SELECT {} {} ORDER BY id",
+            AUDIT_COLUMNS, FILTERED_AUDIT_LOG
+        );
+        let rows = match client.query_raw(&query, filter_params(&filter)).await {
+            Ok(rows) => rows,
+            Err(err) => {
+                error!("Failed to export audit log: {:?}", err);
+                return;
+            }
+        };
+        futures::pin_mut!(rows);
+        while let Some(row) = rows.next().await {
+            let line = match row {
+                Ok(row) => match serde_json::to_vec(&AuditEntry::from(&row)) {
+                    Ok(mut line) => {
+                        line.push(b'\n');
+                        line
+                    }
+                    Err(err) => {
+                        error!("Failed to export audit log: {:?}", err);
+                        return;
+                    }
+                },
+                Err(err) => {
+                    error!("Failed to export audit log: {:?}", err);
+                    return;
+                }
+            };
+            // The client went away
+            if sender.send(Ok(web::Bytes::from(line))).await.is_err() {
+                return;
+            }
+        }
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .streaming(receiver))
+}
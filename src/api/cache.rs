@@ -0,0 +1,27 @@
+use actix_web::{delete, web, HttpResponse};
+
+use crate::utils::{
+    auth::{Principal, SCOPE_TEMPLATES_WRITE},
+    common_struct::DataResponse,
+    error::AppError,
+    query_cache::QueryCache,
+    tenant::scoped_name,
+};
+
+#[delete("/api/cache/{template_name}")]
+pub async fn invalidate_cache(
+    principal: web::ReqData<Principal>,
+    path: web::Path<String>,
+    query_cache: web::Data<QueryCache>,
+) -> Result<HttpResponse, AppError> {
+    principal.require_scope(SCOPE_TEMPLATES_WRITE)?;
+
+    let template_name = path.into_inner();
+    let template_name = scoped_name(principal.tenant.as_ref(), &template_name);
+    let removed = query_cache.invalidate_template(&template_name);
+    Ok(HttpResponse::Ok().json(DataResponse {
+        code: 200,
+        message: format!("Removed {} cached result(s)", removed),
+        data: Some(removed),
+    }))
+}
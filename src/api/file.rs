@@ -1,13 +1,26 @@
-use crate::utils::{common_struct::BaseResponse, image::get_image_format_from_path};
+use crate::utils::{
+    auth::{Principal, SCOPE_TEMPLATES_WRITE},
+    dataset::{dataset_key, is_valid_dataset_name, parse_csv, parse_spreadsheet},
+    error::AppError,
+    image::{self as images, DecodeLimits, ImageVariant, OutputFormat, ResizeMode},
+    setting::config,
+    staged_files::StagedFiles,
+    static_file::{self, is_valid_path, CACHE_IMMUTABLE},
+    storage::{image_url, Storage},
+    tenant::{check_storage_quota, check_template_quota, tenant_key},
+    upload::{read_field, sanitize_filename, validate_template, write_field},
+};
 use actix_multipart::Multipart;
-use actix_web::{post, web, HttpResponse, Result};
+use actix_web::{post, route, web, HttpRequest, HttpResponse};
 use futures::StreamExt;
+use image::ImageError;
 use serde::{Deserialize, Serialize};
 use std::{
+    collections::BTreeMap,
     fs::{self},
-    io::Write,
     path::Path,
 };
+use tracing::{debug, error};
 use uuid::Uuid;
 
 #[derive(Serialize)]
@@ -18,26 +31,38 @@ pub struct UploadResponse {
 }
 
 #[post("/api/upload")]
-pub async fn upload(mut payload: Multipart) -> Result<HttpResponse, Box<dyn std::error::Error>> {
-    while let Some(item) = payload.next().await {
+pub async fn upload(
+    principal: web::ReqData<Principal>,
+    storage: web::Data<dyn Storage>,
+    mut payload: Multipart,
+) -> Result<HttpResponse, AppError> {
+    principal.require_scope(SCOPE_TEMPLATES_WRITE)?;
+
+    let tenant = principal.tenant.as_ref();
+    check_template_quota(&**storage, tenant).await?;
+
+    if let Some(item) = payload.next().await {
         let mut field = item?;
         let content_disposition = field.content_disposition();
-        let original_name = content_disposition.get_filename().unwrap().to_string();
+        let original_name = sanitize_filename(content_disposition.get_filename())?;
+
+        let contents = read_field(&mut field, config().limits.max_template_bytes).await?;
+        validate_template(&original_name, &contents)?;
 
         let unique_id = Uuid::new_v4();
 
         let filename = format!("{}_{}", unique_id, original_name);
-        let filepath = format!("./templates/{}", filename);
-
-        let mut file = web::block(move || std::fs::File::create(filepath.clone()))
-            .await?
-            .unwrap();
-        while let Some(chunk) = field.next().await {
-            let data = chunk?;
-            file = web::block(move || file.write_all(&data).map(|_| file))
-                .await?
-                .unwrap();
-        }
+        let filepath = format!("{}/{}", config().paths.temp, filename);
+
+        let mut staged = StagedFiles::default();
+        let path = staged.add(filepath.clone());
+        web::block(move || fs::write(path, contents)).await??;
+        storage
+            .put(
+                &tenant_key(tenant, "templates", &filename),
+                Path::new(&filepath),
+            )
+            .await?;
 
         let url = format!("/templates/{}", filename);
         return Ok(HttpResponse::Ok().json(UploadResponse {
@@ -47,133 +72,348 @@ pub async fn upload(mut payload: Multipart) -> Result<HttpResponse, Box<dyn std:
         }));
     }
 
-    Ok(HttpResponse::InternalServerError().json(UploadResponse {
-        code: 500,
-        message: "File upload failed".to_string(),
-        url: "".to_string(),
-    }))
+    Err(AppError::InvalidRequest(String::from(
+        "No file was uploaded!",
+    )))
 }
 
 #[derive(Deserialize)]
-pub struct ResolutionInfo {
+pub struct ImageUploadInfo {
+    // Comma separated names of configured variants, all of them by default
+    variants: Option<String>,
+    // An additional variant of this size, e.g. `300x200`
     resolution: Option<String>,
+    mode: Option<ResizeMode>,
+    // Overrides the configured format and quality
+    format: Option<OutputFormat>,
+    quality: Option<u8>,
+}
+
+#[derive(Serialize)]
+pub struct ImageInfo {
+    pub url: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+#[derive(Serialize)]
+pub struct ImageUploadResponse {
+    pub code: u16,
+    pub message: String,
+    // The original image
+    pub url: String,
+    pub width: u32,
+    pub height: u32,
+    pub variants: BTreeMap<String, ImageInfo>,
+}
+
+struct StagedVariant {
+    name: String,
+    file_name: String,
+    path: String,
+    width: u32,
+    height: u32,
+}
+
+fn requested_variants(info: &ImageUploadInfo) -> Result<BTreeMap<String, ImageVariant>, AppError> {
+    let configured = &config().images.variants;
+    let mut variants = match &info.variants {
+        Some(names) => names
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(|name| match configured.get(name) {
+                Some(variant) => Ok((name.to_string(), *variant)),
+                None => Err(AppError::InvalidRequest(format!(
+                    "Unknown image variant {}!",
+                    name
+                ))),
+            })
+            .collect::<Result<_, _>>()?,
+        None => configured.clone(),
+    };
+    if let Some(resolution) = &info.resolution {
+        let mut variant: ImageVariant = resolution.parse().map_err(|err| {
+            AppError::InvalidRequest(format!("Invalid resolution {:?}: {}", resolution, err))
+        })?;
+        if let Some(mode) = info.mode {
+            variant.mode = mode;
+        }
+        variants.insert(format!("{}x{}", variant.width, variant.height), variant);
+    }
+    Ok(variants)
+}
+
+// How the original and the variants of an upload are written
+struct Encoding {
+    format: OutputFormat,
+    quality: u8,
+    strip_metadata: bool,
+}
+
+fn requested_encoding(info: &ImageUploadInfo) -> Result<Encoding, AppError> {
+    let images = &config().images;
+    let quality = info.quality.unwrap_or(images.quality);
+    if !(1..=100).contains(&quality) {
+        return Err(AppError::InvalidRequest(format!(
+            "Invalid quality {}: must be between 1 and 100",
+            quality
+        )));
+    }
+    Ok(Encoding {
+        format: info.format.unwrap_or(images.format),
+        quality,
+        strip_metadata: images.strip_metadata,
+    })
+}
+
+// Decodes the upload, whatever its name claims it is, and writes the original and every
+// variant next to it. The original is only copied as it is if it needs no conversion and
+// its metadata is kept.
+fn process_image(
+    upload_path: &str,
+    encoding: &Encoding,
+    variants: &BTreeMap<String, ImageVariant>,
+    dir: &str,
+    id: Uuid,
+) -> Result<(StagedVariant, Vec<StagedVariant>), AppError> {
+    let settings = &config().images;
+    let limits = DecodeLimits {
+        max_width: settings.max_width,
+        max_height: settings.max_height,
+        max_alloc: settings.max_decode_mb * 1024 * 1024,
+    };
+    let (image, source) =
+        images::decode(Path::new(upload_path), limits).map_err(|err| match err {
+            ImageError::Limits(_) => AppError::InvalidRequest(format!(
+                "Image is too large, at most {}x{} pixels and {} MB decoded are allowed",
+                settings.max_width, settings.max_height, settings.max_decode_mb
+            )),
+            err => {
+                AppError::InvalidRequest(format!("Uploaded file is not a supported image: {}", err))
+            }
+        })?;
+    let format = encoding.format.resolve(source);
+    let quality = encoding.quality;
+
+    let write = |name: &str, image: &image::DynamicImage| -> Result<StagedVariant, AppError> {
+        let file_name = format!("{}.{}", name, images::extension(format));
+        let path = format!("{}/{}_{}", dir, id, file_name);
+        images::encode(image, format, quality, &path).map_err(|err| {
+            error!("Image saving error: {}", err);
+            AppError::internal(err)
+        })?;
+        Ok(StagedVariant {
+            name: name.to_string(),
+            file_name,
+            path,
+            width: image.width(),
+            height: image.height(),
+        })
+    };
+
+    let original = if encoding.format == OutputFormat::Original && !encoding.strip_metadata {
+        let file_name = format!("original.{}", images::extension(source));
+        let path = format!("{}/{}_{}", dir, id, file_name);
+        fs::rename(upload_path, &path)?;
+        StagedVariant {
+            name: String::from("original"),
+            file_name,
+            path,
+            width: image.width(),
+            height: image.height(),
+        }
+    } else {
+        write("original", &image)?
+    };
+    let mut staged = Vec::new();
+    for (name, variant) in variants {
+        staged.push(write(name, &variant.apply(&image))?);
+    }
+    Ok((original, staged))
 }
 
 #[post("/api/upload-image")]
 pub async fn upload_image(
-    web::Query(info): web::Query<ResolutionInfo>,
+    principal: web::ReqData<Principal>,
+    web::Query(info): web::Query<ImageUploadInfo>,
+    storage: web::Data<dyn Storage>,
     mut payload: Multipart,
-) -> Result<HttpResponse, Box<dyn std::error::Error>> {
-    while let Some(item) = payload.next().await {
+) -> Result<HttpResponse, AppError> {
+    principal.require_scope(SCOPE_TEMPLATES_WRITE)?;
+
+    let tenant = principal.tenant.as_ref();
+    check_storage_quota(&**storage, tenant).await?;
+    let variants = requested_variants(&info)?;
+    let encoding = requested_encoding(&info)?;
+
+    let Some(item) = payload.next().await else {
+        return Err(AppError::InvalidRequest(String::from(
+            "No image was uploaded!",
+        )));
+    };
+    let mut field = item?;
+    let content_disposition = field.content_disposition();
+    let original_name = sanitize_filename(content_disposition.get_filename())?;
+    debug!("Uploading image {}", original_name);
+
+    // The upload and its variants are prepared in temp and then moved into storage, as
+    // `{id}/original.{ext}` and `{id}/{variant}.{ext}`
+    let id = Uuid::new_v4();
+    let temp_dir = config().paths.temp.clone();
+    let mut staged = StagedFiles::default();
+    let upload_path = staged.add(format!("{}/{}_upload", temp_dir, id));
+
+    write_field(&mut field, &upload_path, config().limits.max_image_bytes).await?;
+
+    // Decoding, resizing and encoding are CPU bound, so they run on the blocking pool
+    let path = upload_path.clone();
+    let (original, variant_files) =
+        web::block(move || process_image(&path, &encoding, &variants, &temp_dir, id)).await??;
+
+    staged.add(original.path.clone());
+    let original_key = tenant_key(tenant, "images", &format!("{}/{}", id, original.file_name));
+    storage
+        .put(&original_key, Path::new(&original.path))
+        .await?;
+    let mut stored = BTreeMap::new();
+    for variant in variant_files {
+        staged.add(variant.path.clone());
+        let key = tenant_key(tenant, "images", &format!("{}/{}", id, variant.file_name));
+        storage.put(&key, Path::new(&variant.path)).await?;
+        stored.insert(
+            variant.name,
+            ImageInfo {
+                url: image_url(&key),
+                width: variant.width,
+                height: variant.height,
+            },
+        );
+    }
+
+    Ok(HttpResponse::Ok().json(ImageUploadResponse {
+        code: 200,
+        message: "Image uploaded successfully".to_string(),
+        url: image_url(&original_key),
+        width: original.width,
+        height: original.height,
+        variants: stored,
+    }))
+}
+
+// Images are public so templates can link to them, their names can't be guessed.
+// `/storage/` is where earlier versions pointed image URLs.
+#[route("/images/{path:.*}", method = "GET", method = "HEAD")]
+pub async fn get_image(
+    req: HttpRequest,
+    path: web::Path<String>,
+    storage: web::Data<dyn Storage>,
+) -> Result<HttpResponse, AppError> {
+    serve_image(&req, &path, &**storage).await
+}
+
+#[route("/storage/{path:.*}", method = "GET", method = "HEAD")]
+pub async fn get_legacy_image(
+    req: HttpRequest,
+    path: web::Path<String>,
+    storage: web::Data<dyn Storage>,
+) -> Result<HttpResponse, AppError> {
+    serve_image(&req, &path, &**storage).await
+}
+
+async fn serve_image(
+    req: &HttpRequest,
+    path: &str,
+    storage: &dyn Storage,
+) -> Result<HttpResponse, AppError> {
+    if !is_valid_path(path) {
+        return Err(AppError::NotFound(String::from("Image not found!")));
+    }
+    static_file::serve(req, storage, &format!("images/{path}"), CACHE_IMMUTABLE)
+        .await?
+        .ok_or_else(|| AppError::NotFound(String::from("Image not found!")))
+}
+
+#[derive(Deserialize)]
+pub struct DatasetInfo {
+    name: String,
+}
+
+#[derive(Serialize)]
+pub struct DatasetResponse {
+    pub code: u16,
+    pub message: String,
+    pub name: String,
+    pub columns: Vec<String>,
+    pub rows: usize,
+}
+
+#[post("/api/upload-dataset")]
+pub async fn upload_dataset(
+    principal: web::ReqData<Principal>,
+    web::Query(info): web::Query<DatasetInfo>,
+    storage: web::Data<dyn Storage>,
+    mut payload: Multipart,
+) -> Result<HttpResponse, AppError> {
+    principal.require_scope(SCOPE_TEMPLATES_WRITE)?;
+
+    if !is_valid_dataset_name(&info.name) {
+        return Err(AppError::InvalidRequest(String::from(
+            "Dataset name may only contain letters, numbers, '_' and '-'!",
+        )));
+    }
+
+    let tenant = principal.tenant.as_ref();
+    check_storage_quota(&**storage, tenant).await?;
+
+    if let Some(item) = payload.next().await {
         let mut field = item?;
         let content_disposition = field.content_disposition();
-        let original_name = content_disposition.get_filename().unwrap().to_string();
-        let path = Path::new(&original_name);
-        let stem = path
-            .file_stem()
-            .and_then(|s| s.to_str())
-            .unwrap_or_default();
-        let extension = path
-            .extension()
-            .and_then(|s| s.to_str())
-            .unwrap_or_default();
+        let original_name = content_disposition
+            .get_filename()
+            .unwrap_or_default()
+            .to_lowercase();
 
-        let unique_id = Uuid::new_v4();
+        let bytes = read_field(&mut field, config().limits.max_dataset_bytes).await?;
 
-        let original_filename = format!("{}_{}_original.{}", unique_id, stem, extension);
-        let original_filepath = format!("./images/{}", original_filename);
+        let parsed = if original_name.ends_with(".csv") {
+            parse_csv(&bytes)
+        } else if original_name.ends_with(".xlsx")
+            || original_name.ends_with(".xls")
+            || original_name.ends_with(".ods")
+        {
+            parse_spreadsheet(bytes)
+        } else {
+            return Err(AppError::InvalidRequest(String::from(
+                "Only CSV and Excel files are supported!",
+            )));
+        };
 
-        let filename = format!("{}_{}", unique_id, original_name);
-        let filepath = format!("./images/{}", filename);
-
-        let mut file = web::block(move || std::fs::File::create(filepath.clone()))
-            .await?
-            .unwrap();
-        while let Some(chunk) = field.next().await {
-            let data = chunk?;
-            file = web::block(move || file.write_all(&data).map(|_| file))
-                .await?
-                .unwrap();
-        }
+        let (columns, rows) = parsed.map_err(|err| AppError::Dataset {
+            name: info.name.clone(),
+            message: format!("Dataset could not be parsed: {}", err),
+        })?;
 
-        match fs::copy(format!("./images/{}", filename), &original_filepath) {
-            Ok(_) => {
-                println!("File copied successfully!");
-            }
-            Err(e) => {
-                println!("Failed to copy file: {}", e);
-            }
-        }
+        let row_count = rows.len();
+        let contents = serde_json::to_vec(&rows).map_err(AppError::internal)?;
+        let mut staged = StagedFiles::default();
+        let dataset_path = staged.add(format!("{}/{}.json", config().paths.temp, Uuid::new_v4()));
+        let path = dataset_path.clone();
+        web::block(move || fs::write(path, contents)).await??;
+        storage
+            .put(&dataset_key(tenant, &info.name), Path::new(&dataset_path))
+            .await?;
 
-        // Resize the image if resolution parameter is provided
-        if let Some(resolution) = &info.resolution {
-            let parts: Vec<&str> = resolution.split('x').collect();
-            if parts.len() == 2 {
-                if let (Ok(width), Ok(height)) = (parts[0].parse::<u32>(), parts[1].parse::<u32>())
-                {
-                    let img_path = format!("./storage/{}", filename);
-                    match image::open(img_path) {
-                        Ok(img) => {
-                            // Check if the original image dimensions are smaller than the target dimensions
-                            if img.width() < width || img.height() < height {
-                                let url = format!("/images/{}", filename);
-                                return Ok(HttpResponse::Ok().json(UploadResponse {
-                                    code: 200,
-                                    message: "Original image resolution is lower than the given resolution. No resizing performed.".to_string(),
-                                    url
-                                }));
-                            }
-
-                            let resized =
-                                img.resize(width, height, image::imageops::FilterType::Lanczos3);
-                            // Determine the format based on the original image's format
-                            let format = get_image_format_from_path(
-                                format!("./images/{}", filename).as_str(),
-                            )
-                            .unwrap_or(image::ImageFormat::Png);
-                            if let Err(e) =
-                                resized.save_with_format(format!("./images/{}", filename), format)
-                            {
-                                eprintln!("Resized image saving error: {}", e);
-                                match fs::remove_file(format!("./images/{}", filename)) {
-                                    Ok(_) => println!("File deleted successfully!"),
-                                    Err(e) => println!("Error deleting file: {}", e),
-                                };
-                                return Ok(HttpResponse::InternalServerError().json(
-                                    BaseResponse {
-                                        code: 500,
-                                        message: String::from("Error resizing image!"),
-                                    },
-                                ));
-                            }
-                        }
-                        Err(e) => {
-                            eprintln!("Image opening error: {}", e);
-                            match fs::remove_file(format!("./images/{}", filename)) {
-                                Ok(_) => println!("File deleted successfully!"),
-                                Err(e) => println!("Error deleting file: {}", e),
-                            };
-                            return Ok(HttpResponse::InternalServerError().json(BaseResponse {
-                                code: 500,
-                                message: String::from("Error resizing image!"),
-                            }));
-                        }
-                    }
-                }
-            }
-        }
-
-        let url = format!("/storage/{}", filename);
-        return Ok(HttpResponse::Ok().json(UploadResponse {
+        return Ok(HttpResponse::Ok().json(DatasetResponse {
             code: 200,
-            message: "Image uploaded successfully".to_string(),
-            url,
+            message: "Dataset uploaded successfully".to_string(),
+            name: info.name,
+            columns,
+            rows: row_count,
         }));
     }
 
-    Ok(HttpResponse::InternalServerError().json(UploadResponse {
-        code: 500,
-        message: "Image upload failed".to_string(),
-        url: "".to_string(),
-    }))
+    Err(AppError::InvalidRequest(String::from(
+        "No dataset was uploaded!",
+    )))
 }
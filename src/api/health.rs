@@ -0,0 +1,37 @@
+use actix_web::{get, web, HttpResponse};
+use deadpool_postgres::Pool;
+use serde_json::json;
+
+use crate::utils::{
+    health::HealthChecker, render_supervisor::RenderSupervisor, storage::Storage,
+    url_policy::UrlPolicy,
+};
+
+/// Liveness: the process is up and serving requests.
+#[get("/healthz")]
+pub async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(json!({ "status": "ok" }))
+}
+
+/// Readiness: the database, the renderer and the storage all work. A draining
+/// server is never ready, so load balancers stop sending it work.
+#[get("/readyz")]
+pub async fn readyz(
+    health: web::Data<HealthChecker>,
+    supervisor: web::Data<RenderSupervisor>,
+    storage: web::Data<dyn Storage>,
+    url_policy: web::Data<UrlPolicy>,
+    pool: web::Data<Pool>,
+) -> HttpResponse {
+    if supervisor.is_draining() {
+        return HttpResponse::ServiceUnavailable().json(json!({ "status": "shutting_down" }));
+    }
+    let readiness = health
+        .readiness(&pool, &**storage, &supervisor, url_policy.into_inner())
+        .await;
+    if readiness.status == "ok" {
+        HttpResponse::Ok().json(readiness)
+    } else {
+        HttpResponse::ServiceUnavailable().json(readiness)
+    }
+}
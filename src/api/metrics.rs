@@ -0,0 +1,23 @@
+use actix_web::{get, web, HttpResponse};
+
+use crate::utils::{
+    auth::{Principal, SCOPE_METRICS_READ},
+    error::AppError,
+    metrics::Metrics,
+};
+
+#[get("/metrics")]
+pub async fn get_metrics(
+    principal: web::ReqData<Principal>,
+    metrics: web::Data<Metrics>,
+) -> Result<HttpResponse, AppError> {
+    principal.require_scope(SCOPE_METRICS_READ)?;
+
+    // Measuring the directory sizes walks the file system
+    let body = web::block(move || metrics.render())
+        .await?
+        .map_err(AppError::internal)?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(body))
+}
@@ -1,26 +1,74 @@
 use std::{
-    fs::{self, File},
-    io::Write,
-    path::{Path, PathBuf},
+    path::Path,
     process::Command,
-    sync::Arc,
+    time::{Duration, Instant},
 };
 
-use actix_web::{get, post, web, Error, HttpResponse, Responder};
+use actix_web::{
+    get,
+    http::{Method, StatusCode},
+    post, route, web, HttpRequest, HttpResponse,
+};
+use deadpool_postgres::Pool;
 use serde::Deserialize;
-use tokio_postgres::Client;
+use serde_json::Value;
+use tokio::io::BufWriter;
+use tracing::{error, Span};
 use uuid::Uuid;
 
 use crate::utils::{
-    common_struct::{BaseResponse, DataResponse},
-    html_parser::process_template,
-    setting::get_port,
+    auth::{Principal, SCOPE_RENDER_TEMPLATE, SCOPE_RENDER_URL, SCOPE_REPORTS_READ},
+    common_struct::DataResponse,
+    error::AppError,
+    html_parser::{process_template, TemplateContext, TemplateMode},
+    metrics::Metrics,
+    query_cache::QueryCache,
+    rate_limit::RateLimiter,
+    render_proxy::{RenderPage, RenderProxy},
+    render_supervisor::RenderSupervisor,
+    setting::config,
+    signed_url::{is_usable, redeem, SignatureQuery, UrlSigner},
+    staged_files::StagedFiles,
+    static_file::{self, is_valid_path, CACHE_PRIVATE},
+    storage::Storage,
+    tenant::{check_storage_quota, scoped_name, tenant_key, DataSources},
+    url_policy::UrlPolicy,
 };
 
 #[derive(Deserialize)]
 pub struct ProcessReportRequest {
     pub template_name: String,
     pub options: SitetopdfOptions,
+    pub data: Option<Value>,
+    // Defaults to the templates.mode setting
+    pub mode: Option<TemplateMode>,
+    // Lifetime of the returned download URL in seconds
+    pub expires_in: Option<u64>,
+    pub single_use: Option<bool>,
+}
+
+/// Builds the download URL of a stored report. It is a presigned storage URL when those are
+/// enabled, except for single use URLs, which only this service can enforce.
+async fn report_url(
+    storage: &dyn Storage,
+    url_signer: &UrlSigner,
+    key: &str,
+    expires_in: Option<u64>,
+    single_use: bool,
+) -> Result<String, AppError> {
+    let report_urls = &config().report_urls;
+    let expires_in = expires_in
+        .unwrap_or(report_urls.ttl)
+        .min(report_urls.max_ttl);
+    if config().storage.presigned_urls && !single_use {
+        if let Some(url) = storage
+            .presigned_url(key, Duration::from_secs(expires_in))
+            .await?
+        {
+            return Ok(url);
+        }
+    }
+    Ok(url_signer.sign(&format!("/{}", key), expires_in, single_use))
 }
 
 #[derive(Deserialize)]
@@ -47,17 +95,43 @@ pub struct SitetopdfOptions {
 }
 
 #[post("/api/site-to-pdf")]
-pub async fn site_to_pdf(body: web::Json<SitetopdfOptions>) -> impl Responder {
-    let mut sitetopdf = Command::new("sitetopdf");
+#[allow(clippy::too_many_arguments)]
+pub async fn site_to_pdf(
+    principal: web::ReqData<Principal>,
+    body: web::Json<SitetopdfOptions>,
+    url_signer: web::Data<UrlSigner>,
+    url_policy: web::Data<UrlPolicy>,
+    rate_limiter: web::Data<RateLimiter>,
+    metrics: web::Data<Metrics>,
+    supervisor: web::Data<RenderSupervisor>,
+    storage: web::Data<dyn Storage>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, AppError> {
+    let mut sitetopdf = Command::new(&config().renderer.command);
 
     if body.url.is_none() && body.content.is_none() {
-        return HttpResponse::BadRequest().json(BaseResponse {
-            code: 400,
-            message: String::from("Url or content must be set!"),
-        });
+        return Err(AppError::InvalidRequest(String::from(
+            "Url or content must be set!",
+        )));
     }
 
+    let scope = if body.url.is_some() {
+        SCOPE_RENDER_URL
+    } else {
+        SCOPE_RENDER_TEMPLATE
+    };
+    principal.require_scope(scope)?;
+    let mut permit = RateLimiter::start_render(&rate_limiter, &pool, &principal.id).await?;
+    let mut render_metrics = metrics.start_render("site-to-pdf");
+
+    let tenant = principal.tenant.as_ref();
+    check_storage_quota(&**storage, tenant).await?;
+
     if let Some(url) = &body.url {
+        url_policy
+            .check_url(url)
+            .await
+            .map_err(AppError::UrlNotAllowed)?;
         sitetopdf.arg("--url").arg(url);
     } else {
         if let Some(content) = &body.content {
@@ -70,19 +144,24 @@ pub async fn site_to_pdf(body: web::Json<SitetopdfOptions>) -> impl Responder {
         }
     }
 
+    // The renderer writes to temp, the result is moved into storage once it succeeded
     let unique_id = Uuid::new_v4();
-    let mut pdf_file_path = format!("./reports/{unique_id}.pdf");
+    let mut staged = StagedFiles::default();
+    let mut pdf_file_path = staged.add(format!("{}/{unique_id}.pdf", config().paths.temp));
+    let mut key = tenant_key(tenant, "reports", &format!("{unique_id}.pdf"));
 
-    let mut url = format!("/reports/{unique_id}.pdf");
     if let Some(image) = body.image {
         if image {
             sitetopdf.arg("--image");
-            pdf_file_path = format!("./images/{unique_id}.png");
-            sitetopdf.arg("--image-output").arg(pdf_file_path).arg("-v");
-            url = format!("/images/{unique_id}.png");
+            pdf_file_path = staged.add(format!("{}/{unique_id}.png", config().paths.temp));
+            key = tenant_key(tenant, "reports", &format!("{unique_id}.png"));
+            sitetopdf
+                .arg("--image-output")
+                .arg(&pdf_file_path)
+                .arg("-v");
         }
     } else {
-        sitetopdf.arg("-o").arg(pdf_file_path).arg("-v");
+        sitetopdf.arg("-o").arg(&pdf_file_path).arg("-v");
     }
 
     if let Some(format) = &body.format {
@@ -140,212 +219,316 @@ pub async fn site_to_pdf(body: web::Json<SitetopdfOptions>) -> impl Responder {
         sitetopdf.arg("--timeout").arg(timeout);
     }
 
-    let command = sitetopdf.output();
+    // Every request of the browser goes through the proxy, which enforces the url policy
+    let proxy = RenderProxy::start(url_policy.into_inner(), None).await?;
+    proxy.apply(&mut sitetopdf);
 
-    match command {
-        Ok(output) => {
-            println!("Stdout: {}", String::from_utf8_lossy(&output.stdout));
-            if !output.status.success() {
-                // Convert stderr bytes to a string and print it
-                let stderr_string = String::from_utf8_lossy(&output.stderr);
-                println!("Command Error: {}", stderr_string);
-                return HttpResponse::InternalServerError().json(BaseResponse {
-                    code: 500,
-                    message: "Failed to run sitetopdf command".to_string(),
-                });
-            }
-            return HttpResponse::Ok().json(DataResponse {
-                code: 200,
-                message: String::from(""),
-                data: Some(url),
-            });
-        }
-        Err(err) => {
-            println!("{:?}", err);
-            return HttpResponse::InternalServerError().json(BaseResponse {
-                code: 500,
-                message: "Error executing sitetopdf command".to_string(),
-            });
-        }
+    let render_started = Instant::now();
+    let rendered = supervisor.run(sitetopdf, &pdf_file_path).await;
+    drop(proxy);
+    let render_elapsed = render_started.elapsed();
+    Span::current().record("render_ms", render_elapsed.as_millis() as u64);
+    render_metrics.observe_renderer(render_elapsed);
+    rendered?;
+    render_metrics.succeeded(&pdf_file_path);
+    if let Err(err) = permit.record(&pool, &pdf_file_path).await {
+        error!("Failed to record usage: {:?}", err);
     }
+    storage.put(&key, Path::new(&pdf_file_path)).await?;
+
+    // Screenshots are reports too, only reachable through a signed url
+    let url = report_url(&**storage, &url_signer, &key, None, false).await?;
+    Ok(HttpResponse::Ok().json(DataResponse {
+        code: 200,
+        message: String::from(""),
+        data: Some(url),
+    }))
 }
 
 #[post("/api/process-report")]
+#[allow(clippy::too_many_arguments)]
 pub async fn process_report(
+    principal: web::ReqData<Principal>,
     body: web::Json<ProcessReportRequest>,
-    client: web::Data<Arc<Client>>,
-) -> impl Responder {
-    let file_path = format!("./templates/{}", &body.template_name);
-
-    if !Path::new(&file_path).exists() {
-        return HttpResponse::BadRequest().json(BaseResponse {
-            code: 400,
-            message: String::from("Template not found!"),
-        });
-    }
-
-    let html = match fs::read_to_string(file_path) {
-        Ok(contents) => match process_template(&contents, &client).await {
-            Ok(processed_html) => processed_html,
-            Err(err) => {
-                println!("{:?}", err);
-                "".to_string()
-            }
-        },
-        Err(err) => {
-            println!("{:?}", err);
-            return HttpResponse::BadRequest().json(BaseResponse {
-                code: 400,
-                message: String::from("Template could not be parsed!"),
-            });
-        }
-    };
-    println!("{}", html);
-
-    if !fs::metadata("./reports").is_ok() {
-        if let Err(_) = fs::create_dir_all("./reports") {
-            return HttpResponse::InternalServerError().json(BaseResponse {
-                code: 500,
-                message: "Internal Server Error".to_string(),
-            });
+    data_sources: web::Data<DataSources>,
+    query_cache: web::Data<QueryCache>,
+    url_signer: web::Data<UrlSigner>,
+    url_policy: web::Data<UrlPolicy>,
+    rate_limiter: web::Data<RateLimiter>,
+    metrics: web::Data<Metrics>,
+    supervisor: web::Data<RenderSupervisor>,
+    storage: web::Data<dyn Storage>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, AppError> {
+    principal.require_scope(SCOPE_RENDER_TEMPLATE)?;
+    let mut permit = RateLimiter::start_render(&rate_limiter, &pool, &principal.id).await?;
+
+    // Template names are resolved inside the caller's namespace and must not leave it
+    if body.template_name.contains(['/', '\\']) || body.template_name.starts_with('.') {
+        return Err(AppError::InvalidRequest(String::from(
+            "Invalid template name!",
+        )));
+    }
+
+    if let Some(data) = &body.data {
+        if !data.is_object() {
+            return Err(AppError::InvalidRequest(String::from(
+                "Data must be a JSON object!",
+            )));
         }
     }
 
+    let tenant = principal.tenant.as_ref();
+    let contents = storage
+        .read(&tenant_key(tenant, "templates", &body.template_name))
+        .await?
+        .ok_or_else(|| AppError::NotFound(String::from("Template not found!")))?;
+    // Requests for templates that don't exist aren't counted as renders
+    let mut render_metrics = metrics.start_render("process-report");
+    let contents = String::from_utf8(contents)
+        .map_err(|err| AppError::Template(format!("Template could not be read: {}", err)))?;
+
+    check_storage_quota(&**storage, tenant).await?;
+
+    let (source, source_pool) = data_sources.get(tenant)?;
+
+    // The processed HTML is only ever served to this render, by its proxy. It is removed
+    // however the request ends.
     let unique_id = Uuid::new_v4();
-    let processed_html_file_path = format!("./temp/{unique_id}.html");
-    match File::create(&processed_html_file_path) {
-        Ok(mut file) => {
-            match file.write_all(html.as_bytes()) {
-                Ok(_) => {
-                    let mut sitetopdf = Command::new("sitetopdf");
-
-                    let url = format!("http://localhost:{}/temp/{unique_id}.html", get_port());
-                    sitetopdf.arg("--url").arg(url);
-
-                    let pdf_file_path = format!("./reports/{unique_id}.pdf");
-                    sitetopdf
-                        .arg("--output")
-                        .arg(pdf_file_path)
-                        .arg("--verbose");
-
-                    if let Some(format) = &body.options.format {
-                        sitetopdf.arg("--format").arg(format);
-                    }
-                    if let Some(landscape) = body.options.landscape {
-                        if landscape {
-                            sitetopdf.arg("--landscape");
-                        }
-                    }
-                    if let Some(scale) = &body.options.scale {
-                        sitetopdf.arg("--scale").arg(scale);
-                    }
-                    if let Some(margin_top) = &body.options.margin_top {
-                        sitetopdf.arg("--margin-top").arg(margin_top);
-                    }
-                    if let Some(margin_bottom) = &body.options.margin_bottom {
-                        sitetopdf.arg("--margin-bottom").arg(margin_bottom);
-                    }
-                    if let Some(margin_right) = &body.options.margin_right {
-                        sitetopdf.arg("--margin-right").arg(margin_right);
-                    }
-                    if let Some(margin_left) = &body.options.margin_left {
-                        sitetopdf.arg("--margin-left").arg(margin_left);
-                    }
-                    if let Some(header_template) = &body.options.header_template {
-                        sitetopdf.arg("--header-template").arg(header_template);
-                    }
-                    if let Some(footer_template) = &body.options.footer_template {
-                        sitetopdf.arg("--footer-template").arg(footer_template);
-                    }
-                    if let Some(display_header_footer) = body.options.display_header_footer {
-                        if display_header_footer {
-                            sitetopdf.arg("--display-header-footer");
-                        }
-                    }
-                    if let Some(prefer_css_page_size) = body.options.prefer_css_page_size {
-                        if prefer_css_page_size {
-                            sitetopdf.arg("--prefer-css-page-size");
-                        }
-                    }
-                    if let Some(page_ranges) = &body.options.page_ranges {
-                        sitetopdf.arg("--page-ranges").arg(page_ranges);
-                    }
-                    if let Some(ignore_http_errors) = body.options.ignore_http_errors {
-                        if ignore_http_errors {
-                            sitetopdf.arg("--ignore-http-errors");
-                        }
-                    }
-                    match &body.options.wait_until {
-                        Some(wait_until) => sitetopdf.arg("--wait-until").arg(wait_until),
-                        None => sitetopdf.arg("--wait-until").arg("load"),
-                    };
-                    if let Some(timeout) = &body.options.timeout {
-                        sitetopdf.arg("--timeout").arg(timeout);
-                    }
-
-                    let command = sitetopdf.output();
-
-                    match command {
-                        Ok(output) => {
-                            println!("Stdout: {}", String::from_utf8_lossy(&output.stdout));
-                            if !output.status.success() {
-                                // Convert stderr bytes to a string and print it
-                                let stderr_string = String::from_utf8_lossy(&output.stderr);
-                                println!("Command Error: {}", stderr_string);
-                                return HttpResponse::InternalServerError().json(BaseResponse {
-                                    code: 500,
-                                    message: "Failed to run sitetopdf command".to_string(),
-                                });
-                            }
-                            tokio::spawn(async move {
-                                match fs::remove_file(&processed_html_file_path) {
-                                    Ok(_) => println!("File deleted successfully!"),
-                                    Err(e) => println!("Error deleting file: {}", e),
-                                };
-                            });
-                            return HttpResponse::Ok().json(DataResponse {
-                                code: 200,
-                                message: String::from(""),
-                                data: Some(format!("/reports/{unique_id}.pdf")),
-                            });
-                        }
-                        Err(err) => {
-                            println!("{:?}", err);
-                            return HttpResponse::InternalServerError().json(BaseResponse {
-                                code: 500,
-                                message: "Error executing sitetopdf command".to_string(),
-                            });
-                        }
-                    }
-                }
-                Err(err) => {
-                    println!("{:?}", err);
-                    return HttpResponse::InternalServerError().json(BaseResponse {
-                        code: 500,
-                        message: String::from("Error writing template!"),
-                    });
-                }
-            };
+    let mut staged = StagedFiles::default();
+    let processed_html_file_path = staged.add(format!("{}/{unique_id}.html", config().paths.temp));
+    let mut file = BufWriter::new(tokio::fs::File::create(&processed_html_file_path).await?);
+
+    Span::current().record("template", body.template_name.as_str());
+
+    // The processed HTML is streamed into the temp file instead of being built in memory
+    let ctx = TemplateContext {
+        pool: source_pool.as_ref(),
+        source: &source,
+        query_cache: &query_cache,
+        template_name: &scoped_name(tenant, &body.template_name),
+        storage: &**storage,
+        tenant,
+        data: body.data.as_ref(),
+        mode: body.mode.unwrap_or(config().templates.mode),
+        metrics: &metrics,
+    };
+    let template_started = Instant::now();
+    let processed = process_template(&contents, &ctx, &mut file).await;
+    Span::current().record("template_ms", template_started.elapsed().as_millis() as u64);
+    drop(file);
+    processed?;
+
+    let mut sitetopdf = Command::new(&config().renderer.command);
+
+    let url = format!("{}/render/{unique_id}.html", config().internal_base_url());
+    sitetopdf.arg("--url").arg(&url);
+
+    let pdf_file_path = staged.add(format!("{}/{unique_id}.pdf", config().paths.temp));
+    let key = tenant_key(tenant, "reports", &format!("{unique_id}.pdf"));
+    sitetopdf
+        .arg("--output")
+        .arg(&pdf_file_path)
+        .arg("--verbose");
+
+    if let Some(format) = &body.options.format {
+        sitetopdf.arg("--format").arg(format);
+    }
+    if let Some(landscape) = body.options.landscape {
+        if landscape {
+            sitetopdf.arg("--landscape");
+        }
+    }
+    if let Some(scale) = &body.options.scale {
+        sitetopdf.arg("--scale").arg(scale);
+    }
+    if let Some(margin_top) = &body.options.margin_top {
+        sitetopdf.arg("--margin-top").arg(margin_top);
+    }
+    if let Some(margin_bottom) = &body.options.margin_bottom {
+        sitetopdf.arg("--margin-bottom").arg(margin_bottom);
+    }
+    if let Some(margin_right) = &body.options.margin_right {
+        sitetopdf.arg("--margin-right").arg(margin_right);
+    }
+    if let Some(margin_left) = &body.options.margin_left {
+        sitetopdf.arg("--margin-left").arg(margin_left);
+    }
+    if let Some(header_template) = &body.options.header_template {
+        sitetopdf.arg("--header-template").arg(header_template);
+    }
+    if let Some(footer_template) = &body.options.footer_template {
+        sitetopdf.arg("--footer-template").arg(footer_template);
+    }
+    if let Some(display_header_footer) = body.options.display_header_footer {
+        if display_header_footer {
+            sitetopdf.arg("--display-header-footer");
+        }
+    }
+    if let Some(prefer_css_page_size) = body.options.prefer_css_page_size {
+        if prefer_css_page_size {
+            sitetopdf.arg("--prefer-css-page-size");
         }
-        Err(err) => {
-            println!("{:?}", err);
-            return HttpResponse::InternalServerError().json(BaseResponse {
-                code: 500,
-                message: String::from("Error creating output file!"),
-            });
+    }
+    if let Some(page_ranges) = &body.options.page_ranges {
+        sitetopdf.arg("--page-ranges").arg(page_ranges);
+    }
+    if let Some(ignore_http_errors) = body.options.ignore_http_errors {
+        if ignore_http_errors {
+            sitetopdf.arg("--ignore-http-errors");
         }
+    }
+    match &body.options.wait_until {
+        Some(wait_until) => sitetopdf.arg("--wait-until").arg(wait_until),
+        None => sitetopdf.arg("--wait-until").arg("load"),
+    };
+    if let Some(timeout) = &body.options.timeout {
+        sitetopdf.arg("--timeout").arg(timeout);
+    }
+
+    // Every request of the browser goes through the proxy, which enforces the url policy
+    let page = RenderPage {
+        url,
+        path: processed_html_file_path,
     };
+    let proxy = RenderProxy::start(url_policy.into_inner(), Some(page)).await?;
+    proxy.apply(&mut sitetopdf);
+
+    let render_started = Instant::now();
+    let rendered = supervisor.run(sitetopdf, &pdf_file_path).await;
+    drop(proxy);
+    let render_elapsed = render_started.elapsed();
+    Span::current().record("render_ms", render_elapsed.as_millis() as u64);
+    render_metrics.observe_renderer(render_elapsed);
+    rendered?;
+    render_metrics.succeeded(&pdf_file_path);
+    if let Err(err) = permit.record(&pool, &pdf_file_path).await {
+        error!("Failed to record usage: {:?}", err);
+    }
+    storage.put(&key, Path::new(&pdf_file_path)).await?;
+
+    let download_url = report_url(
+        &**storage,
+        &url_signer,
+        &key,
+        body.expires_in,
+        body.single_use.unwrap_or(false),
+    )
+    .await?;
+    Ok(HttpResponse::Ok().json(DataResponse {
+        code: 200,
+        message: String::from(""),
+        data: Some(download_url),
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct SignUrlRequest {
+    expires_in: Option<u64>,
+    single_use: Option<bool>,
+}
+
+#[get("/api/reports/{file_name}/signed-url")]
+pub async fn sign_report_url(
+    principal: web::ReqData<Principal>,
+    path: web::Path<String>,
+    web::Query(info): web::Query<SignUrlRequest>,
+    url_signer: web::Data<UrlSigner>,
+    storage: web::Data<dyn Storage>,
+) -> Result<HttpResponse, AppError> {
+    principal.require_scope(SCOPE_REPORTS_READ)?;
+
+    let file_name = path.into_inner();
+    let key = tenant_key(principal.tenant.as_ref(), "reports", &file_name);
+    if file_name.starts_with('.') || !storage.exists(&key).await? {
+        return Err(AppError::NotFound(String::from("Report not found!")));
+    }
+
+    Ok(HttpResponse::Ok().json(DataResponse {
+        code: 200,
+        message: String::from(""),
+        data: Some(
+            report_url(
+                &**storage,
+                &url_signer,
+                &key,
+                info.expires_in,
+                info.single_use.unwrap_or(false),
+            )
+            .await?,
+        ),
+    }))
 }
 
-#[get("/reports/{file_name}")]
-pub async fn get_report(path: web::Path<String>) -> Result<HttpResponse, Error> {
+#[route("/reports/{file_name:.*}", method = "GET", method = "HEAD")]
+pub async fn get_report(
+    req: HttpRequest,
+    path: web::Path<String>,
+    web::Query(query): web::Query<SignatureQuery>,
+    url_signer: web::Data<UrlSigner>,
+    storage: web::Data<dyn Storage>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, AppError> {
+    // The signature covers the tenant prefix as well, so a valid URL can't be redirected
+    // to another tenant's reports
+    url_signer
+        .verify(req.path(), &query)
+        .map_err(|message| AppError::InvalidSignature(message.to_string()))?;
+
     let file_name = path.into_inner();
-    // Check if the file exists
-    let file_path = format!("./reports/{file_name}");
-    if !PathBuf::from(&file_path).exists() {
-        return Ok(HttpResponse::NotFound().finish());
+    if !is_valid_path(&file_name) {
+        return Err(AppError::NotFound(String::from("Report not found!")));
     }
 
-    // Serve the PDF file
-    Ok(HttpResponse::Ok().body(actix_web::web::Bytes::from(std::fs::read(file_path)?)))
+    // A single use URL is only redeemed by a GET that sends content, so a HEAD or a 304
+    // revalidation doesn't use it up. If `auth.single_use_grace_secs` is set, the client that
+    // redeemed it may keep fetching parts of the report for that long.
+    let grace_secs = config().auth.single_use_grace_secs;
+    let single_use = match (query.single_use, query.signature.as_deref()) {
+        (true, Some(signature)) => {
+            let client = req
+                .peer_addr()
+                .map(|addr| addr.ip().to_string())
+                .unwrap_or_default();
+            let usable = is_usable(&pool, signature, &client, grace_secs)
+                .await
+                .map_err(AppError::internal)?;
+            if !usable {
+                return Err(AppError::InvalidSignature(String::from(
+                    "Download URL has already been used!",
+                )));
+            }
+            Some((signature, client))
+        }
+        _ => None,
+    };
+
+    let response = static_file::serve(
+        &req,
+        &**storage,
+        &format!("reports/{file_name}"),
+        CACHE_PRIVATE,
+    )
+    .await?
+    .ok_or_else(|| AppError::NotFound(String::from("Report not found!")))?;
+
+    if let Some((signature, client)) = single_use {
+        let sends_content = req.method() == Method::GET
+            && matches!(
+                response.status(),
+                StatusCode::OK | StatusCode::PARTIAL_CONTENT
+            );
+        if sends_content {
+            let expires = query.expires.unwrap_or_default();
+            let redeemed = redeem(&pool, signature, expires, &client, grace_secs)
+                .await
+                .map_err(AppError::internal)?;
+            if !redeemed {
+                return Err(AppError::InvalidSignature(String::from(
+                    "Download URL has already been used!",
+                )));
+            }
+        }
+    }
+    Ok(response)
 }
@@ -0,0 +1,23 @@
+use actix_web::{get, web, HttpResponse};
+use deadpool_postgres::Pool;
+
+use crate::utils::{
+    auth::Principal, common_struct::DataResponse, error::AppError, rate_limit::RateLimiter,
+};
+
+#[get("/api/usage")]
+pub async fn get_usage(
+    principal: web::ReqData<Principal>,
+    rate_limiter: web::Data<RateLimiter>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, AppError> {
+    let usage = rate_limiter
+        .usage(&pool, &principal.id)
+        .await
+        .map_err(AppError::internal)?;
+    Ok(HttpResponse::Ok().json(DataResponse {
+        code: 200,
+        message: String::from(""),
+        data: Some(usage),
+    }))
+}
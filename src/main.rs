@@ -1,10 +1,29 @@
 extern crate dotenv;
 
-use actix_cors::Cors;
-use actix_files as fs;
-use actix_web::{web, App, HttpServer};
+use std::time::Duration;
+
+use actix_web::{dev::ServerHandle, web, App, HttpServer};
 use dotenv::dotenv;
-use utils::setting;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info, warn};
+use utils::{
+    audit::{self, AuditLog},
+    auth,
+    cors::CorsConfig,
+    error::AppError,
+    health::HealthChecker,
+    logging,
+    metrics::Metrics,
+    query_cache::QueryCache,
+    rate_limit::{self, RateLimit, RateLimiter},
+    render_supervisor::RenderSupervisor,
+    request_id::RequestIdMiddleware,
+    retention, setting,
+    signed_url::{self, UrlSigner},
+    storage::{self, Storage},
+    tenant::{self, DataSources},
+    url_policy::UrlPolicy,
+};
 
 mod api;
 mod utils;
@@ -12,46 +31,147 @@ mod utils;
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenv().ok();
-    let port: u16 = setting::get_port();
-    let client = setting::get_postgres_connection().await;
-
-    HttpServer::new(move || {
-        // let default_size = env::var("DEFAULT_REQUEST_SIZE")
-        //     .unwrap_or_else(|_| "2097152".to_string())
-        //     .parse::<usize>()
-        //     .unwrap_or(2097152);
-        let cors = Cors::permissive() // This allows all origins. Be careful with this in production!
-            .allow_any_origin()
-            .allow_any_method()
-            .allow_any_header()
-            .max_age(3600);
-        if !std::fs::metadata("./temp").is_ok() {
-            if let Err(err) = std::fs::create_dir_all("./temp") {
-                println!("{:?}", err);
-            }
-        }
-        if !std::fs::metadata("./templates").is_ok() {
-            if let Err(err) = std::fs::create_dir_all("./templates") {
-                println!("{:?}", err);
-            }
-        }
-        if !std::fs::metadata("./reports").is_ok() {
-            if let Err(err) = std::fs::create_dir_all("./reports") {
-                println!("{:?}", err);
-            }
-        }
-        if !std::fs::metadata("./images").is_ok() {
-            if let Err(err) = std::fs::create_dir_all("./images") {
-                println!("{:?}", err);
-            }
+    let config = setting::init().unwrap_or_else(|err| {
+        eprint!("{}", err);
+        std::process::exit(1);
+    });
+    logging::init(&config.logging);
+    for (_, dir) in config.paths.all() {
+        if let Err(err) = std::fs::create_dir_all(dir) {
+            error!(dir, "Failed to create directory: {}", err);
         }
+    }
+    let pool = setting::get_postgres_pool();
+    tenant::init_schema(&pool)
+        .await
+        .expect("Failed to create tenants table");
+    auth::init_schema(&pool)
+        .await
+        .expect("Failed to create api_keys table");
+    signed_url::init_schema(&pool)
+        .await
+        .expect("Failed to create used_signatures table");
+    audit::init_schema(&pool)
+        .await
+        .expect("Failed to create audit_log table");
+    rate_limit::init_schema(&pool)
+        .await
+        .expect("Failed to create daily_usage table");
+    let url_signer = web::Data::new(UrlSigner::new(config.auth.url_signing_secret().as_bytes()));
+    let url_policy = web::Data::new(UrlPolicy::from_settings());
+    let cors_config = CorsConfig::from_settings();
+    let rate_limiter = web::Data::new(RateLimiter::from_settings());
+    let template_pool = setting::get_template_pool();
+    check_template_pool(template_pool.as_ref()).await;
+    let data_sources = web::Data::new(DataSources::new(template_pool));
+    let health = web::Data::new(HealthChecker::default());
+    let metrics = web::Data::new(Metrics::new().expect("Failed to create metrics"));
+    let supervisor = web::Data::new(RenderSupervisor::default());
+    let storage: web::Data<dyn Storage> = web::Data::from(storage::from_settings().await);
+    let query_cache = web::Data::new(QueryCache::new(
+        config.query_cache.max_entries,
+        config.query_cache.max_bytes,
+    ));
+    retention::spawn(
+        storage.clone().into_inner(),
+        &config.paths,
+        &config.retention,
+    );
+
+    info!(bind = %config.server.bind, port = config.server.port, "Starting server");
+    // The app factory takes its own copy of the supervisor
+    let draining = supervisor.clone();
+    let server = HttpServer::new(move || {
+        let cors = cors_config.build();
         App::new()
+            .wrap(RateLimit::PerPrincipal)
+            .wrap(auth::ApiKeyAuth)
+            .wrap(RateLimit::PerIp)
+            .wrap(AuditLog)
+            .wrap(RequestIdMiddleware)
             .wrap(cors)
-            .app_data(web::Data::new(client.clone()))
+            .app_data(web::Data::new(pool.clone()))
+            .app_data(data_sources.clone())
+            .app_data(query_cache.clone())
+            .app_data(url_signer.clone())
+            .app_data(url_policy.clone())
+            .app_data(rate_limiter.clone())
+            .app_data(metrics.clone())
+            .app_data(health.clone())
+            .app_data(supervisor.clone())
+            .app_data(storage.clone())
+            // Malformed requests get the same error body as everything else
+            .app_data(
+                web::JsonConfig::default()
+                    .limit(config.limits.max_request_bytes)
+                    .error_handler(|err, _| AppError::InvalidRequest(err.to_string()).into()),
+            )
+            .app_data(
+                web::QueryConfig::default()
+                    .error_handler(|err, _| AppError::InvalidRequest(err.to_string()).into()),
+            )
+            .app_data(
+                web::PathConfig::default()
+                    .error_handler(|err, _| AppError::InvalidRequest(err.to_string()).into()),
+            )
             .configure(api::init)
-            .service(fs::Files::new("/temp", "./temp").show_files_listing())
     })
-    .bind(("0.0.0.0", port))?
-    .run()
-    .await
+    // Signals are handled below, so renders are drained before the server stops
+    .disable_signals()
+    .shutdown_timeout(config.server.shutdown_timeout + 5)
+    .bind((config.server.bind.as_str(), config.server.port))?
+    .run();
+
+    actix_web::rt::spawn(shutdown_on_signal(
+        server.handle(),
+        draining.clone(),
+        Duration::from_secs(config.server.shutdown_timeout),
+    ));
+    server.await?;
+    // Nothing may outlive the process
+    draining.kill_all();
+    info!("Server stopped");
+    Ok(())
+}
+
+// Template SQL must not reach the service tables, so a template connection whose role can
+// access them is refused
+async fn check_template_pool(pool: Option<&deadpool_postgres::Pool>) {
+    let Some(pool) = pool else {
+        info!("database.template_connection is not set, sql blocks only work for tenants with their own database");
+        return;
+    };
+    match tenant::accessible_service_tables(pool).await {
+        Ok(tables) if !tables.is_empty() => {
+            error!(
+                tables = tables.join(", "),
+                "The role of database.template_connection can access service tables, use a role without privileges on them"
+            );
+            std::process::exit(1);
+        }
+        Ok(_) => {}
+        Err(err) => warn!(
+            "Could not check the role of database.template_connection: {}",
+            err
+        ),
+    }
+}
+
+// On SIGTERM or Ctrl-C, stops accepting connections and gives running renders until the
+// deadline to finish. Renders killed at the deadline are answered with `shutting_down`.
+async fn shutdown_on_signal(
+    server: ServerHandle,
+    supervisor: web::Data<RenderSupervisor>,
+    deadline: Duration,
+) {
+    let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+    info!(
+        deadline_secs = deadline.as_secs(),
+        "Shutting down, draining running renders"
+    );
+    tokio::join!(supervisor.drain(deadline), server.stop(true));
 }
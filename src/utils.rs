@@ -1,4 +1,25 @@
+pub mod audit;
+pub mod auth;
 pub mod common_struct;
+pub mod cors;
+pub mod dataset;
+pub mod error;
+pub mod health;
 pub mod html_parser;
 pub mod image;
+pub mod logging;
+pub mod metrics;
+pub mod query_cache;
+pub mod rate_limit;
+pub mod render_proxy;
+pub mod render_supervisor;
+pub mod request_id;
+pub mod retention;
 pub mod setting;
+pub mod signed_url;
+pub mod staged_files;
+pub mod static_file;
+pub mod storage;
+pub mod tenant;
+pub mod upload;
+pub mod url_policy;
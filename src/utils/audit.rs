@@ -0,0 +1,334 @@
+use std::{
+    cell::RefCell,
+    future::{ready, Ready},
+    rc::Rc,
+};
+
+use actix_web::{
+    body::{to_bytes, BoxBody, MessageBody},
+    dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform},
+    http::{header, Method},
+    web, Error, HttpMessage,
+};
+use chrono::{DateTime, Utc};
+use deadpool_postgres::Pool;
+use futures::{future::LocalBoxFuture, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tokio_postgres::Row;
+use tracing::error;
+
+use crate::utils::auth::Principal;
+
+// Only this much of a JSON request or response is kept to find the audit target
+const MAX_INSPECTED_BODY: usize = 64 * 1024;
+
+// Actor of requests that were turned away before a key was accepted
+const ANONYMOUS: &str = "anonymous";
+
+#[derive(Serialize)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub actor: String,
+    pub actor_name: String,
+    pub tenant_id: Option<String>,
+    pub action: String,
+    pub target: Option<String>,
+    // SHA-256 of the query string and request body
+    pub params_digest: String,
+    pub ip: Option<String>,
+    pub status: i32,
+    pub outcome: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<&Row> for AuditEntry {
+    fn from(row: &Row) -> Self {
+        AuditEntry {
+            id: row.get("id"),
+            actor: row.get("actor"),
+            actor_name: row.get("actor_name"),
+            tenant_id: row.get("tenant_id"),
+            action: row.get("action"),
+            target: row.get("target"),
+            params_digest: row.get("params_digest"),
+            ip: row.get("ip"),
+            status: row.get("status"),
+            outcome: row.get("outcome"),
+            created_at: row.get("created_at"),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct AuditFilter {
+    pub actor: Option<String>,
+    pub action: Option<String>,
+    pub tenant_id: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+// Filters are optional; a NULL parameter matches every row
+pub const FILTERED_AUDIT_LOG: &str = "FROM audit_log
+     WHERE ($1::text IS NULL OR actor = $1)
+       AND ($2::text IS NULL OR action = $2)
+       AND ($3::text IS NULL OR tenant_id = $3)
+       AND ($4::timestamptz IS NULL OR created_at >= $4)
+       AND ($5::timestamptz IS NULL OR created_at < $5)";
+
+pub const AUDIT_COLUMNS: &str =
+    "id, actor, actor_name, tenant_id, action, target, params_digest, ip, status, outcome, created_at";
+
+pub async fn init_schema(pool: &Pool) -> Result<(), Box<dyn std::error::Error>> {
+    let client = pool.get().await?;
+    client
+        .batch_execute(
+            "CREATE TABLE IF NOT EXISTS audit_log (
+                id bigserial PRIMARY KEY,
+                actor text NOT NULL,
+                actor_name text NOT NULL,
+                tenant_id text,
+                action text NOT NULL,
+                target text,
+                params_digest text NOT NULL,
+                ip text,
+                status integer NOT NULL,
+                outcome text NOT NULL,
+                created_at timestamptz NOT NULL DEFAULT now()
+            );
+            CREATE INDEX IF NOT EXISTS audit_log_created_at ON audit_log (created_at);
+            CREATE OR REPLACE FUNCTION audit_log_append_only() RETURNS trigger AS $$
+            BEGIN
+                RAISE EXCEPTION 'audit_log is append-only';
+            END;
+            $$ LANGUAGE plpgsql;
+            DO $$
+            BEGIN
+                IF NOT EXISTS (SELECT 1 FROM pg_trigger WHERE tgname = 'audit_log_no_changes') THEN
+                    CREATE TRIGGER audit_log_no_changes BEFORE UPDATE OR DELETE ON audit_log
+                        FOR EACH ROW EXECUTE FUNCTION audit_log_append_only();
+                END IF;
+                IF NOT EXISTS (SELECT 1 FROM pg_trigger WHERE tgname = 'audit_log_no_truncate') THEN
+                    CREATE TRIGGER audit_log_no_truncate BEFORE TRUNCATE ON audit_log
+                        FOR EACH STATEMENT EXECUTE FUNCTION audit_log_append_only();
+                END IF;
+            END
+            $$;",
+        )
+        .await?;
+    Ok(())
+}
+
+fn outcome(status: u16) -> &'static str {
+    match status {
+        200..=399 => "success",
+        401 | 403 | 429 => "denied",
+        _ => "failure",
+    }
+}
+
+// The target is what the action was about: the template or url that was rendered, the
+// key or template named in the path, or the file an upload created. Query strings are
+// dropped, they hold signatures of returned urls and possibly secrets of rendered ones.
+fn find_target(request: &Value, path_params: &[String], response: &Value) -> Option<String> {
+    let field = |value: &Value, key: &str| value.get(key).and_then(Value::as_str).map(String::from);
+    field(request, "template_name")
+        .or_else(|| field(request, "url"))
+        .or_else(|| (!path_params.is_empty()).then(|| path_params.join("/")))
+        .or_else(|| field(response, "url"))
+        .or_else(|| field(response, "name"))
+        .or_else(|| field(response, "data"))
+        .map(|target| match target.find(['?', '#']) {
+            Some(end) => target[..end].to_string(),
+            None => target,
+        })
+}
+
+/// Middleware recording every state changing request, i.e. anything but `GET`, in the
+/// append-only audit log: who did it, what it was about, a digest of the parameters, the
+/// client IP and how it ended. Requests turned away with `401` or `429` are recorded too,
+/// so it has to wrap the authentication and rate limit middlewares.
+pub struct AuditLog;
+
+impl<S, B> Transform<S, ServiceRequest> for AuditLog
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type Transform = AuditLogMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AuditLogMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct AuditLogMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for AuditLogMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+
+        Box::pin(async move {
+            let is_get = req.method() == Method::GET;
+            let pool = req.app_data::<web::Data<Pool>>().cloned();
+
+            // The body is hashed while the handler reads it, so uploads aren't buffered
+            let hasher = Rc::new(RefCell::new(Sha256::new()));
+            hasher.borrow_mut().update(req.query_string().as_bytes());
+            let is_json = req.content_type() == "application/json";
+            let inspected = Rc::new(RefCell::new(Vec::new()));
+            let payload = {
+                let hasher = hasher.clone();
+                let inspected = inspected.clone();
+                req.take_payload().map(move |chunk| {
+                    if let Ok(bytes) = &chunk {
+                        hasher.borrow_mut().update(bytes);
+                        let mut inspected = inspected.borrow_mut();
+                        if is_json && inspected.len() < MAX_INSPECTED_BODY {
+                            inspected.extend_from_slice(bytes);
+                        }
+                    }
+                    chunk
+                })
+            };
+            req.set_payload(Payload::Stream {
+                payload: Box::pin(payload),
+            });
+
+            let action = req.match_name().unwrap_or(req.path()).to_string();
+            let ip = req.peer_addr().map(|addr| addr.ip().to_string());
+
+            let res = service.call(req).await?;
+            let status = res.status().as_u16();
+            // The principal is only known once authentication ran
+            let principal = res.request().extensions().get::<Principal>().cloned();
+            let rejected = matches!(status, 401 | 429);
+            if !rejected && (principal.is_none() || is_get) {
+                return Ok(res.map_into_boxed_body());
+            }
+            // Path parameters are only known once the request has been routed
+            let path_params: Vec<String> = res
+                .request()
+                .match_info()
+                .iter()
+                .map(|(_, value)| value.to_string())
+                .collect();
+
+            // Handlers answer with small JSON bodies, which may name the created resource
+            let response_is_json = res
+                .headers()
+                .get(header::CONTENT_TYPE)
+                .is_some_and(|value| value.as_bytes().starts_with(b"application/json"));
+            let (res, response) = if response_is_json {
+                let (req, res) = res.into_parts();
+                let (res, body) = res.into_parts();
+                let bytes = match to_bytes(body).await {
+                    Ok(bytes) => bytes,
+                    Err(err) => return Err(actix_web::error::ErrorInternalServerError(err.into())),
+                };
+                let response: Value = serde_json::from_slice(&bytes).unwrap_or_default();
+                (
+                    ServiceResponse::new(req, res.set_body(BoxBody::new(bytes))),
+                    response,
+                )
+            } else {
+                (res.map_into_boxed_body(), Value::Null)
+            };
+
+            let request: Value = serde_json::from_slice(&inspected.borrow()).unwrap_or_default();
+            let target = find_target(&request, &path_params, &response);
+            let params_digest = hex::encode(hasher.borrow().clone().finalize());
+
+            if let Some(pool) = pool {
+                let (actor, actor_name, tenant_id) = match &principal {
+                    Some(principal) => (
+                        principal.id.as_str(),
+                        principal.name.as_str(),
+                        principal.tenant.as_ref().map(|tenant| tenant.id.clone()),
+                    ),
+                    None => (ANONYMOUS, ANONYMOUS, None),
+                };
+                let result = async {
+                    let client = pool.get().await?;
+                    client
+                        .execute(
+                            "INSERT INTO audit_log
+                                (actor, actor_name, tenant_id, action, target, params_digest,
+                                 ip, status, outcome)
+                             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+                            &[
+                                &actor,
+                                &actor_name,
+                                &tenant_id,
+                                &action,
+                                &target,
+                                &params_digest,
+                                &ip,
+                                &(status as i32),
+                                &outcome(status),
+                            ],
+                        )
+                        .await?;
+                    Ok::<_, Box<dyn std::error::Error>>(())
+                }
+                .await;
+                if let Err(err) = result {
+                    error!("Failed to write audit log: {:?}", err);
+                }
+            }
+
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn targets_never_keep_query_strings() {
+        let target = |request: Value, response: Value| find_target(&request, &[], &response);
+        assert_eq!(
+            target(
+                json!({ "url": "https://example.com/a?token=secret#x" }),
+                Value::Null
+            ),
+            Some(String::from("https://example.com/a"))
+        );
+        assert_eq!(
+            target(
+                json!({ "content": "<p>hi</p>" }),
+                json!({ "data": "/reports/a.pdf?expires=1&signature=abc" })
+            ),
+            Some(String::from("/reports/a.pdf"))
+        );
+        assert_eq!(
+            target(json!({ "template_name": "invoice.html" }), Value::Null),
+            Some(String::from("invoice.html"))
+        );
+        assert_eq!(target(Value::Null, Value::Null), None);
+    }
+}
@@ -0,0 +1,606 @@
+use std::{
+    future::{ready, Ready},
+    rc::Rc,
+};
+
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::header,
+    web, Error, HttpMessage, ResponseError,
+};
+use deadpool_postgres::Pool;
+use futures::future::LocalBoxFuture;
+use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::utils::{
+    error::AppError,
+    setting::config,
+    tenant::{get_tenant, Tenant},
+};
+
+pub const SCOPE_RENDER_URL: &str = "render:url";
+pub const SCOPE_RENDER_TEMPLATE: &str = "render:template";
+pub const SCOPE_TEMPLATES_WRITE: &str = "templates:write";
+pub const SCOPE_REPORTS_READ: &str = "reports:read";
+pub const SCOPE_METRICS_READ: &str = "metrics:read";
+pub const SCOPE_ADMIN: &str = "admin";
+
+pub const ALL_SCOPES: [&str; 6] = [
+    SCOPE_RENDER_URL,
+    SCOPE_RENDER_TEMPLATE,
+    SCOPE_TEMPLATES_WRITE,
+    SCOPE_REPORTS_READ,
+    SCOPE_METRICS_READ,
+    SCOPE_ADMIN,
+];
+
+// Uploaded images which templates link to, and report downloads which are authorized by
+// their signature instead of a key
+const PUBLIC_PATH_PREFIXES: [&str; 3] = ["/images/", "/storage/", "/reports/"];
+
+// Probes of the orchestrator, which has no key
+const PUBLIC_PATHS: [&str; 2] = ["/healthz", "/readyz"];
+
+/// The authenticated caller of a request, available to handlers as `web::ReqData<Principal>`.
+#[derive(Clone, Debug)]
+pub struct Principal {
+    // Stable identifier of the client, used for rate limits and usage accounting
+    pub id: String,
+    pub name: String,
+    pub scopes: Vec<String>,
+    // Keys without a tenant work on the shared, top level namespace
+    pub tenant: Option<Tenant>,
+}
+
+impl Principal {
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|s| s == scope || s == SCOPE_ADMIN)
+    }
+
+    pub fn require_scope(&self, scope: &str) -> Result<(), AppError> {
+        if self.has_scope(scope) {
+            return Ok(());
+        }
+        Err(AppError::Forbidden(format!(
+            "'{}' is missing the {} scope!",
+            self.name, scope
+        )))
+    }
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+    #[serde(default)]
+    scopes: Vec<String>,
+    #[serde(default)]
+    scope: Option<String>,
+    #[serde(default)]
+    tenant: Option<String>,
+}
+
+pub fn hash_api_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+pub fn generate_api_key() -> String {
+    format!("rf_{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+pub async fn init_schema(pool: &Pool) -> Result<(), Box<dyn std::error::Error>> {
+    let client = pool.get().await?;
+    client
+        .batch_execute(
+            "CREATE TABLE IF NOT EXISTS api_keys (
+                id uuid PRIMARY KEY,
+                name text NOT NULL,
+                key_hash text NOT NULL UNIQUE,
+                scopes text[] NOT NULL,
+                created_at timestamptz NOT NULL DEFAULT now(),
+                revoked_at timestamptz
+            );
+            ALTER TABLE api_keys ADD COLUMN IF NOT EXISTS tenant_id text REFERENCES tenants(id);",
+        )
+        .await?;
+    Ok(())
+}
+
+async fn principal_from_api_key(
+    pool: &Pool,
+    key: &str,
+) -> Result<Option<Principal>, Box<dyn std::error::Error>> {
+    let key_hash = hash_api_key(key);
+
+    // The bootstrap admin key from the configuration is needed to create the first keys
+    if let Some(admin_key) = &config().auth.admin_api_key {
+        if hash_api_key(admin_key) == key_hash {
+            return Ok(Some(Principal {
+                id: String::from("admin"),
+                name: String::from("admin"),
+                scopes: vec![SCOPE_ADMIN.to_string()],
+                tenant: None,
+            }));
+        }
+    }
+
+    let client = pool.get().await?;
+    let row = client
+        .query_opt(
+            "SELECT id, name, scopes, tenant_id FROM api_keys
+             WHERE key_hash = $1 AND revoked_at IS NULL",
+            &[&key_hash],
+        )
+        .await?;
+    let Some(row) = row else {
+        return Ok(None);
+    };
+
+    let tenant_id: Option<String> = row.get("tenant_id");
+    let tenant = match tenant_id {
+        Some(tenant_id) => match get_tenant(pool, &tenant_id).await? {
+            Some(tenant) => Some(tenant),
+            // Like tokens, keys of a tenant that is gone must not fall back to the shared namespace
+            None => return Ok(None),
+        },
+        None => None,
+    };
+    let id: Uuid = row.get("id");
+    Ok(Some(Principal {
+        id: format!("key:{}", id),
+        name: row.get("name"),
+        scopes: row.get("scopes"),
+        tenant,
+    }))
+}
+
+// JWT bearer tokens are accepted when `auth.jwt_secret` is set. Scopes come from either a
+// `scopes` array or a space separated `scope` claim, the tenant from a `tenant` claim.
+async fn principal_from_jwt(
+    pool: &Pool,
+    token: &str,
+) -> Result<Option<Principal>, Box<dyn std::error::Error>> {
+    let Some(secret) = &config().auth.jwt_secret else {
+        return Ok(None);
+    };
+    let data = match decode::<Claims>(
+        token,
+        &DecodingKey::from_secret(secret.as_bytes()),
+        &Validation::new(Algorithm::HS256),
+    ) {
+        Ok(data) => data,
+        Err(_) => return Ok(None),
+    };
+
+    let mut scopes = data.claims.scopes;
+    if let Some(scope) = data.claims.scope {
+        scopes.extend(scope.split_whitespace().map(String::from));
+    }
+    let tenant = match &data.claims.tenant {
+        Some(tenant_id) => match get_tenant(pool, tenant_id).await? {
+            Some(tenant) => Some(tenant),
+            // A token for an unknown tenant must not fall back to the shared namespace
+            None => return Ok(None),
+        },
+        None => None,
+    };
+    Ok(Some(Principal {
+        id: format!("jwt:{}", data.claims.sub),
+        name: data.claims.sub,
+        scopes,
+        tenant,
+    }))
+}
+
+async fn authenticate(
+    req: &ServiceRequest,
+) -> Result<Option<Principal>, Box<dyn std::error::Error>> {
+    let pool = match req.app_data::<web::Data<Pool>>() {
+        Some(pool) => pool.clone(),
+        None => return Err("Database pool is not configured".into()),
+    };
+    let headers = req.headers();
+
+    if let Some(key) = headers.get("X-API-Key").and_then(|v| v.to_str().ok()) {
+        return principal_from_api_key(&pool, key).await;
+    }
+
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::trim);
+    match bearer {
+        // A JWT has three dot separated parts, anything else is treated as an API key
+        Some(token) if token.matches('.').count() == 2 => principal_from_jwt(&pool, token).await,
+        Some(token) => principal_from_api_key(&pool, token).await,
+        None => Ok(None),
+    }
+}
+
+/// Middleware that rejects requests without a valid API key or JWT bearer token and makes
+/// the resulting [`Principal`] available to handlers. Scopes are checked by the handlers.
+pub struct ApiKeyAuth;
+
+impl<S, B> Transform<S, ServiceRequest> for ApiKeyAuth
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = ApiKeyAuthMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ApiKeyAuthMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct ApiKeyAuthMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for ApiKeyAuthMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+
+        Box::pin(async move {
+            let path = req.path();
+            if PUBLIC_PATHS.contains(&path)
+                || PUBLIC_PATH_PREFIXES
+                    .iter()
+                    .any(|prefix| path.starts_with(prefix))
+            {
+                return Ok(service.call(req).await?.map_into_left_body());
+            }
+
+            let response = match authenticate(&req).await {
+                Ok(Some(principal)) => {
+                    req.extensions_mut().insert(principal);
+                    return Ok(service.call(req).await?.map_into_left_body());
+                }
+                Ok(None) => AppError::Unauthorized(String::from(
+                    "A valid API key or bearer token is required!",
+                )),
+                Err(err) => AppError::internal(err),
+            };
+            Ok(req
+                .into_response(response.error_response())
+                .map_into_right_body())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use actix_web::{
+        get,
+        http::StatusCode,
+        test::{self, TestRequest},
+        App, HttpResponse,
+    };
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::{
+        api,
+        utils::{
+            setting::{create_postgres_pool, testing, PathsConfig},
+            signed_url::UrlSigner,
+            storage::{LocalStorage, Storage},
+            tenant,
+        },
+    };
+
+    #[get("/api/whoami")]
+    async fn whoami(principal: web::ReqData<Principal>) -> Result<HttpResponse, AppError> {
+        principal.require_scope(SCOPE_RENDER_URL)?;
+        Ok(HttpResponse::Ok().json(json!({
+            "id": principal.id,
+            "scopes": principal.scopes,
+            "tenant": principal.tenant.as_ref().map(|tenant| &tenant.id),
+        })))
+    }
+
+    // The database is only reached for keys, tenants and single use URLs; without
+    // `TEST_DB_CONNECTION` the pool points nowhere
+    fn pool() -> Pool {
+        testing::pool()
+            .unwrap_or_else(|| create_postgres_pool("host=/nonexistent user=none").unwrap())
+    }
+
+    fn signer() -> UrlSigner {
+        UrlSigner::new(b"secret")
+    }
+
+    // A local storage holding a report and an image
+    async fn storage() -> (Arc<dyn Storage>, std::path::PathBuf) {
+        let base = std::env::temp_dir().join(format!("auth-{}", Uuid::new_v4()));
+        let dir = |name: &str| base.join(name).to_string_lossy().to_string();
+        let paths = PathsConfig {
+            temp: dir("temp"),
+            templates: dir("templates"),
+            reports: dir("reports"),
+            images: dir("images"),
+            datasets: dir("datasets"),
+        };
+        std::fs::create_dir_all(&paths.temp).unwrap();
+        let storage = LocalStorage::new(&paths);
+        for key in ["reports/a.pdf", "images/a.png"] {
+            let upload = base.join("temp/upload");
+            std::fs::write(&upload, key).unwrap();
+            storage.put(key, &upload).await.unwrap();
+        }
+        (Arc::new(storage), base)
+    }
+
+    async fn status_of(pool: Pool, storage: Arc<dyn Storage>, req: TestRequest) -> StatusCode {
+        let app = test::init_service(
+            App::new()
+                .wrap(ApiKeyAuth)
+                .app_data(web::Data::new(pool))
+                .app_data(web::Data::new(signer()))
+                .app_data(web::Data::from(storage))
+                .service(whoami)
+                .configure(api::init),
+        )
+        .await;
+        test::call_service(&app, req.to_request()).await.status()
+    }
+
+    // The principal the middleware hands to handlers for `req`, as `/api/whoami` returns it
+    async fn whoami_of(pool: Pool, req: TestRequest) -> (StatusCode, Value) {
+        let app = test::init_service(
+            App::new()
+                .wrap(ApiKeyAuth)
+                .app_data(web::Data::new(pool))
+                .service(whoami),
+        )
+        .await;
+        let response = test::call_service(&app, req.uri("/api/whoami").to_request()).await;
+        let status = response.status();
+        let body = test::read_body(response).await;
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    fn bearer(token: &str) -> (header::HeaderName, String) {
+        (header::AUTHORIZATION, format!("Bearer {token}"))
+    }
+
+    fn jwt(claims: Value) -> String {
+        encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(testing::JWT_SECRET.as_bytes()),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn admin_implies_every_scope() {
+        let principal = |scopes: &[&str]| Principal {
+            id: String::from("key:1"),
+            name: String::from("billing"),
+            scopes: scopes.iter().map(|scope| scope.to_string()).collect(),
+            tenant: None,
+        };
+        let renderer = principal(&[SCOPE_RENDER_URL]);
+        assert!(renderer.require_scope(SCOPE_RENDER_URL).is_ok());
+        assert!(matches!(
+            renderer.require_scope(SCOPE_TEMPLATES_WRITE),
+            Err(AppError::Forbidden(_))
+        ));
+        let admin = principal(&[SCOPE_ADMIN]);
+        assert!(ALL_SCOPES
+            .iter()
+            .all(|scope| admin.require_scope(scope).is_ok()));
+    }
+
+    #[actix_web::test]
+    async fn public_paths_skip_authentication() {
+        testing::config();
+        let (storage, base) = storage().await;
+        let status = |req: TestRequest| status_of(pool(), storage.clone(), req);
+
+        assert_eq!(
+            status(TestRequest::get().uri("/healthz")).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(TestRequest::get().uri("/api/whoami")).await,
+            StatusCode::UNAUTHORIZED
+        );
+        // Images can be linked from templates, their names can't be guessed
+        for uri in ["/images/a.png", "/storage/a.png"] {
+            assert_eq!(status(TestRequest::get().uri(uri)).await, StatusCode::OK);
+        }
+        std::fs::remove_dir_all(base).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn public_paths_only_serve_signed_reports() {
+        testing::config();
+        let (storage, base) = storage().await;
+        let status = |uri: String| status_of(pool(), storage.clone(), TestRequest::get().uri(&uri));
+
+        let signed = signer().sign("/reports/a.pdf", 60, false);
+        assert_eq!(status(signed.clone()).await, StatusCode::OK);
+        assert_eq!(
+            status(String::from("/reports/a.pdf")).await,
+            StatusCode::FORBIDDEN
+        );
+        let tampered = signed.replace("/reports/a.pdf", "/reports/b.pdf");
+        assert_eq!(status(tampered).await, StatusCode::FORBIDDEN);
+        let forged = UrlSigner::new(b"guess").sign("/reports/a.pdf", 60, false);
+        assert_eq!(status(forged).await, StatusCode::FORBIDDEN);
+        // The image routes only reach images
+        for uri in [
+            "/images/reports/a.pdf",
+            "/storage/reports/a.pdf",
+            "/images/../reports/a.pdf",
+            "/storage/%2E%2E/reports/a.pdf",
+        ] {
+            assert_eq!(
+                status(uri.to_string()).await,
+                StatusCode::NOT_FOUND,
+                "{uri}"
+            );
+        }
+        std::fs::remove_dir_all(base).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn rejects_missing_and_invalid_credentials() {
+        testing::config();
+        let requests = [
+            TestRequest::get(),
+            TestRequest::get().insert_header(bearer("not.a.jwt")),
+            TestRequest::get().insert_header(bearer(
+                &encode(
+                    &Header::default(),
+                    &json!({ "sub": "x", "scopes": [SCOPE_ADMIN], "exp": 4102444800u64 }),
+                    &EncodingKey::from_secret(b"guess"),
+                )
+                .unwrap(),
+            )),
+            // Expired
+            TestRequest::get().insert_header(bearer(&jwt(
+                json!({ "sub": "x", "scopes": [SCOPE_ADMIN], "exp": 1 }),
+            ))),
+        ];
+        for req in requests {
+            assert_eq!(whoami_of(pool(), req).await.0, StatusCode::UNAUTHORIZED);
+        }
+    }
+
+    #[actix_web::test]
+    async fn checks_scopes() {
+        testing::config();
+        let admin = TestRequest::get().insert_header(("X-API-Key", testing::ADMIN_API_KEY));
+        assert_eq!(whoami_of(pool(), admin).await.0, StatusCode::OK);
+
+        let token =
+            jwt(json!({ "sub": "x", "scopes": [SCOPE_REPORTS_READ], "exp": 4102444800u64 }));
+        let req = TestRequest::get().insert_header(bearer(&token));
+        assert_eq!(whoami_of(pool(), req).await.0, StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn merges_jwt_scope_claims() {
+        testing::config();
+        let token = jwt(json!({
+            "sub": "billing",
+            "scopes": [SCOPE_REPORTS_READ],
+            "scope": format!("{SCOPE_RENDER_URL}  {SCOPE_METRICS_READ}"),
+            "exp": 4102444800u64,
+        }));
+        let req = TestRequest::get().insert_header(bearer(&token));
+        let (status, principal) = whoami_of(pool(), req).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            principal,
+            json!({
+                "id": "jwt:billing",
+                "scopes": [SCOPE_REPORTS_READ, SCOPE_RENDER_URL, SCOPE_METRICS_READ],
+                "tenant": null,
+            })
+        );
+    }
+
+    #[actix_web::test]
+    async fn checks_keys_and_tenants_against_the_database() {
+        let Some(pool) = testing::pool() else {
+            return;
+        };
+        tenant::init_schema(&pool).await.unwrap();
+        init_schema(&pool).await.unwrap();
+        let client = pool.get().await.unwrap();
+        let tenant_id = format!("auth-test-{}", Uuid::new_v4().simple());
+        client
+            .execute(
+                "INSERT INTO tenants (id, name, storage_prefix) VALUES ($1, $1, $1)",
+                &[&tenant_id],
+            )
+            .await
+            .unwrap();
+        let key = generate_api_key();
+        let key_id = Uuid::new_v4();
+        client
+            .execute(
+                "INSERT INTO api_keys (id, name, key_hash, scopes, tenant_id)
+                 VALUES ($1, 'auth-test', $2, $3, $4)",
+                &[
+                    &key_id,
+                    &hash_api_key(&key),
+                    &vec![SCOPE_RENDER_URL],
+                    &tenant_id,
+                ],
+            )
+            .await
+            .unwrap();
+
+        let with_key = || TestRequest::get().insert_header(("X-API-Key", key.as_str()));
+        let (status, principal) = whoami_of(pool.clone(), with_key()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(principal["tenant"], json!(tenant_id));
+        let as_bearer = TestRequest::get().insert_header(bearer(&key));
+        assert_eq!(whoami_of(pool.clone(), as_bearer).await.0, StatusCode::OK);
+        for unknown in [
+            TestRequest::get().insert_header(("X-API-Key", "rf_unknown")),
+            TestRequest::get().insert_header(bearer("")),
+        ] {
+            assert_eq!(
+                whoami_of(pool.clone(), unknown).await.0,
+                StatusCode::UNAUTHORIZED
+            );
+        }
+
+        let claims = |tenant: &str| {
+            jwt(json!({
+                "sub": "x",
+                "scopes": [SCOPE_RENDER_URL],
+                "tenant": tenant,
+                "exp": 4102444800u64,
+            }))
+        };
+        let req = TestRequest::get().insert_header(bearer(&claims(&tenant_id)));
+        assert_eq!(whoami_of(pool.clone(), req).await.0, StatusCode::OK);
+        let req = TestRequest::get().insert_header(bearer(&claims("no-such-tenant")));
+        assert_eq!(
+            whoami_of(pool.clone(), req).await.0,
+            StatusCode::UNAUTHORIZED
+        );
+
+        client
+            .execute(
+                "UPDATE api_keys SET revoked_at = now() WHERE id = $1",
+                &[&key_id],
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            whoami_of(pool.clone(), with_key()).await.0,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+}
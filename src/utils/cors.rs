@@ -0,0 +1,205 @@
+use actix_cors::Cors;
+
+use crate::utils::setting::config;
+
+/// Cross origin settings. Without configured origins every cross origin request is rejected.
+#[derive(Clone, Debug)]
+pub struct CorsConfig {
+    // `*` allows any origin
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    pub allow_credentials: bool,
+    pub max_age: usize,
+}
+
+impl CorsConfig {
+    pub fn from_settings() -> Self {
+        let settings = &config().cors;
+        CorsConfig {
+            allowed_origins: settings.allowed_origins.clone(),
+            allowed_methods: settings.allowed_methods.clone(),
+            allowed_headers: settings.allowed_headers.clone(),
+            allow_credentials: settings.allow_credentials,
+            max_age: settings.max_age,
+        }
+    }
+
+    pub fn build(&self) -> Cors {
+        let mut cors = Cors::default()
+            .allowed_methods(self.allowed_methods.iter().map(String::as_str))
+            .allowed_headers(self.allowed_headers.iter().map(String::as_str))
+            .max_age(self.max_age);
+        for origin in &self.allowed_origins {
+            cors = if origin == "*" {
+                cors.allow_any_origin()
+            } else {
+                cors.allowed_origin(origin)
+            };
+        }
+        if self.allow_credentials {
+            cors = cors.supports_credentials();
+        }
+        cors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{
+        http::{header, Method, StatusCode},
+        test, web, App, HttpResponse,
+    };
+
+    fn config(origins: &[&str]) -> CorsConfig {
+        CorsConfig {
+            allowed_origins: origins.iter().map(|o| o.to_string()).collect(),
+            allowed_methods: vec![String::from("GET"), String::from("POST")],
+            allowed_headers: vec![String::from("Content-Type"), String::from("X-API-Key")],
+            allow_credentials: false,
+            max_age: 600,
+        }
+    }
+
+    async fn preflight(
+        config: &CorsConfig,
+        origin: &str,
+        method: &str,
+        headers: &str,
+    ) -> actix_web::dev::ServiceResponse {
+        let app = test::init_service(
+            App::new()
+                .wrap(config.build())
+                .route("/api/process-report", web::post().to(HttpResponse::Ok)),
+        )
+        .await;
+        let req = test::TestRequest::default()
+            .method(Method::OPTIONS)
+            .uri("/api/process-report")
+            .insert_header((header::ORIGIN, origin))
+            .insert_header((header::ACCESS_CONTROL_REQUEST_METHOD, method))
+            .insert_header((header::ACCESS_CONTROL_REQUEST_HEADERS, headers))
+            .to_request();
+        test::call_service(&app, req).await.map_into_boxed_body()
+    }
+
+    fn header_value(res: &actix_web::dev::ServiceResponse, name: header::HeaderName) -> String {
+        res.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_string()
+    }
+
+    #[actix_web::test]
+    async fn preflight_from_allowed_origin_succeeds() {
+        let res = preflight(
+            &config(&["https://app.example.com"]),
+            "https://app.example.com",
+            "POST",
+            "content-type,x-api-key",
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            header_value(&res, header::ACCESS_CONTROL_ALLOW_ORIGIN),
+            "https://app.example.com"
+        );
+        assert_eq!(header_value(&res, header::ACCESS_CONTROL_MAX_AGE), "600");
+        assert!(res
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_CREDENTIALS)
+            .is_none());
+    }
+
+    #[actix_web::test]
+    async fn default_config_rejects_every_origin() {
+        let res = preflight(
+            &CorsConfig {
+                allowed_origins: vec![],
+                ..config(&[])
+            },
+            "https://app.example.com",
+            "POST",
+            "content-type",
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert!(res
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .is_none());
+    }
+
+    #[actix_web::test]
+    async fn preflight_from_unknown_origin_is_rejected() {
+        let res = preflight(
+            &config(&["https://app.example.com"]),
+            "https://evil.example.com",
+            "POST",
+            "content-type",
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn preflight_with_disallowed_method_is_rejected() {
+        let res = preflight(
+            &config(&["https://app.example.com"]),
+            "https://app.example.com",
+            "DELETE",
+            "content-type",
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn preflight_with_disallowed_header_is_rejected() {
+        let res = preflight(
+            &config(&["https://app.example.com"]),
+            "https://app.example.com",
+            "POST",
+            "x-forwarded-for",
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn credentials_are_allowed_when_enabled() {
+        let res = preflight(
+            &CorsConfig {
+                allow_credentials: true,
+                ..config(&["https://app.example.com"])
+            },
+            "https://app.example.com",
+            "POST",
+            "content-type",
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            header_value(&res, header::ACCESS_CONTROL_ALLOW_CREDENTIALS),
+            "true"
+        );
+    }
+
+    #[actix_web::test]
+    async fn wildcard_allows_any_origin() {
+        let res = preflight(
+            &config(&["*"]),
+            "https://anything.example.org",
+            "GET",
+            "content-type",
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            header_value(&res, header::ACCESS_CONTROL_ALLOW_ORIGIN),
+            "https://anything.example.org"
+        );
+    }
+}
@@ -0,0 +1,162 @@
+use calamine::{open_workbook_auto_from_rs, Data, Reader};
+use serde_json::{Map, Number, Value};
+use std::{error::Error, io::Cursor};
+
+use crate::utils::{
+    storage::Storage,
+    tenant::{tenant_key, Tenant},
+};
+
+// Dataset names end up in file paths and in `{{#dataset(name)}}` tags, so keep them simple
+pub fn is_valid_dataset_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Storage key of a parsed dataset, e.g. `datasets/acme/sales.json`.
+pub fn dataset_key(tenant: Option<&Tenant>, name: &str) -> String {
+    tenant_key(tenant, "datasets", &format!("{}.json", name))
+}
+
+// Turns a header such as "Unit Price" into a key usable as a placeholder, e.g. `{{Unit_Price}}`
+fn normalize_header(header: &str, index: usize) -> String {
+    let key: String = header
+        .trim()
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '_' })
+        .collect();
+    if key.is_empty() {
+        format!("column_{}", index + 1)
+    } else {
+        key
+    }
+}
+
+// Normalizes every header and rejects headers that end up as the same key, as the later
+// column would silently replace the earlier one in every row
+fn normalize_headers<'a>(
+    headers: impl Iterator<Item = &'a str>,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let headers: Vec<String> = headers
+        .enumerate()
+        .map(|(i, header)| normalize_header(header, i))
+        .collect();
+
+    let mut duplicates: Vec<&str> = Vec::new();
+    for (i, header) in headers.iter().enumerate() {
+        if headers[..i].contains(header) && !duplicates.contains(&header.as_str()) {
+            duplicates.push(header);
+        }
+    }
+    if !duplicates.is_empty() {
+        return Err(format!("Duplicate column names: {}", duplicates.join(", ")).into());
+    }
+    Ok(headers)
+}
+
+fn rows_to_json(headers: &[String], rows: Vec<Vec<Value>>) -> Vec<Value> {
+    rows.into_iter()
+        .map(|row| {
+            let mut object = Map::new();
+            for (i, header) in headers.iter().enumerate() {
+                let value = row.get(i).cloned().unwrap_or(Value::Null);
+                object.insert(header.clone(), value);
+            }
+            Value::Object(object)
+        })
+        .collect()
+}
+
+pub fn parse_csv(bytes: &[u8]) -> Result<(Vec<String>, Vec<Value>), Box<dyn Error>> {
+    let mut reader = csv::ReaderBuilder::new().flexible(true).from_reader(bytes);
+    let headers = normalize_headers(reader.headers()?.iter())?;
+
+    let mut rows = Vec::new();
+    for record in reader.records() {
+        let record = record?;
+        rows.push(record.iter().map(Value::from).collect());
+    }
+
+    let data = rows_to_json(&headers, rows);
+    Ok((headers, data))
+}
+
+fn cell_to_json(cell: &Data) -> Value {
+    match cell {
+        Data::Empty => Value::Null,
+        Data::Int(val) => Value::from(*val),
+        Data::Float(val) => Number::from_f64(*val)
+            .map(Value::Number)
+            .unwrap_or(Value::Null),
+        Data::Bool(val) => Value::from(*val),
+        Data::String(val) => Value::from(val.as_str()),
+        other => Value::from(other.to_string()),
+    }
+}
+
+// Reads the first worksheet of an xlsx/xls/ods workbook; the first row holds the headers
+pub fn parse_spreadsheet(bytes: Vec<u8>) -> Result<(Vec<String>, Vec<Value>), Box<dyn Error>> {
+    let mut workbook = open_workbook_auto_from_rs(Cursor::new(bytes))?;
+    let range = match workbook.worksheet_range_at(0) {
+        Some(range) => range?,
+        None => return Err("Workbook has no worksheets".into()),
+    };
+
+    let mut sheet_rows = range.rows();
+    let headers = match sheet_rows.next() {
+        Some(header_row) => {
+            let cells: Vec<String> = header_row.iter().map(|cell| cell.to_string()).collect();
+            normalize_headers(cells.iter().map(String::as_str))?
+        }
+        None => Vec::new(),
+    };
+    let rows = sheet_rows
+        .map(|row| row.iter().map(cell_to_json).collect())
+        .collect();
+
+    let data = rows_to_json(&headers, rows);
+    Ok((headers, data))
+}
+
+pub async fn load_dataset(
+    storage: &dyn Storage,
+    tenant: Option<&Tenant>,
+    name: &str,
+) -> Result<Vec<Value>, Box<dyn Error>> {
+    if !is_valid_dataset_name(name) {
+        return Err(format!("Invalid dataset name: {}", name).into());
+    }
+    let contents = match storage.read(&dataset_key(tenant, name)).await? {
+        Some(contents) => contents,
+        None => return Err(format!("Dataset not found: {}", name).into()),
+    };
+    Ok(serde_json::from_slice(&contents)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn normalizes_csv_headers() {
+        let (columns, rows) = parse_csv(b"Region,Unit Price,\nnorth,3,x\n").unwrap();
+        assert_eq!(columns, ["Region", "Unit_Price", "column_3"]);
+        assert_eq!(
+            rows,
+            [json!({ "Region": "north", "Unit_Price": "3", "column_3": "x" })]
+        );
+    }
+
+    #[test]
+    fn rejects_headers_that_normalize_to_the_same_key() {
+        let err = parse_csv(b"Unit Price,Unit-Price,Region,Region\n1,2,a,b\n").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Duplicate column names: Unit_Price, Region"
+        );
+    }
+}
@@ -0,0 +1,240 @@
+use std::fmt;
+
+use actix_web::{
+    http::{header, StatusCode},
+    HttpResponse, ResponseError,
+};
+use serde::Serialize;
+use serde_json::{json, Value};
+use tracing::{error, info};
+
+use crate::utils::request_id;
+
+/// Errors returned by the API. Each variant has a stable, machine readable `error` code
+/// that clients can match on; the message is meant for humans and may change.
+#[derive(Debug)]
+pub enum AppError {
+    InvalidRequest(String),
+    Unauthorized(String),
+    Forbidden(String),
+    QuotaExceeded(String),
+    InvalidSignature(String),
+    UrlNotAllowed(String),
+    // An upload larger than its limit, in bytes
+    PayloadTooLarge {
+        limit: u64,
+    },
+    NotFound(String),
+    RateLimited {
+        message: String,
+        retry_after: u64,
+    },
+    Template(String),
+    Dataset {
+        name: String,
+        message: String,
+    },
+    SqlBlock {
+        // 1 based position of the block in the template
+        block: usize,
+        sqlstate: Option<String>,
+        message: String,
+    },
+    DataSource(String),
+    Render {
+        message: String,
+        // Tail of the renderer's stderr, if it ran
+        stderr: Option<String>,
+    },
+    RenderTimeout {
+        timeout: u64,
+        stderr: Option<String>,
+    },
+    // The server is draining before it stops
+    ShuttingDown(String),
+    // Logged, but not shown to the client
+    Internal(String),
+}
+
+#[derive(Serialize)]
+pub struct ErrorResponse {
+    pub code: u16,
+    pub error: &'static str,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+impl AppError {
+    pub fn internal(err: impl fmt::Debug) -> Self {
+        AppError::Internal(format!("{:?}", err))
+    }
+
+    /// Describes a failed `{{#sql}}` block, keeping the Postgres SQLSTATE when there is one.
+    pub fn sql_block(index: usize, err: &tokio_postgres::Error) -> Self {
+        AppError::SqlBlock {
+            block: index + 1,
+            sqlstate: err.code().map(|code| code.code().to_string()),
+            message: match err.as_db_error() {
+                Some(db_error) => db_error.message().to_string(),
+                None => err.to_string(),
+            },
+        }
+    }
+
+    pub fn error_code(&self) -> &'static str {
+        match self {
+            AppError::InvalidRequest(_) => "invalid_request",
+            AppError::Unauthorized(_) => "unauthorized",
+            AppError::Forbidden(_) => "forbidden",
+            AppError::QuotaExceeded(_) => "quota_exceeded",
+            AppError::InvalidSignature(_) => "invalid_signature",
+            AppError::UrlNotAllowed(_) => "url_not_allowed",
+            AppError::PayloadTooLarge { .. } => "payload_too_large",
+            AppError::NotFound(_) => "not_found",
+            AppError::RateLimited { .. } => "rate_limited",
+            AppError::Template(_) => "template_error",
+            AppError::Dataset { .. } => "dataset_error",
+            AppError::SqlBlock { .. } => "sql_block_failed",
+            AppError::DataSource(_) => "data_source_unavailable",
+            AppError::Render { .. } => "render_failed",
+            AppError::RenderTimeout { .. } => "render_timeout",
+            AppError::ShuttingDown(_) => "shutting_down",
+            AppError::Internal(_) => "internal_error",
+        }
+    }
+
+    fn details(&self) -> Option<Value> {
+        match self {
+            AppError::RateLimited { retry_after, .. } => {
+                Some(json!({ "retry_after": retry_after }))
+            }
+            AppError::PayloadTooLarge { limit } => Some(json!({ "limit": limit })),
+            AppError::Dataset { name, .. } => Some(json!({ "dataset": name })),
+            AppError::SqlBlock {
+                block, sqlstate, ..
+            } => Some(json!({ "block": block, "sqlstate": sqlstate })),
+            AppError::Render {
+                stderr: Some(stderr),
+                ..
+            }
+            | AppError::RenderTimeout {
+                stderr: Some(stderr),
+                ..
+            } => Some(json!({ "stderr": stderr })),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::InvalidRequest(message)
+            | AppError::Unauthorized(message)
+            | AppError::Forbidden(message)
+            | AppError::QuotaExceeded(message)
+            | AppError::InvalidSignature(message)
+            | AppError::UrlNotAllowed(message)
+            | AppError::NotFound(message)
+            | AppError::Template(message)
+            | AppError::DataSource(message)
+            | AppError::ShuttingDown(message)
+            | AppError::RateLimited { message, .. }
+            | AppError::Render { message, .. } => write!(f, "{}", message),
+            AppError::PayloadTooLarge { limit } => {
+                write!(f, "Upload is larger than the limit of {} bytes", limit)
+            }
+            AppError::RenderTimeout { timeout, .. } => {
+                write!(f, "Render did not finish within {}s", timeout)
+            }
+            AppError::Dataset { name, message } => write!(f, "dataset {}: {}", name, message),
+            AppError::SqlBlock { block, message, .. } => {
+                write!(f, "sql block #{} failed: {}", block, message)
+            }
+            AppError::Internal(_) => write!(f, "Internal Server Error"),
+        }
+    }
+}
+
+impl std::error::Error for AppError {}
+
+impl From<std::io::Error> for AppError {
+    fn from(err: std::io::Error) -> Self {
+        AppError::internal(err)
+    }
+}
+
+impl From<actix_web::error::BlockingError> for AppError {
+    fn from(err: actix_web::error::BlockingError) -> Self {
+        AppError::internal(err)
+    }
+}
+
+// A malformed upload is the client's fault
+impl From<actix_multipart::MultipartError> for AppError {
+    fn from(err: actix_multipart::MultipartError) -> Self {
+        AppError::InvalidRequest(err.to_string())
+    }
+}
+
+impl From<actix_web::Error> for AppError {
+    fn from(err: actix_web::Error) -> Self {
+        AppError::internal(err)
+    }
+}
+
+impl ResponseError for AppError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AppError::InvalidRequest(_) | AppError::UrlNotAllowed(_) => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) | AppError::QuotaExceeded(_) | AppError::InvalidSignature(_) => {
+                StatusCode::FORBIDDEN
+            }
+            AppError::PayloadTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            AppError::Template(_) | AppError::Dataset { .. } | AppError::SqlBlock { .. } => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            AppError::DataSource(_) | AppError::ShuttingDown(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::Render { .. } | AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::RenderTimeout { .. } => StatusCode::GATEWAY_TIMEOUT,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let status = self.status_code();
+        let request_id = request_id::current();
+        if status.is_server_error() {
+            let detail = match self {
+                AppError::Internal(detail) => detail.clone(),
+                _ => self.to_string(),
+            };
+            error!(error = self.error_code(), "{}", detail);
+        } else {
+            // Messages of template, dataset and sql errors can quote row data, so only the
+            // code is logged; the client still gets the full message
+            info!(
+                error = self.error_code(),
+                status = status.as_u16(),
+                "Request rejected"
+            );
+        }
+
+        let mut res = HttpResponse::build(status);
+        if let AppError::RateLimited { retry_after, .. } = self {
+            res.insert_header((header::RETRY_AFTER, retry_after.to_string()));
+        }
+        res.json(ErrorResponse {
+            code: status.as_u16(),
+            error: self.error_code(),
+            message: self.to_string(),
+            details: self.details(),
+            request_id,
+        })
+    }
+}
@@ -0,0 +1,194 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    future::Future,
+    process::Command,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use deadpool_postgres::Pool;
+use serde::Serialize;
+use tokio::{sync::Mutex, time::timeout};
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::utils::{
+    render_proxy::{RenderPage, RenderProxy},
+    render_supervisor::RenderSupervisor,
+    setting::config,
+    staged_files::StagedFiles,
+    storage::Storage,
+    url_policy::UrlPolicy,
+};
+
+// The database and storage checks have to answer within this time, the test render has
+// `renderer.check_timeout`
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+// A test render starts a browser, so its result is reused for a while
+const RENDERER_CHECK_TTL: Duration = Duration::from_secs(60);
+
+#[derive(Clone)]
+pub struct CheckResult {
+    pub status: &'static str,
+    pub message: Option<String>,
+    pub elapsed_ms: u64,
+}
+
+/// The outcome of the readiness checks. `/readyz` doesn't need a key, so only whether each
+/// check passed is returned; why one failed is logged.
+#[derive(Serialize)]
+pub struct Readiness {
+    pub status: &'static str,
+    pub checks: BTreeMap<&'static str, &'static str>,
+}
+
+async fn run_check<F>(limit: Duration, check: F) -> CheckResult
+where
+    F: Future<Output = Result<(), String>>,
+{
+    let started = Instant::now();
+    let result = match timeout(limit, check).await {
+        Ok(result) => result,
+        Err(_) => Err(format!("timed out after {}s", limit.as_secs())),
+    };
+    CheckResult {
+        status: if result.is_ok() { "ok" } else { "error" },
+        message: result.err(),
+        elapsed_ms: started.elapsed().as_millis() as u64,
+    }
+}
+
+async fn check_database(pool: &Pool) -> Result<(), String> {
+    let client = pool.get().await.map_err(|err| err.to_string())?;
+    client
+        .simple_query("SELECT 1")
+        .await
+        .map_err(|err| err.to_string())?;
+    Ok(())
+}
+
+// Renders a tiny page the way reports are rendered: supervised in its own process group and
+// loaded through the render proxy, which is the only place the page is served from. A
+// renderer that ignores the proxy fails the check.
+async fn check_renderer(
+    supervisor: &RenderSupervisor,
+    url_policy: Arc<UrlPolicy>,
+) -> Result<(), String> {
+    let config = config();
+    let unique_id = Uuid::new_v4();
+    let mut staged = StagedFiles::default();
+    let page_path = staged.add(format!("{}/readyz-{unique_id}.html", config.paths.temp));
+    let output_path = staged.add(format!("{}/readyz-{unique_id}.pdf", config.paths.temp));
+    fs::write(&page_path, "<p>ok</p>").map_err(|err| err.to_string())?;
+
+    let url = format!(
+        "{}/render/readyz-{unique_id}.html",
+        config.internal_base_url()
+    );
+    let mut sitetopdf = Command::new(&config.renderer.command);
+    sitetopdf
+        .arg("--url")
+        .arg(&url)
+        .arg("--output")
+        .arg(&output_path);
+    let page = RenderPage {
+        url,
+        path: page_path,
+    };
+    let proxy = RenderProxy::start(url_policy, Some(page))
+        .await
+        .map_err(|err| format!("render proxy could not be started: {}", err))?;
+    proxy.apply(&mut sitetopdf);
+
+    supervisor
+        .run(sitetopdf, &output_path)
+        .await
+        .map_err(|err| err.to_string())?;
+    if !fs::metadata(&output_path).is_ok_and(|metadata| metadata.len() > 0) {
+        return Err(String::from("sitetopdf did not write any output"));
+    }
+    Ok(())
+}
+
+// The temp directory is always local, the stored objects are checked by the backend
+async fn check_storage(storage: &dyn Storage) -> Result<(), String> {
+    let mut failed = Vec::new();
+    let temp = &config().paths.temp;
+    let probe = format!("{}/.readyz-{}", temp, Uuid::new_v4());
+    match fs::write(&probe, b"ok") {
+        Ok(()) => {
+            let _ = fs::remove_file(&probe);
+        }
+        Err(err) => failed.push(format!("{} is not writable: {}", temp, err)),
+    }
+    if let Err(err) = storage.check().await {
+        failed.push(err);
+    }
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(failed.join("; "))
+    }
+}
+
+/// Runs the readiness checks. The renderer check is cached for [`RENDERER_CHECK_TTL`], and
+/// only one runs at a time: concurrent probes wait for it and share its result.
+#[derive(Default)]
+pub struct HealthChecker {
+    renderer: Mutex<Option<(Instant, CheckResult)>>,
+}
+
+impl HealthChecker {
+    async fn renderer(
+        &self,
+        supervisor: &RenderSupervisor,
+        url_policy: Arc<UrlPolicy>,
+    ) -> CheckResult {
+        let mut cached = self.renderer.lock().await;
+        if let Some((checked_at, result)) = cached.as_ref() {
+            if checked_at.elapsed() < RENDERER_CHECK_TTL {
+                return result.clone();
+            }
+        }
+        let limit = Duration::from_secs(config().renderer.check_timeout);
+        let result = run_check(limit, check_renderer(supervisor, url_policy)).await;
+        *cached = Some((Instant::now(), result.clone()));
+        result
+    }
+
+    pub async fn readiness(
+        &self,
+        pool: &Pool,
+        storage: &dyn Storage,
+        supervisor: &RenderSupervisor,
+        url_policy: Arc<UrlPolicy>,
+    ) -> Readiness {
+        let (database, renderer, storage) = tokio::join!(
+            run_check(CHECK_TIMEOUT, check_database(pool)),
+            self.renderer(supervisor, url_policy),
+            run_check(CHECK_TIMEOUT, check_storage(storage)),
+        );
+        let mut checks = BTreeMap::new();
+        for (name, result) in [
+            ("database", database),
+            ("renderer", renderer),
+            ("storage", storage),
+        ] {
+            if let Some(message) = &result.message {
+                warn!(
+                    check = name,
+                    elapsed_ms = result.elapsed_ms,
+                    "Readiness check failed: {}",
+                    message
+                );
+            }
+            checks.insert(name, result.status);
+        }
+        let ready = checks.values().all(|status| *status == "ok");
+        Readiness {
+            status: if ready { "ok" } else { "unavailable" },
+            checks,
+        }
+    }
+}
@@ -1,69 +1,1130 @@
+use actix_web::rt::{self, task::JoinHandle};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use deadpool_postgres::{Object, Pool};
+use futures::{pin_mut, TryStreamExt};
 use regex::Regex;
-use std::error::Error;
-use tokio_postgres::Client;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::{collections::HashMap, str::FromStr, sync::Arc, time::Instant};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::{mpsc, watch},
+};
+use tokio_postgres::{NoTls, Row, RowStream};
+use tracing::{debug, warn};
+use uuid::Uuid;
 
-pub async fn process_template(
-    html_template: &str,
-    db_pool: &Client,
-) -> Result<String, Box<dyn Error>> {
+use crate::utils::{
+    dataset::load_dataset,
+    error::AppError,
+    metrics::Metrics,
+    query_cache::{CacheKey, QueryCache},
+    storage::Storage,
+    tenant::Tenant,
+};
+
+// Rendered HTML is handed to the writer in chunks of roughly this size
+const FLUSH_THRESHOLD: usize = 64 * 1024;
+
+/// How rendering problems are handled. `Strict` aborts the report with the error, `Lenient`
+/// writes an inline error marker into the HTML and keeps going, which helps when debugging
+/// a template. Syntax errors in the template abort in both modes.
+#[derive(Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TemplateMode {
+    #[default]
+    Strict,
+    Lenient,
+}
+
+impl FromStr for TemplateMode {
+    type Err = &'static str;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "strict" => Ok(TemplateMode::Strict),
+            "lenient" => Ok(TemplateMode::Lenient),
+            _ => Err("must be strict or lenient"),
+        }
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn error_marker(err: &AppError) -> String {
+    format!(
+        "<span class=\"report-forge-error\" style=\"color:#c00;font-weight:bold\">[{}: {}]</span>",
+        err.error_code(),
+        escape_html(&err.to_string())
+    )
+}
+
+// A parsed template. Blocks keep their body as a node list so that `{{#sql}}` rows,
+// `{{#dataset}}` rows and `{{#each}}` items are rendered by the same code path.
+enum Node {
+    Text(String),
+    // `{{path}}` is HTML-escaped, `{{{path}}}` inserts the value as it is
+    Placeholder {
+        raw: String,
+        path: String,
+        escape: bool,
+    },
+    Each {
+        path: String,
+        body: Vec<Node>,
+    },
+    Sql {
+        index: usize,
+        query: String,
+        cache_ttl: Option<u64>,
+        body: Vec<Node>,
+    },
+    Dataset {
+        name: String,
+        body: Vec<Node>,
+    },
+}
+
+fn parse_template(html_template: &str) -> Result<Vec<Node>, AppError> {
     // Adjusted regex pattern to handle new lines and any spaces within the SQL tag
-    let re = Regex::new(r"\{\{#sql\(([\s\S]*?)\)\}\}([\s\S]*?)\{\{/sql\}}")?;
-    let placeholder_re = Regex::new(r"\{\{(\w+)\}\}")?;
-
-    let mut processed_html = String::from(html_template);
-
-    for cap in re.captures_iter(html_template) {
-        let query = cap[1].trim().replace("\n", " "); // Trim whitespace and replace newlines
-        let template = &cap[2];
-
-        // Execute the SQL query, handle errors properly
-        let rows = db_pool.query(&query, &[]).await?;
-
-        let mut generated_html = String::new();
-        for row in rows {
-            println!("{:?}", row);
-            let mut row_html = String::from(template);
-
-            // Iterate through all placeholders in the template
-            // for placeholder_cap in placeholder_re.captures_iter(template) {
-            //     let placeholder = &placeholder_cap[0]; // e.g., "{{name}}"
-            //     let column_name = &placeholder_cap[1]; // e.g., "name"
-
-            //     if let Ok(value) = row.try_get::<&str, &str>(column_name) {
-            //         row_html = row_html.replace(placeholder, value);
-            //     } else {
-            //         // Insert a default value or handle the error appropriately
-            //         row_html = row_html.replace(placeholder, "");
-            //     }
-            // }
-
-            for placeholder_cap in placeholder_re.captures_iter(template) {
-                let placeholder = &placeholder_cap[0]; // e.g., "{{name}}"
-                let column_name = &placeholder_cap[1]; // e.g., "name"
-
-                // Attempt to get the value from the row.
-                // Try as string, then as i32, then as f64, etc.
-                let value: String = if let Ok(val) = row.try_get::<&str, String>(column_name) {
-                    val
-                } else if let Ok(val) = row.try_get::<&str, i32>(column_name) {
-                    val.to_string()
-                } else if let Ok(val) = row.try_get::<&str, f64>(column_name) {
-                    val.to_string()
-                } else {
-                    // If none of the above types match, you might want to log this case or handle it specifically.
-                    // For now, we'll just return an empty string.
-                    "".to_owned()
-                };
+    let tag_re = Regex::new(
+        r"\{\{(?:#sql\(([\s\S]*?)\)|#each\s+([\w.]+)|#dataset\(\s*([\w-]+)\s*\)|/(sql|each|dataset)|([\w.]+)|\{([\w.]+)\})\}\}",
+    )
+    .map_err(AppError::internal)?;
+
+    // `{{#sql(select ..., cache=300)}}` caches the result for the given number of seconds
+    let cache_re = Regex::new(r"^([\s\S]*?),\s*cache\s*=\s*(\d+)$").map_err(AppError::internal)?;
+
+    // Each open block is kept on the stack together with the nodes collected so far
+    let mut stack: Vec<(Node, Vec<Node>)> = Vec::new();
+    let mut nodes: Vec<Node> = Vec::new();
+    let mut sql_count = 0;
+    let mut last = 0;
+
+    for cap in tag_re.captures_iter(html_template) {
+        let tag = cap.get(0).unwrap();
+        if tag.start() > last {
+            nodes.push(Node::Text(html_template[last..tag.start()].to_string()));
+        }
+        last = tag.end();
 
-                // Replace the placeholder with the actual data or an empty string if the value could not be retrieved.
-                row_html = row_html.replace(placeholder, &value);
+        if let Some(query) = cap.get(1) {
+            // Trim whitespace and replace newlines
+            let query = query.as_str().trim().replace('\n', " ");
+            let (query, cache_ttl) = match cache_re.captures(&query) {
+                Some(options) => (options[1].trim().to_string(), options[2].parse().ok()),
+                None => (query, None),
+            };
+            let block = Node::Sql {
+                index: sql_count,
+                query,
+                cache_ttl,
+                body: Vec::new(),
+            };
+            sql_count += 1;
+            stack.push((block, std::mem::take(&mut nodes)));
+        } else if let Some(path) = cap.get(2) {
+            let block = Node::Each {
+                path: path.as_str().to_string(),
+                body: Vec::new(),
+            };
+            stack.push((block, std::mem::take(&mut nodes)));
+        } else if let Some(name) = cap.get(3) {
+            let block = Node::Dataset {
+                name: name.as_str().to_string(),
+                body: Vec::new(),
+            };
+            stack.push((block, std::mem::take(&mut nodes)));
+        } else if let Some(closing) = cap.get(4) {
+            let (mut block, parent) = match stack.pop() {
+                Some(open) => open,
+                None => {
+                    return Err(AppError::Template(format!(
+                        "Unexpected {{{{/{}}}}}",
+                        closing.as_str()
+                    )))
+                }
+            };
+            match (&mut block, closing.as_str()) {
+                (Node::Sql { body, .. }, "sql")
+                | (Node::Each { body, .. }, "each")
+                | (Node::Dataset { body, .. }, "dataset") => {
+                    *body = std::mem::replace(&mut nodes, parent);
+                }
+                _ => {
+                    return Err(AppError::Template(format!(
+                        "Mismatched {{{{/{}}}}}",
+                        closing.as_str()
+                    )))
+                }
             }
+            nodes.push(block);
+        } else if let Some(path) = cap.get(5) {
+            nodes.push(Node::Placeholder {
+                raw: tag.as_str().to_string(),
+                path: path.as_str().to_string(),
+                escape: true,
+            });
+        } else if let Some(path) = cap.get(6) {
+            nodes.push(Node::Placeholder {
+                raw: tag.as_str().to_string(),
+                path: path.as_str().to_string(),
+                escape: false,
+            });
+        }
+    }
+
+    if let Some((block, _)) = stack.last() {
+        let name = match block {
+            Node::Sql { .. } => "sql",
+            Node::Dataset { .. } => "dataset",
+            _ => "each",
+        };
+        return Err(AppError::Template(format!(
+            "Unclosed {{{{#{}}}}} block",
+            name
+        )));
+    }
+    if last < html_template.len() {
+        nodes.push(Node::Text(html_template[last..].to_string()));
+    }
+
+    Ok(nodes)
+}
+
+// Gathers the names of the datasets a template depends on, in document order
+fn collect_datasets<'a>(nodes: &'a [Node], datasets: &mut Vec<&'a str>) {
+    for node in nodes {
+        match node {
+            Node::Dataset { name, body } => {
+                if !datasets.contains(&name.as_str()) {
+                    datasets.push(name);
+                }
+                collect_datasets(body, datasets);
+            }
+            Node::Sql { body, .. } | Node::Each { body, .. } => collect_datasets(body, datasets),
+            _ => {}
+        }
+    }
+}
 
-            generated_html.push_str(&row_html);
+// Gathers the sql blocks nested inside other blocks. Top level blocks are rendered exactly
+// once, so their rows are streamed; nested ones can be rendered any number of times.
+fn collect_nested_sql<'a>(nodes: &'a [Node], nested: bool, blocks: &mut Vec<&'a Node>) {
+    for node in nodes {
+        match node {
+            Node::Sql { body, .. } => {
+                if nested {
+                    blocks.push(node);
+                }
+                collect_nested_sql(body, true, blocks);
+            }
+            Node::Each { body, .. } | Node::Dataset { body, .. } => {
+                collect_nested_sql(body, true, blocks)
+            }
+            _ => {}
         }
+    }
+}
+
+// Converts a row into a JSON object. Columns of a type that can't be converted are reported
+// as an error of the given sql block; in lenient mode they are null instead and the error is
+// returned with the row, so the caller can mark it.
+fn row_to_json(
+    row: &Row,
+    index: usize,
+    mode: TemplateMode,
+) -> Result<(Value, Option<AppError>), AppError> {
+    let mut object = Map::new();
+    let mut conversion_error = None;
+    for (i, column) in row.columns().iter().enumerate() {
+        // Try as string, then as the integer and float types, bool, dates and uuids
+        let value = if let Ok(val) = row.try_get::<_, Option<String>>(i) {
+            val.map(Value::from)
+        } else if let Ok(val) = row.try_get::<_, Option<i32>>(i) {
+            val.map(Value::from)
+        } else if let Ok(val) = row.try_get::<_, Option<i64>>(i) {
+            val.map(Value::from)
+        } else if let Ok(val) = row.try_get::<_, Option<i16>>(i) {
+            val.map(Value::from)
+        } else if let Ok(val) = row.try_get::<_, Option<f64>>(i) {
+            val.map(Value::from)
+        } else if let Ok(val) = row.try_get::<_, Option<f32>>(i) {
+            val.map(Value::from)
+        } else if let Ok(val) = row.try_get::<_, Option<bool>>(i) {
+            val.map(Value::from)
+        } else if let Ok(val) = row.try_get::<_, Option<DateTime<Utc>>>(i) {
+            val.map(|val| Value::from(val.to_rfc3339()))
+        } else if let Ok(val) = row.try_get::<_, Option<NaiveDateTime>>(i) {
+            val.map(|val| Value::from(val.to_string()))
+        } else if let Ok(val) = row.try_get::<_, Option<NaiveDate>>(i) {
+            val.map(|val| Value::from(val.to_string()))
+        } else if let Ok(val) = row.try_get::<_, Option<Uuid>>(i) {
+            val.map(|val| Value::from(val.to_string()))
+        } else {
+            let err = AppError::SqlBlock {
+                block: index + 1,
+                sqlstate: None,
+                message: format!(
+                    "column \"{}\" has unsupported type {}, cast it to text in the query",
+                    column.name(),
+                    column.type_()
+                ),
+            };
+            if mode == TemplateMode::Strict {
+                return Err(err);
+            }
+            conversion_error.get_or_insert(err);
+            None
+        };
+        object.insert(column.name().to_string(), value.unwrap_or(Value::Null));
+    }
+    Ok((Value::Object(object), conversion_error))
+}
 
-        processed_html = processed_html.replacen(&cap[0], &generated_html, 1);
+// Looks a dotted path up in the innermost scope first, falling back to the outer ones
+fn resolve<'a>(path: &str, scopes: &[&'a Value]) -> Option<&'a Value> {
+    if path == "this" {
+        return scopes.last().copied();
     }
+    let (path, scopes) = match path.strip_prefix("this.") {
+        Some(rest) => (rest, &scopes[scopes.len().saturating_sub(1)..]),
+        None => (path, scopes),
+    };
+    scopes.iter().rev().find_map(|scope| {
+        path.split('.').try_fold(*scope, |value, key| match value {
+            Value::Object(map) => map.get(key),
+            Value::Array(items) => key.parse::<usize>().ok().and_then(|i| items.get(i)),
+            _ => None,
+        })
+    })
+}
+
+fn value_to_string(value: &Value) -> String {
+    match value {
+        Value::Null => "".to_owned(),
+        Value::String(val) => val.clone(),
+        other => other.to_string(),
+    }
+}
+
+// Rows of a nested sql block, or why they couldn't be fetched
+type SqlRows = Result<Arc<Vec<Value>>, AppError>;
+
+struct Renderer<'a> {
+    // Datasets that failed to load only end up here in lenient mode
+    datasets: &'a HashMap<String, Result<Vec<Value>, String>>,
+    // Rows of the nested sql blocks by block index; failures only end up here in lenient
+    // mode
+    sql_rows: &'a HashMap<usize, SqlRows>,
+    mode: TemplateMode,
+}
+
+impl<'a> Renderer<'a> {
+    // Aborts in strict mode, otherwise marks the problem in the output
+    fn fail(&self, err: AppError, out: &mut String) -> Result<(), AppError> {
+        match self.mode {
+            TemplateMode::Strict => Err(err),
+            TemplateMode::Lenient => {
+                out.push_str(&error_marker(&err));
+                Ok(())
+            }
+        }
+    }
+
+    fn render<'v>(
+        &self,
+        nodes: &'v [Node],
+        scopes: &mut Vec<&'v Value>,
+        out: &mut String,
+    ) -> Result<(), AppError>
+    where
+        'a: 'v,
+    {
+        for node in nodes {
+            match node {
+                Node::Text(text) => out.push_str(text),
+                Node::Placeholder { raw, path, escape } => {
+                    if let Some(value) = resolve(path, scopes) {
+                        let text = value_to_string(value);
+                        if *escape {
+                            out.push_str(&escape_html(&text));
+                        } else {
+                            out.push_str(&text);
+                        }
+                    } else {
+                        self.fail(
+                            AppError::Template(format!("Unknown placeholder {}", raw)),
+                            out,
+                        )?;
+                    }
+                }
+                Node::Each { path, body } => match resolve(path, scopes) {
+                    Some(Value::Array(items)) => {
+                        for item in items {
+                            scopes.push(item);
+                            self.render(body, scopes, out)?;
+                            scopes.pop();
+                        }
+                    }
+                    Some(Value::Null) => {}
+                    Some(_) => self.fail(
+                        AppError::Template(format!("{{{{#each {}}}}} expects a list", path)),
+                        out,
+                    )?,
+                    None => self.fail(
+                        AppError::Template(format!("Unknown list {{{{#each {}}}}}", path)),
+                        out,
+                    )?,
+                },
+                Node::Dataset { name, body } => match &self.datasets[name] {
+                    Ok(rows) => {
+                        for row in rows {
+                            scopes.push(row);
+                            self.render(body, scopes, out)?;
+                            scopes.pop();
+                        }
+                    }
+                    Err(message) => self.fail(
+                        AppError::Dataset {
+                            name: name.clone(),
+                            message: message.clone(),
+                        },
+                        out,
+                    )?,
+                },
+                // Top level sql blocks aren't in `sql_rows`, `process_template` streams them
+                Node::Sql { index, body, .. } => match self.sql_rows.get(index) {
+                    Some(Ok(rows)) => {
+                        for row in rows.iter() {
+                            scopes.push(row);
+                            self.render(body, scopes, out)?;
+                            scopes.pop();
+                        }
+                    }
+                    Some(Err(err)) => out.push_str(&error_marker(err)),
+                    None => {}
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+type QueryHandle = JoinHandle<Result<(Object, RowStream), AppError>>;
+
+// The top level queries of a render, in document order. Dropping it, e.g. because a block
+// failed in strict mode, stops starting queries and cancels the ones not consumed yet.
+struct StartedQueries {
+    receiver: mpsc::UnboundedReceiver<QueryHandle>,
+    starter: JoinHandle<()>,
+    // Queries wait on this until they return rows; dropping it stops them
+    _running: watch::Sender<()>,
+}
+
+impl StartedQueries {
+    async fn next(&mut self) -> Option<QueryHandle> {
+        self.receiver.recv().await
+    }
+}
+
+impl Drop for StartedQueries {
+    fn drop(&mut self) {
+        self.starter.abort();
+    }
+}
+
+// Runs a top level query until it returns rows, or cancels it on the server once the render
+// is given up.
+async fn run_query(
+    client: Object,
+    index: usize,
+    query: String,
+    mut running: watch::Receiver<()>,
+) -> Result<(Object, RowStream), AppError> {
+    let rows = tokio::select! {
+        rows = client.query_raw(query.as_str(), Vec::<String>::new()) => rows,
+        _ = running.changed() => {
+            if let Err(err) = client.cancel_token().cancel_query(NoTls).await {
+                warn!(block = index + 1, "Failed to cancel sql block: {}", err);
+            }
+            // The connection may still be busy with the query, so it isn't reused
+            drop(Object::take(client));
+            return Err(AppError::Internal(format!("sql block #{} was cancelled", index + 1)));
+        }
+    };
+    Ok((
+        client,
+        rows.map_err(|err| AppError::sql_block(index, &err))?,
+    ))
+}
+
+// Starts the given queries in document order, each on its own pooled connection. Connections
+// are taken in the same order the rows are consumed, so a report only ever waits on the block
+// it is currently rendering and concurrent reports can't deadlock each other on the pool.
+fn start_queries(pool: Option<&Pool>, queries: Vec<(usize, String)>) -> StartedQueries {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let (running, stopped) = watch::channel(());
+    let pool = pool.cloned();
+    let starter = rt::spawn(async move {
+        for (index, query) in queries {
+            let client = match &pool {
+                Some(pool) => pool.get().await.map_err(|err| {
+                    AppError::DataSource(format!("Database is unavailable: {}", err))
+                }),
+                None => Err(AppError::DataSource(String::from(
+                    "No data source is configured for sql blocks",
+                ))),
+            };
+            let stopped = stopped.clone();
+            let handle: QueryHandle =
+                rt::spawn(async move { run_query(client?, index, query, stopped).await });
+            if sender.send(handle).is_err() {
+                break;
+            }
+        }
+    });
+    StartedQueries {
+        receiver,
+        starter,
+        _running: running,
+    }
+}
+
+// Runs the query of a nested sql block and collects its rows. A column that can't be
+// converted fails the whole block, in lenient mode too.
+async fn fetch_rows(
+    pool: Option<&Pool>,
+    index: usize,
+    query: &str,
+    mode: TemplateMode,
+) -> Result<Vec<Value>, AppError> {
+    let Some(pool) = pool else {
+        return Err(AppError::DataSource(String::from(
+            "No data source is configured for sql blocks",
+        )));
+    };
+    let client = pool
+        .get()
+        .await
+        .map_err(|err| AppError::DataSource(format!("Database is unavailable: {}", err)))?;
+    let rows = client
+        .query_raw(query, Vec::<String>::new())
+        .await
+        .map_err(|err| AppError::sql_block(index, &err))?;
+    pin_mut!(rows);
+    let mut values = Vec::new();
+    while let Some(row) = rows
+        .try_next()
+        .await
+        .map_err(|err| AppError::sql_block(index, &err))?
+    {
+        match row_to_json(&row, index, mode)? {
+            (row, None) => values.push(row),
+            (_, Some(err)) => return Err(err),
+        }
+    }
+    Ok(values)
+}
+
+async fn flush<W: AsyncWrite + Unpin>(out: &mut String, writer: &mut W) -> std::io::Result<()> {
+    writer.write_all(out.as_bytes()).await?;
+    out.clear();
+    Ok(())
+}
 
-    Ok(processed_html)
+pub struct TemplateContext<'a> {
+    // `None` if the caller has no data source, sql blocks then fail
+    pub pool: Option<&'a Pool>,
+    // Name of the data source `pool` belongs to, part of the query cache key
+    pub source: &'a str,
+    pub query_cache: &'a QueryCache,
+    // Cached results are tracked per template so they can be invalidated together
+    pub template_name: &'a str,
+    // Datasets are looked up in the tenant's namespace
+    pub storage: &'a dyn Storage,
+    pub tenant: Option<&'a Tenant>,
+    pub data: Option<&'a Value>,
+    pub mode: TemplateMode,
+    pub metrics: &'a Metrics,
+}
+
+// Renders the template into `writer`. Rows of `{{#sql}}` blocks are streamed from Postgres and
+// written as they arrive, so memory stays bounded no matter how many rows a query returns.
+pub async fn process_template<W: AsyncWrite + Unpin>(
+    html_template: &str,
+    ctx: &TemplateContext<'_>,
+    writer: &mut W,
+) -> Result<(), AppError> {
+    let nodes = parse_template(html_template)?;
+
+    let mut dataset_names = Vec::new();
+    collect_datasets(&nodes, &mut dataset_names);
+    let mut datasets = HashMap::new();
+    for name in dataset_names {
+        let rows = load_dataset(ctx.storage, ctx.tenant, name)
+            .await
+            .map_err(|err| err.to_string());
+        if let (TemplateMode::Strict, Err(message)) = (ctx.mode, &rows) {
+            return Err(AppError::Dataset {
+                name: name.to_string(),
+                message: message.clone(),
+            });
+        }
+        datasets.insert(name.to_string(), rows);
+    }
+
+    // Nested sql blocks may be rendered many times, e.g. once per `{{#each}}` item, so their
+    // rows are fetched up front and kept in memory. Their connections are returned before
+    // the top level blocks take theirs.
+    let mut nested = Vec::new();
+    collect_nested_sql(&nodes, false, &mut nested);
+    let mut sql_rows = HashMap::new();
+    for node in nested {
+        let Node::Sql {
+            index,
+            query,
+            cache_ttl,
+            ..
+        } = node
+        else {
+            continue;
+        };
+        let key = CacheKey {
+            template: ctx.template_name.to_string(),
+            source: ctx.source.to_string(),
+            query: query.to_string(),
+        };
+        if let Some(rows) = cache_ttl.and_then(|_| ctx.query_cache.get(&key)) {
+            sql_rows.insert(*index, Ok(rows));
+            continue;
+        }
+        let block_started = Instant::now();
+        let rows = match fetch_rows(ctx.pool, *index, query, ctx.mode).await {
+            Ok(rows) => {
+                ctx.metrics
+                    .observe_sql_block(block_started.elapsed(), rows.len() as u64);
+                let rows = Arc::new(rows);
+                if let Some(ttl) = cache_ttl {
+                    ctx.query_cache.insert(key, rows.clone(), *ttl);
+                }
+                Ok(rows)
+            }
+            Err(err) if ctx.mode == TemplateMode::Strict => return Err(err),
+            Err(err) => Err(err),
+        };
+        sql_rows.insert(*index, rows);
+    }
+
+    // Cached results are looked up front; every other sql block is started right away so
+    // independent queries run concurrently, bounded by the pool size.
+    let mut cached = HashMap::new();
+    let mut queries = Vec::new();
+    for node in &nodes {
+        if let Node::Sql {
+            index,
+            query,
+            cache_ttl,
+            ..
+        } = node
+        {
+            let key = CacheKey {
+                template: ctx.template_name.to_string(),
+                source: ctx.source.to_string(),
+                query: query.to_string(),
+            };
+            match cache_ttl.and_then(|_| ctx.query_cache.get(&key)) {
+                Some(rows) => {
+                    cached.insert(*index, rows);
+                }
+                None => queries.push((*index, query.clone())),
+            }
+        }
+    }
+    let mut started = start_queries(ctx.pool, queries);
+
+    let root = ctx.data.cloned().unwrap_or(Value::Null);
+    let renderer = Renderer {
+        datasets: &datasets,
+        sql_rows: &sql_rows,
+        mode: ctx.mode,
+    };
+    let mut out = String::with_capacity(FLUSH_THRESHOLD * 2);
+
+    for node in &nodes {
+        let (index, query, cache_ttl, body) = match node {
+            Node::Sql {
+                index,
+                query,
+                cache_ttl,
+                body,
+            } => (*index, query, *cache_ttl, body),
+            other => {
+                renderer.render(std::slice::from_ref(other), &mut vec![&root], &mut out)?;
+                continue;
+            }
+        };
+
+        if let Some(rows) = cached.get(&index) {
+            debug!(
+                block = index + 1,
+                rows = rows.len(),
+                "sql block served from cache"
+            );
+            for row in rows.iter() {
+                renderer.render(body, &mut vec![&root, row], &mut out)?;
+                if out.len() >= FLUSH_THRESHOLD {
+                    flush(&mut out, writer).await?;
+                }
+            }
+            continue;
+        }
+
+        let block_started = Instant::now();
+        let mut row_count = 0;
+        let handle = match started.next().await {
+            Some(handle) => handle,
+            None => {
+                return Err(AppError::Internal(format!(
+                    "sql block #{} was not started",
+                    index + 1
+                )))
+            }
+        };
+        let (_client, rows) = match handle.await.map_err(AppError::internal)? {
+            Ok(started) => started,
+            Err(err) => {
+                renderer.fail(err, &mut out)?;
+                continue;
+            }
+        };
+        pin_mut!(rows);
+
+        // Rows are kept for the cache only while they fit into it
+        let mut to_cache = cache_ttl.map(|_| (Vec::new(), 0));
+        loop {
+            let row = match rows.try_next().await {
+                Ok(Some(row)) => row,
+                Ok(None) => break,
+                Err(err) => {
+                    // Rows rendered so far stay in place; the marker goes after them
+                    renderer.fail(AppError::sql_block(index, &err), &mut out)?;
+                    to_cache = None;
+                    break;
+                }
+            };
+            row_count += 1;
+            let (row, conversion_error) = row_to_json(&row, index, ctx.mode)?;
+            // Rows with a column that couldn't be converted must not end up in the cache
+            if let Some(err) = conversion_error {
+                out.push_str(&error_marker(&err));
+                to_cache = None;
+            }
+            renderer.render(body, &mut vec![&root, &row], &mut out)?;
+            if out.len() >= FLUSH_THRESHOLD {
+                flush(&mut out, writer).await?;
+            }
+
+            if let Some((cache_rows, size)) = &mut to_cache {
+                *size += row.to_string().len();
+                if *size > ctx.query_cache.max_bytes() {
+                    to_cache = None;
+                } else {
+                    cache_rows.push(row);
+                }
+            }
+        }
+
+        let elapsed = block_started.elapsed();
+        ctx.metrics.observe_sql_block(elapsed, row_count);
+        debug!(
+            block = index + 1,
+            rows = row_count,
+            elapsed_ms = elapsed.as_millis() as u64,
+            "sql block finished"
+        );
+
+        if let (Some(ttl), Some((cache_rows, _))) = (cache_ttl, to_cache) {
+            let key = CacheKey {
+                template: ctx.template_name.to_string(),
+                source: ctx.source.to_string(),
+                query: query.to_string(),
+            };
+            ctx.query_cache.insert(key, Arc::new(cache_rows), ttl);
+        }
+    }
+
+    flush(&mut out, writer).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::utils::{
+        dataset::{dataset_key, parse_csv},
+        setting::{testing, PathsConfig},
+        storage::LocalStorage,
+    };
+
+    fn nested_indexes(template: &str) -> Vec<usize> {
+        let nodes = parse_template(template).unwrap();
+        let mut nested = Vec::new();
+        collect_nested_sql(&nodes, false, &mut nested);
+        nested
+            .into_iter()
+            .filter_map(|node| match node {
+                Node::Sql { index, .. } => Some(*index),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn render_in(mode: TemplateMode, template: &str, data: Value) -> Result<String, AppError> {
+        let nodes = parse_template(template)?;
+        let renderer = Renderer {
+            datasets: &HashMap::new(),
+            sql_rows: &HashMap::new(),
+            mode,
+        };
+        let mut out = String::new();
+        renderer.render(&nodes, &mut vec![&data], &mut out)?;
+        Ok(out)
+    }
+
+    fn render(template: &str, data: Value) -> String {
+        render_in(TemplateMode::Strict, template, data).unwrap()
+    }
+
+    fn strict_error(template: &str, data: Value) -> String {
+        match render_in(TemplateMode::Strict, template, data) {
+            Err(AppError::Template(message)) => message,
+            other => panic!(
+                "expected a template error, got {:?}",
+                other.map_err(|e| e.to_string())
+            ),
+        }
+    }
+
+    #[test]
+    fn escapes_values_unless_triple_braced() {
+        let data = serde_json::json!({
+            "name": "<script>alert('x')</script> & \"co\"",
+            "logo": "<img src=\"/images/a.png\">",
+            "items": [{ "label": "<b>" }],
+        });
+        assert_eq!(
+            render("<p title=\"{{name}}\">{{name}}</p>", data.clone()),
+            "<p title=\"&lt;script&gt;alert(&#39;x&#39;)&lt;/script&gt; &amp; &quot;co&quot;\">&lt;script&gt;alert(&#39;x&#39;)&lt;/script&gt; &amp; &quot;co&quot;</p>"
+        );
+        assert_eq!(
+            render("{{{logo}}}", data.clone()),
+            "<img src=\"/images/a.png\">"
+        );
+        assert_eq!(
+            render("{{#each items}}{{label}}{{{label}}}{{/each}}", data),
+            "&lt;b&gt;<b>"
+        );
+    }
+
+    #[test]
+    fn resolves_nested_paths() {
+        let data = serde_json::json!({
+            "customer": { "name": "Acme", "address": { "city": "Yangon", "zip": 11181 } },
+            "tags": ["a", "b"],
+            "paid": true,
+            "note": null,
+        });
+        assert_eq!(
+            render(
+                "{{customer.name}}, {{customer.address.city}} {{customer.address.zip}}",
+                data.clone()
+            ),
+            "Acme, Yangon 11181"
+        );
+        assert_eq!(render("{{tags.1}} {{paid}} [{{note}}]", data), "b true []");
+    }
+
+    #[test]
+    fn iterates_lists() {
+        let data = serde_json::json!({
+            "currency": "USD",
+            "items": [
+                { "name": "Pen", "price": 2, "tags": ["blue"] },
+                { "name": "Ink", "price": 5, "tags": [], "currency": "EUR" },
+            ],
+            "labels": ["x", "y"],
+            "empty": [],
+            "none": null,
+        });
+        // Item fields shadow outer ones, which stay reachable
+        assert_eq!(
+            render(
+                "{{#each items}}<li>{{name}} {{price}} {{currency}}{{#each tags}} #{{this}}{{/each}}</li>{{/each}}",
+                data.clone()
+            ),
+            "<li>Pen 2 USD #blue</li><li>Ink 5 EUR</li>"
+        );
+        assert_eq!(
+            render("{{#each labels}}{{this}}{{/each}}", data.clone()),
+            "xy"
+        );
+        assert_eq!(
+            render(
+                "{{#each items}}{{this.name}}{{#each labels}}{{this}}{{/each}};{{/each}}",
+                data.clone()
+            ),
+            "Penxy;Inkxy;"
+        );
+        // Empty and null lists render nothing, missing ones are an error
+        assert_eq!(
+            render(
+                "[{{#each empty}}x{{/each}}{{#each none}}x{{/each}}]",
+                data.clone()
+            ),
+            "[]"
+        );
+        assert_eq!(
+            strict_error("{{#each items}}{{#each missing}}{{/each}}{{/each}}", data),
+            "Unknown list {{#each missing}}"
+        );
+    }
+
+    #[test]
+    fn strict_mode_aborts_on_unresolved_values() {
+        let data = serde_json::json!({ "name": "Acme", "items": [{ "label": "a" }], "count": 2 });
+        assert_eq!(
+            strict_error("<p>{{missing}}</p>", data.clone()),
+            "Unknown placeholder {{missing}}"
+        );
+        // Also without a data payload
+        assert_eq!(
+            strict_error("<p>{{{name}}}</p>", Value::Null),
+            "Unknown placeholder {{{name}}}"
+        );
+        assert_eq!(
+            strict_error("{{#each items}}{{label.x}}{{/each}}", data.clone()),
+            "Unknown placeholder {{label.x}}"
+        );
+        assert_eq!(
+            strict_error("{{#each rows}}{{/each}}", data.clone()),
+            "Unknown list {{#each rows}}"
+        );
+        assert_eq!(
+            strict_error("{{#each count}}{{/each}}", data),
+            "{{#each count}} expects a list"
+        );
+    }
+
+    #[test]
+    fn lenient_mode_marks_unresolved_values() {
+        let data = serde_json::json!({ "name": "Acme", "count": 2 });
+        let out = render_in(
+            TemplateMode::Lenient,
+            "<p>{{name}} {{missing}}</p>{{#each count}}x{{/each}}",
+            data,
+        )
+        .unwrap();
+        assert_eq!(
+            out,
+            format!(
+                "<p>Acme {}</p>{}",
+                error_marker(&AppError::Template(String::from(
+                    "Unknown placeholder {{missing}}"
+                ))),
+                error_marker(&AppError::Template(String::from(
+                    "{{#each count}} expects a list"
+                ))),
+            )
+        );
+        assert!(out.contains("[template_error: Unknown placeholder {{missing}}]"));
+        let out = render_in(TemplateMode::Lenient, "{{title}}", Value::Null).unwrap();
+        assert!(out.contains("[template_error: Unknown placeholder {{title}}]"));
+    }
+
+    #[test]
+    fn sql_blocks_can_be_nested() {
+        assert_eq!(
+            nested_indexes("{{#sql(SELECT 1)}}{{#sql(SELECT 2)}}{{/sql}}{{/sql}}"),
+            [1]
+        );
+        assert_eq!(
+            nested_indexes(
+                "{{#each items}}{{#sql(SELECT 1)}}{{x}}{{/sql}}{{/each}}{{#sql(SELECT 2)}}{{/sql}}"
+            ),
+            [0]
+        );
+        assert!(nested_indexes("{{#sql(SELECT 1)}}{{x}}{{/sql}}").is_empty());
+        assert!(parse_template("{{#each items}}{{#sql(SELECT 1)}}{{/each}}{{/sql}}").is_err());
+    }
+
+    fn local_storage(base: &std::path::Path) -> LocalStorage {
+        let dir = |name: &str| base.join(name).to_string_lossy().to_string();
+        LocalStorage::new(&PathsConfig {
+            temp: dir("temp"),
+            templates: dir("templates"),
+            reports: dir("reports"),
+            images: dir("images"),
+            datasets: dir("datasets"),
+        })
+    }
+
+    async fn process(pool: &Pool, template: &str, mode: TemplateMode) -> Result<String, AppError> {
+        let storage = local_storage(std::path::Path::new("/nonexistent"));
+        process_in(Some(pool), &storage, template, mode).await
+    }
+
+    async fn process_in(
+        pool: Option<&Pool>,
+        storage: &dyn Storage,
+        template: &str,
+        mode: TemplateMode,
+    ) -> Result<String, AppError> {
+        let query_cache = QueryCache::new(10, 1 << 20);
+        let metrics = Metrics::new().unwrap();
+        let ctx = TemplateContext {
+            pool,
+            source: "default",
+            query_cache: &query_cache,
+            template_name: "test.html",
+            storage,
+            tenant: None,
+            data: None,
+            mode,
+            metrics: &metrics,
+        };
+        let mut out = Vec::new();
+        process_template(template, &ctx, &mut out).await?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    // Fails with division_by_zero after `secs`, when the row is produced
+    fn failing_query(secs: f64) -> String {
+        format!(
+            "SELECT 1 / (extract(epoch FROM clock_timestamp())::int * 0) AS x FROM pg_sleep({secs})"
+        )
+    }
+
+    #[actix_web::test]
+    async fn top_level_blocks_run_concurrently() {
+        let Some(pool) = testing::pool() else {
+            return;
+        };
+        let block = "{{#sql(SELECT 1 AS x FROM pg_sleep(0.5))}}{{x}}{{/sql}}";
+        let started = Instant::now();
+        let out = process(&pool, &block.repeat(4), TemplateMode::Strict)
+            .await
+            .unwrap();
+        assert_eq!(out, "1111");
+        assert!(started.elapsed() < Duration::from_millis(1500));
+    }
+
+    #[actix_web::test]
+    async fn reports_the_first_failing_block_and_cancels_the_rest() {
+        let Some(pool) = testing::pool() else {
+            return;
+        };
+        let marker = Uuid::new_v4().simple().to_string();
+        // The first block fails after the second one
+        let template = format!(
+            "<p>{{{{#sql({})}}}}{{{{/sql}}}}{{{{#sql({})}}}}{{{{/sql}}}}\
+             {{{{#sql(SELECT 1 AS x FROM pg_sleep(30) /* {marker} */)}}}}{{{{/sql}}}}</p>",
+            failing_query(0.3),
+            failing_query(0.0),
+        );
+
+        let started = Instant::now();
+        let err = process(&pool, &template, TemplateMode::Strict)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            AppError::SqlBlock {
+                block: 1,
+                ref sqlstate,
+                ..
+            } if sqlstate.as_deref() == Some("22012")
+        ));
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        // The third query is cancelled on the server
+        let client = pool.get().await.unwrap();
+        let mut running = true;
+        for _ in 0..20 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let row = client
+                .query_one(
+                    "SELECT count(*) FROM pg_stat_activity
+                     WHERE pid <> pg_backend_pid() AND query LIKE '%' || $1 || '%'",
+                    &[&marker],
+                )
+                .await
+                .unwrap();
+            running = row.get::<_, i64>(0) > 0;
+            if !running {
+                break;
+            }
+        }
+        assert!(!running);
+
+        // Lenient mode marks every failing block in document order
+        let template = format!(
+            "{{{{#sql({})}}}}{{{{/sql}}}}|{{{{#sql({})}}}}{{{{/sql}}}}",
+            failing_query(0.2),
+            failing_query(0.0),
+        );
+        let out = process(&pool, &template, TemplateMode::Lenient)
+            .await
+            .unwrap();
+        let (first, second) = out.split_once('|').unwrap();
+        assert!(first.contains("sql block #1"), "{first}");
+        assert!(second.contains("sql block #2"), "{second}");
+    }
+
+    #[actix_web::test]
+    async fn binds_dataset_rows() {
+        let base = std::env::temp_dir().join(format!("datasets-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&base).unwrap();
+        let storage = local_storage(&base);
+        let (_, rows) = parse_csv(b"Region,Unit Price\nNorth,3\n<South>,4\n").unwrap();
+        let upload = base.join("sales.json");
+        std::fs::write(&upload, serde_json::to_vec(&rows).unwrap()).unwrap();
+        storage
+            .put(&dataset_key(None, "sales"), &upload)
+            .await
+            .unwrap();
+
+        let out = process_in(
+            None,
+            &storage,
+            "<table>{{#dataset(sales)}}<tr><td>{{Region}}</td><td>{{Unit_Price}}</td></tr>{{/dataset}}</table>",
+            TemplateMode::Strict,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            out,
+            "<table><tr><td>North</td><td>3</td></tr><tr><td>&lt;South&gt;</td><td>4</td></tr></table>"
+        );
+
+        let missing = "{{#dataset(missing)}}{{Region}}{{/dataset}}";
+        let err = process_in(None, &storage, missing, TemplateMode::Strict)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AppError::Dataset { ref name, .. } if name == "missing"));
+        let out = process_in(None, &storage, missing, TemplateMode::Lenient)
+            .await
+            .unwrap();
+        assert!(out.contains("[dataset_error: "), "{out}");
+        std::fs::remove_dir_all(base).unwrap();
+    }
 }
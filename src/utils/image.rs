@@ -1,17 +1,399 @@
-pub fn get_image_format_from_path(path: &str) -> Option<image::ImageFormat> {
-    if path.ends_with(".png") {
-        Some(image::ImageFormat::Png)
-    } else if path.ends_with(".jpg") || path.ends_with(".jpeg") {
-        Some(image::ImageFormat::Jpeg)
-    } else if path.ends_with(".gif") {
-        Some(image::ImageFormat::Gif)
-    } else if path.ends_with(".bmp") {
-        Some(image::ImageFormat::Bmp)
-    } else if path.ends_with(".ico") {
-        Some(image::ImageFormat::Ico)
-    } else if path.ends_with(".tiff") {
-        Some(image::ImageFormat::Tiff)
-    } else {
-        None
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Write},
+    path::Path,
+    str::FromStr,
+};
+
+use image::{
+    codecs::{
+        jpeg::JpegEncoder,
+        png::{CompressionType, FilterType as PngFilter, PngEncoder},
+        webp::{WebPEncoder, WebPQuality},
+    },
+    error::{ImageFormatHint, UnsupportedError, UnsupportedErrorKind},
+    imageops::FilterType,
+    io::{Limits, Reader},
+    DynamicImage, GenericImageView, ImageEncoder, ImageError, ImageFormat, ImageResult, Rgb,
+    RgbImage,
+};
+use serde::Deserialize;
+
+/// The format uploaded images are stored in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// The format of the upload, if it can be written, otherwise PNG.
+    #[default]
+    Original,
+    Webp,
+    Jpeg,
+    Png,
+}
+
+impl FromStr for OutputFormat {
+    type Err = &'static str;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "original" => Ok(OutputFormat::Original),
+            "webp" => Ok(OutputFormat::Webp),
+            "jpeg" | "jpg" => Ok(OutputFormat::Jpeg),
+            "png" => Ok(OutputFormat::Png),
+            _ => Err("must be original, webp, jpeg or png"),
+        }
+    }
+}
+
+impl OutputFormat {
+    /// The format images uploaded as `source` are written in.
+    pub fn resolve(self, source: ImageFormat) -> ImageFormat {
+        match self {
+            OutputFormat::Original => match source {
+                ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::WebP | ImageFormat::Gif => {
+                    source
+                }
+                _ => ImageFormat::Png,
+            },
+            OutputFormat::Webp => ImageFormat::WebP,
+            OutputFormat::Jpeg => ImageFormat::Jpeg,
+            OutputFormat::Png => ImageFormat::Png,
+        }
+    }
+}
+
+pub fn extension(format: ImageFormat) -> &'static str {
+    format.extensions_str().first().copied().unwrap_or("bin")
+}
+
+/// The largest image `decode` accepts. The dimensions in the header are checked before
+/// any pixels are allocated, so a small file can't claim a huge image.
+#[derive(Clone, Copy)]
+pub struct DecodeLimits {
+    pub max_width: u32,
+    pub max_height: u32,
+    pub max_alloc: u64,
+}
+
+/// Decodes the image at `path`. The format is sniffed from its contents, whatever its name
+/// says, and the image is turned upright according to its EXIF orientation. Images over
+/// `limits` fail with `ImageError::Limits`.
+pub fn decode(path: &Path, limits: DecodeLimits) -> ImageResult<(DynamicImage, ImageFormat)> {
+    let mut reader = Reader::open(path)?.with_guessed_format()?;
+    let mut reader_limits = Limits::default();
+    reader_limits.max_image_width = Some(limits.max_width);
+    reader_limits.max_image_height = Some(limits.max_height);
+    reader_limits.max_alloc = Some(limits.max_alloc);
+    reader.limits(reader_limits);
+    let format = reader.format().ok_or_else(|| {
+        ImageError::Unsupported(UnsupportedError::from_format_and_kind(
+            ImageFormatHint::Unknown,
+            UnsupportedErrorKind::Format(ImageFormatHint::Unknown),
+        ))
+    })?;
+    let image = reader.decode()?;
+    Ok((apply_orientation(image, orientation(path)), format))
+}
+
+// The EXIF orientation, 1 (upright) when there is none
+fn orientation(path: &Path) -> u32 {
+    let Ok(file) = File::open(path) else {
+        return 1;
+    };
+    exif::Reader::new()
+        .read_from_container(&mut BufReader::new(file))
+        .ok()
+        .and_then(|exif| {
+            exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)?
+                .value
+                .get_uint(0)
+        })
+        .unwrap_or(1)
+}
+
+fn apply_orientation(image: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
+
+/// Writes the image to `path`. `quality` (1-100) applies to JPEG and WebP. Nothing but the
+/// pixels is written, so metadata like EXIF and GPS positions is dropped.
+pub fn encode(
+    image: &DynamicImage,
+    format: ImageFormat,
+    quality: u8,
+    path: &str,
+) -> ImageResult<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    let (width, height) = image.dimensions();
+    match format {
+        ImageFormat::Jpeg => {
+            let pixels = flatten(image);
+            JpegEncoder::new_with_quality(&mut writer, quality).encode(
+                &pixels,
+                width,
+                height,
+                image::ColorType::Rgb8,
+            )?;
+        }
+        ImageFormat::WebP => {
+            let encoder = WebPEncoder::new_with_quality(&mut writer, WebPQuality::lossy(quality));
+            if image.color().has_alpha() {
+                encoder.encode(&image.to_rgba8(), width, height, image::ColorType::Rgba8)?;
+            } else {
+                encoder.encode(&image.to_rgb8(), width, height, image::ColorType::Rgb8)?;
+            }
+        }
+        ImageFormat::Png => {
+            let encoder = PngEncoder::new_with_quality(
+                &mut writer,
+                CompressionType::Best,
+                PngFilter::Adaptive,
+            );
+            if image.color().has_alpha() {
+                encoder.write_image(&image.to_rgba8(), width, height, image::ColorType::Rgba8)?;
+            } else {
+                encoder.write_image(&image.to_rgb8(), width, height, image::ColorType::Rgb8)?;
+            }
+        }
+        _ => image.write_to(&mut writer, format)?,
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+// JPEG has no transparency, so transparent parts become white instead of black
+fn flatten(image: &DynamicImage) -> RgbImage {
+    if !image.color().has_alpha() {
+        return image.to_rgb8();
+    }
+    let rgba = image.to_rgba8();
+    RgbImage::from_fn(image.width(), image.height(), |x, y| {
+        let [r, g, b, a] = rgba.get_pixel(x, y).0;
+        let blend =
+            |channel: u8| ((channel as u16 * a as u16 + 255 * (255 - a as u16)) / 255) as u8;
+        Rgb([blend(r), blend(g), blend(b)])
+    })
+}
+
+/// How an image is brought to the size of a variant. Images are never enlarged.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResizeMode {
+    /// Scales the image down to fit inside the size, keeping its aspect ratio.
+    #[default]
+    Fit,
+    /// Scales the image down to cover the size and crops the overflow around the center.
+    Fill,
+    /// Cuts the size out of the center of the image without scaling it.
+    Crop,
+}
+
+impl FromStr for ResizeMode {
+    type Err = &'static str;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "fit" => Ok(ResizeMode::Fit),
+            "fill" => Ok(ResizeMode::Fill),
+            "crop" => Ok(ResizeMode::Crop),
+            _ => Err("must be fit, fill or crop"),
+        }
+    }
+}
+
+/// A size an uploaded image is also stored in, e.g. a thumbnail.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ImageVariant {
+    pub width: u32,
+    pub height: u32,
+    #[serde(default)]
+    pub mode: ResizeMode,
+}
+
+// Written as `WxH` or `WxH:mode`, e.g. `150x150:fill`
+impl FromStr for ImageVariant {
+    type Err = &'static str;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (size, mode) = match value.split_once(':') {
+            Some((size, mode)) => (size, mode.parse()?),
+            None => (value, ResizeMode::default()),
+        };
+        let (width, height) = size
+            .split_once('x')
+            .and_then(|(width, height)| Some((width.parse().ok()?, height.parse().ok()?)))
+            .ok_or("must look like 800x600 or 800x600:fill")?;
+        let variant = ImageVariant {
+            width,
+            height,
+            mode,
+        };
+        if !variant.is_valid() {
+            return Err("width and height must be at least 1");
+        }
+        Ok(variant)
+    }
+}
+
+impl ImageVariant {
+    pub fn is_valid(&self) -> bool {
+        self.width > 0 && self.height > 0
+    }
+
+    /// Returns the image in the size of this variant.
+    pub fn apply(&self, image: &DynamicImage) -> DynamicImage {
+        let (width, height) = image.dimensions();
+        match self.mode {
+            ResizeMode::Fit if width <= self.width && height <= self.height => image.clone(),
+            ResizeMode::Fit => image.resize(self.width, self.height, FilterType::Lanczos3),
+            ResizeMode::Fill if width >= self.width && height >= self.height => {
+                image.resize_to_fill(self.width, self.height, FilterType::Lanczos3)
+            }
+            // Too small to cover the size, so only the aspect ratio is matched
+            ResizeMode::Fill => {
+                let scale = f64::min(
+                    width as f64 / self.width as f64,
+                    height as f64 / self.height as f64,
+                );
+                let crop_width = (self.width as f64 * scale).round() as u32;
+                let crop_height = (self.height as f64 * scale).round() as u32;
+                center_crop(image, crop_width, crop_height)
+            }
+            ResizeMode::Crop => center_crop(image, self.width, self.height),
+        }
+    }
+}
+
+fn center_crop(image: &DynamicImage, width: u32, height: u32) -> DynamicImage {
+    let width = width.clamp(1, image.width());
+    let height = height.clamp(1, image.height());
+    image.crop_imm(
+        (image.width() - width) / 2,
+        (image.height() - height) / 2,
+        width,
+        height,
+    )
+}
+
+/// Variant names become file names, so they are limited to letters, digits, `_` and `-`.
+pub fn is_valid_variant_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "original"
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variant(value: &str) -> ImageVariant {
+        value.parse().unwrap()
+    }
+
+    fn blank(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::new_rgb8(width, height)
+    }
+
+    #[test]
+    fn parses_sizes_and_modes() {
+        assert_eq!(
+            variant("150x100:fill"),
+            ImageVariant {
+                width: 150,
+                height: 100,
+                mode: ResizeMode::Fill
+            }
+        );
+        assert_eq!(variant("800x600").mode, ResizeMode::Fit);
+        assert!("0x10".parse::<ImageVariant>().is_err());
+        assert!("10x10:stretch".parse::<ImageVariant>().is_err());
+        assert!("10".parse::<ImageVariant>().is_err());
+    }
+
+    #[test]
+    fn keeps_writable_formats_and_converts_the_rest() {
+        assert_eq!(
+            OutputFormat::Original.resolve(ImageFormat::Jpeg),
+            ImageFormat::Jpeg
+        );
+        assert_eq!(
+            OutputFormat::Original.resolve(ImageFormat::Tiff),
+            ImageFormat::Png
+        );
+        assert_eq!(
+            OutputFormat::Webp.resolve(ImageFormat::Png),
+            ImageFormat::WebP
+        );
+        assert_eq!("jpg".parse(), Ok(OutputFormat::Jpeg));
+    }
+
+    #[test]
+    fn rejects_images_over_the_decode_limits() {
+        let path = std::env::temp_dir().join(format!("decode-{}.png", std::process::id()));
+        blank(100, 40).save(&path).unwrap();
+        let limits = |max_width, max_height, max_alloc| DecodeLimits {
+            max_width,
+            max_height,
+            max_alloc,
+        };
+
+        let (image, format) = decode(&path, limits(100, 40, 1 << 20)).unwrap();
+        assert_eq!((image.dimensions(), format), ((100, 40), ImageFormat::Png));
+        for limits in [
+            limits(99, 40, 1 << 20),
+            limits(100, 39, 1 << 20),
+            limits(100, 40, 1000),
+        ] {
+            assert!(matches!(decode(&path, limits), Err(ImageError::Limits(_))));
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn orientation_turns_the_image_upright() {
+        assert_eq!(apply_orientation(blank(40, 20), 6).dimensions(), (20, 40));
+        assert_eq!(apply_orientation(blank(40, 20), 3).dimensions(), (40, 20));
+        assert_eq!(apply_orientation(blank(40, 20), 8).dimensions(), (20, 40));
+    }
+
+    #[test]
+    fn fit_keeps_the_aspect_ratio_and_never_enlarges() {
+        assert_eq!(
+            variant("100x100").apply(&blank(400, 200)).dimensions(),
+            (100, 50)
+        );
+        assert_eq!(
+            variant("800x800").apply(&blank(400, 200)).dimensions(),
+            (400, 200)
+        );
+    }
+
+    #[test]
+    fn fill_and_crop_produce_the_exact_size() {
+        assert_eq!(
+            variant("100x100:fill").apply(&blank(400, 200)).dimensions(),
+            (100, 100)
+        );
+        assert_eq!(
+            variant("300x100:crop").apply(&blank(400, 200)).dimensions(),
+            (300, 100)
+        );
+        // Smaller images keep the requested aspect ratio instead
+        assert_eq!(
+            variant("800x400:fill").apply(&blank(300, 300)).dimensions(),
+            (300, 150)
+        );
     }
 }
@@ -0,0 +1,23 @@
+use tracing_subscriber::EnvFilter;
+
+use crate::utils::setting::{LogFormat, LoggingConfig};
+
+/// Installs the global log subscriber. `level` takes an `EnvFilter` directive such as
+/// `info` or `report_forge=debug,actix_web=warn`; the json format writes one JSON object
+/// per line, including the fields of the enclosing request span.
+pub fn init(config: &LoggingConfig) {
+    // The level was validated when the configuration was loaded
+    let filter = EnvFilter::try_new(&config.level).unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_target(false);
+    match config.format {
+        LogFormat::Json => builder
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(false)
+            .init(),
+        LogFormat::Text => builder.init(),
+    }
+}
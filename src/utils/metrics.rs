@@ -0,0 +1,171 @@
+use std::{fs, path::Path, time::Duration};
+
+use prometheus::{
+    exponential_buckets, Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+
+use crate::utils::setting::config;
+
+// Directories whose size is reported on every scrape
+const WATCHED_DIRS: [&str; 3] = ["reports", "images", "temp"];
+
+/// Prometheus metrics of the service, rendered in the text format by `GET /metrics`.
+pub struct Metrics {
+    registry: Registry,
+    renders: IntCounterVec,
+    render_duration: HistogramVec,
+    output_bytes: HistogramVec,
+    renders_in_flight: IntGauge,
+    sql_block_duration: prometheus::Histogram,
+    rows_fetched: IntCounter,
+    disk_usage: IntGaugeVec,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self, prometheus::Error> {
+        let renders = IntCounterVec::new(
+            Opts::new(
+                "report_forge_renders_total",
+                "Renders by endpoint and outcome",
+            ),
+            &["endpoint", "outcome"],
+        )?;
+        let render_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "report_forge_render_duration_seconds",
+                "Time spent running sitetopdf",
+            )
+            .buckets(exponential_buckets(0.1, 2.0, 10)?),
+            &["endpoint"],
+        )?;
+        let output_bytes = HistogramVec::new(
+            HistogramOpts::new("report_forge_output_bytes", "Size of rendered files")
+                .buckets(exponential_buckets(16.0 * 1024.0, 4.0, 8)?),
+            &["endpoint"],
+        )?;
+        let renders_in_flight = IntGauge::new(
+            "report_forge_renders_in_flight",
+            "Renders currently being processed",
+        )?;
+        let sql_block_duration = prometheus::Histogram::with_opts(
+            HistogramOpts::new(
+                "report_forge_sql_block_duration_seconds",
+                "Time spent running and streaming a {{#sql}} block",
+            )
+            .buckets(exponential_buckets(0.005, 2.0, 12)?),
+        )?;
+        let rows_fetched = IntCounter::new(
+            "report_forge_sql_rows_fetched_total",
+            "Rows fetched by {{#sql}} blocks",
+        )?;
+        let disk_usage = IntGaugeVec::new(
+            Opts::new(
+                "report_forge_disk_usage_bytes",
+                "Size of the files in a directory",
+            ),
+            &["dir"],
+        )?;
+
+        let registry = Registry::new();
+        registry.register(Box::new(renders.clone()))?;
+        registry.register(Box::new(render_duration.clone()))?;
+        registry.register(Box::new(output_bytes.clone()))?;
+        registry.register(Box::new(renders_in_flight.clone()))?;
+        registry.register(Box::new(sql_block_duration.clone()))?;
+        registry.register(Box::new(rows_fetched.clone()))?;
+        registry.register(Box::new(disk_usage.clone()))?;
+
+        Ok(Metrics {
+            registry,
+            renders,
+            render_duration,
+            output_bytes,
+            renders_in_flight,
+            sql_block_duration,
+            rows_fetched,
+            disk_usage,
+        })
+    }
+
+    /// Counts a render as in flight until the returned guard is dropped. Unless the guard
+    /// was marked as succeeded, the render is recorded as failed.
+    pub fn start_render(&self, endpoint: &'static str) -> RenderMetrics<'_> {
+        self.renders_in_flight.inc();
+        RenderMetrics {
+            metrics: self,
+            endpoint,
+            outcome: "failure",
+        }
+    }
+
+    pub fn observe_sql_block(&self, elapsed: Duration, rows: u64) {
+        self.sql_block_duration.observe(elapsed.as_secs_f64());
+        self.rows_fetched.inc_by(rows);
+    }
+
+    /// Encodes every metric in the Prometheus text format. Directory sizes are measured
+    /// now, so this does blocking file system work.
+    pub fn render(&self) -> Result<String, prometheus::Error> {
+        for (name, dir) in config().paths.all() {
+            if WATCHED_DIRS.contains(&name) {
+                self.disk_usage
+                    .with_label_values(&[name])
+                    .set(dir_size(Path::new(dir)) as i64);
+            }
+        }
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        String::from_utf8(buffer).map_err(|err| prometheus::Error::Msg(err.to_string()))
+    }
+}
+
+pub struct RenderMetrics<'a> {
+    metrics: &'a Metrics,
+    endpoint: &'static str,
+    outcome: &'static str,
+}
+
+impl RenderMetrics<'_> {
+    pub fn observe_renderer(&self, elapsed: Duration) {
+        self.metrics
+            .render_duration
+            .with_label_values(&[self.endpoint])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn succeeded(&mut self, output_path: &str) {
+        let bytes = fs::metadata(output_path)
+            .map(|m| m.len())
+            .unwrap_or_default();
+        self.metrics
+            .output_bytes
+            .with_label_values(&[self.endpoint])
+            .observe(bytes as f64);
+        self.outcome = "success";
+    }
+}
+
+impl Drop for RenderMetrics<'_> {
+    fn drop(&mut self) {
+        self.metrics.renders_in_flight.dec();
+        self.metrics
+            .renders
+            .with_label_values(&[self.endpoint, self.outcome])
+            .inc();
+    }
+}
+
+fn dir_size(path: &Path) -> u64 {
+    let Ok(entries) = fs::read_dir(path) else {
+        return 0;
+    };
+    entries
+        .flatten()
+        .map(|entry| match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() => dir_size(&entry.path()),
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
@@ -0,0 +1,182 @@
+use lru::LruCache;
+use serde_json::Value;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+// Queries are not parameterised, so the data source and the query text identify a result.
+// The template is part of the key too: the same query in two templates is cached twice, but
+// invalidating one template never drops the results of another.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    pub template: String,
+    pub source: String,
+    pub query: String,
+}
+
+struct CacheEntry {
+    rows: Arc<Vec<Value>>,
+    size: usize,
+    expires_at: Instant,
+}
+
+struct CacheState {
+    entries: LruCache<CacheKey, CacheEntry>,
+    // Keys of every cached entry by template, so a template can be invalidated at once
+    templates: HashMap<String, HashSet<CacheKey>>,
+    total_bytes: usize,
+}
+
+impl CacheState {
+    // Removes an entry that is no longer cached, e.g. expired or evicted
+    fn forget(&mut self, key: &CacheKey, entry: CacheEntry) {
+        self.total_bytes -= entry.size;
+        if let Some(keys) = self.templates.get_mut(&key.template) {
+            keys.remove(key);
+            if keys.is_empty() {
+                self.templates.remove(&key.template);
+            }
+        }
+    }
+}
+
+pub struct QueryCache {
+    state: Mutex<CacheState>,
+    max_entries: usize,
+    max_bytes: usize,
+}
+
+impl QueryCache {
+    pub fn new(max_entries: usize, max_bytes: usize) -> Self {
+        QueryCache {
+            state: Mutex::new(CacheState {
+                entries: LruCache::unbounded(),
+                templates: HashMap::new(),
+                total_bytes: 0,
+            }),
+            max_entries,
+            max_bytes,
+        }
+    }
+
+    pub fn max_bytes(&self) -> usize {
+        self.max_bytes
+    }
+
+    pub fn get(&self, key: &CacheKey) -> Option<Arc<Vec<Value>>> {
+        let mut state = self.state.lock().unwrap();
+        let expired = match state.entries.get(key) {
+            Some(entry) if entry.expires_at > Instant::now() => {
+                return Some(entry.rows.clone());
+            }
+            Some(_) => true,
+            None => false,
+        };
+        if expired {
+            if let Some(entry) = state.entries.pop(key) {
+                state.forget(key, entry);
+            }
+        }
+        None
+    }
+
+    pub fn insert(&self, key: CacheKey, rows: Arc<Vec<Value>>, ttl: u64) {
+        // Serialised JSON length is a close enough estimate of what a result set costs
+        let size = rows.iter().map(|row| row.to_string().len()).sum::<usize>() + key.query.len();
+        if size > self.max_bytes || self.max_entries == 0 {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let entry = CacheEntry {
+            rows,
+            size,
+            expires_at: Instant::now() + Duration::from_secs(ttl),
+        };
+        if let Some(old) = state.entries.put(key.clone(), entry) {
+            state.total_bytes -= old.size;
+        }
+        state.total_bytes += size;
+        state
+            .templates
+            .entry(key.template.clone())
+            .or_default()
+            .insert(key);
+
+        while state.entries.len() > self.max_entries || state.total_bytes > self.max_bytes {
+            match state.entries.pop_lru() {
+                Some((evicted_key, evicted)) => state.forget(&evicted_key, evicted),
+                None => break,
+            }
+        }
+    }
+
+    // Drops every cached result that was produced while rendering the given template.
+    // Returns how many entries were removed.
+    pub fn invalidate_template(&self, template_name: &str) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let keys = state.templates.remove(template_name).unwrap_or_default();
+        let mut removed = 0;
+        for key in keys {
+            if let Some(entry) = state.entries.pop(&key) {
+                state.total_bytes -= entry.size;
+                removed += 1;
+            }
+        }
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn key(template: &str, query: &str) -> CacheKey {
+        CacheKey {
+            template: template.to_string(),
+            source: String::from("default"),
+            query: query.to_string(),
+        }
+    }
+
+    fn rows() -> Arc<Vec<Value>> {
+        Arc::new(vec![json!({ "x": 1 })])
+    }
+
+    fn tracked_keys(cache: &QueryCache) -> usize {
+        let state = cache.state.lock().unwrap();
+        state.templates.values().map(HashSet::len).sum()
+    }
+
+    #[test]
+    fn invalidates_only_the_given_template() {
+        let cache = QueryCache::new(10, 1 << 20);
+        cache.insert(key("a.html", "SELECT 1"), rows(), 60);
+        cache.insert(key("a.html", "SELECT 2"), rows(), 60);
+        cache.insert(key("b.html", "SELECT 1"), rows(), 60);
+
+        assert_eq!(cache.invalidate_template("a.html"), 2);
+        assert!(cache.get(&key("a.html", "SELECT 1")).is_none());
+        assert!(cache.get(&key("b.html", "SELECT 1")).is_some());
+        assert_eq!(cache.invalidate_template("a.html"), 0);
+    }
+
+    #[test]
+    fn evicted_and_expired_entries_are_forgotten() {
+        let cache = QueryCache::new(2, 1 << 20);
+        for query in ["SELECT 1", "SELECT 2", "SELECT 3"] {
+            cache.insert(key("a.html", query), rows(), 60);
+        }
+        assert!(cache.get(&key("a.html", "SELECT 1")).is_none());
+        assert_eq!(tracked_keys(&cache), 2);
+
+        cache.insert(key("b.html", "SELECT 1"), rows(), 0);
+        assert!(cache.get(&key("b.html", "SELECT 1")).is_none());
+        assert_eq!(tracked_keys(&cache), 1);
+        assert!(!cache.state.lock().unwrap().templates.contains_key("b.html"));
+    }
+}
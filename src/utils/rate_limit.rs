@@ -0,0 +1,693 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    future::{ready, Ready},
+    hash::Hash,
+    io::{self, Read},
+    net::IpAddr,
+    rc::Rc,
+    sync::{LazyLock, Mutex},
+    time::Instant,
+};
+
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    web, Error, HttpMessage, ResponseError,
+};
+use chrono::{Duration, NaiveDate, Utc};
+use deadpool_postgres::Pool;
+use futures::future::LocalBoxFuture;
+use regex::bytes::Regex;
+use serde::Serialize;
+use tracing::error;
+
+use crate::utils::{auth::Principal, error::AppError, setting::config};
+
+// Idle buckets are dropped once a map grows beyond this many clients
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+// Clients over their concurrency limit are asked to retry after this many seconds
+const RENDER_RETRY_AFTER: u64 = 5;
+
+// Page objects are `/Type /Page`, the page tree nodes are `/Type /Pages`. The whitespace is
+// bounded, so a match never spans more than `PAGE_MATCH_MAX_LEN` bytes.
+static PAGE_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"/Type\s{0,16}/Page[^s]").expect("page regex is valid"));
+const PAGE_MATCH_MAX_LEN: usize = 32;
+const PAGE_SCAN_CHUNK: usize = 64 * 1024;
+
+#[derive(Clone, Copy)]
+struct Rate {
+    per_minute: u32,
+    burst: u32,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+// Takes a token from the client's bucket, returning the seconds until one is available
+fn take<K: Eq + Hash>(buckets: &Mutex<HashMap<K, Bucket>>, key: K, rate: Rate) -> Result<(), u64> {
+    if rate.per_minute == 0 {
+        return Ok(());
+    }
+    let per_second = rate.per_minute as f64 / 60.0;
+    let burst = rate.burst.max(1) as f64;
+    let now = Instant::now();
+    let mut buckets = buckets.lock().unwrap();
+
+    if buckets.len() >= MAX_TRACKED_CLIENTS {
+        buckets.retain(|_, bucket| {
+            bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * per_second < burst
+        });
+    }
+
+    let bucket = buckets.entry(key).or_insert(Bucket {
+        tokens: burst,
+        updated: now,
+    });
+    let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+    bucket.tokens = (bucket.tokens + elapsed * per_second).min(burst);
+    bucket.updated = now;
+    if bucket.tokens >= 1.0 {
+        bucket.tokens -= 1.0;
+        Ok(())
+    } else {
+        Err(((1.0 - bucket.tokens) / per_second).ceil() as u64)
+    }
+}
+
+fn tokens_left<K: Eq + Hash>(buckets: &Mutex<HashMap<K, Bucket>>, key: &K, rate: Rate) -> u32 {
+    let burst = rate.burst.max(1) as f64;
+    let buckets = buckets.lock().unwrap();
+    match buckets.get(key) {
+        Some(bucket) => {
+            let elapsed = bucket.updated.elapsed().as_secs_f64();
+            (bucket.tokens + elapsed * rate.per_minute as f64 / 60.0).min(burst) as u32
+        }
+        None => burst as u32,
+    }
+}
+
+fn seconds_until_tomorrow() -> u64 {
+    let now = Utc::now();
+    let tomorrow = (now.date_naive() + Duration::days(1))
+        .and_hms_opt(0, 0, 0)
+        .unwrap_or_default()
+        .and_utc();
+    (tomorrow - now).num_seconds().max(1) as u64
+}
+
+/// Counts the pages of a rendered PDF; images count as a single page.
+pub fn count_pages(path: &str) -> i64 {
+    if !path.ends_with(".pdf") {
+        return 1;
+    }
+    File::open(path)
+        .and_then(|file| count_page_objects(file, PAGE_SCAN_CHUNK))
+        .unwrap_or_default()
+}
+
+// Scans the PDF in chunks, so large reports aren't read into memory. The tail of a chunk,
+// where a match could be cut off, is carried over and scanned again with the next one.
+fn count_page_objects(mut reader: impl Read, chunk_size: usize) -> io::Result<i64> {
+    let mut buffer = Vec::with_capacity(chunk_size + PAGE_MATCH_MAX_LEN);
+    let mut pages = 0;
+    loop {
+        let carried = buffer.len();
+        buffer.resize(carried + chunk_size, 0);
+        let read = reader.read(&mut buffer[carried..])?;
+        buffer.truncate(carried + read);
+        if read == 0 {
+            pages += PAGE_RE.find_iter(&buffer).count();
+            return Ok(pages as i64);
+        }
+        // Matches starting before the tail are complete, the rest is scanned next time
+        let tail = buffer.len().saturating_sub(PAGE_MATCH_MAX_LEN);
+        let mut next = tail;
+        for found in PAGE_RE.find_iter(&buffer) {
+            if found.start() >= tail {
+                break;
+            }
+            pages += 1;
+            next = next.max(found.end());
+        }
+        buffer.drain(..next);
+    }
+}
+
+#[derive(Serialize)]
+pub struct DailyUsage {
+    pub day: NaiveDate,
+    pub renders: i64,
+    pub pages: i64,
+    pub bytes: i64,
+}
+
+#[derive(Serialize)]
+pub struct Usage {
+    pub client: String,
+    pub requests_remaining: u32,
+    pub requests_per_minute: u32,
+    pub concurrent_renders: usize,
+    pub max_concurrent_renders: usize,
+    pub daily_page_limit: i64,
+    pub daily_byte_limit: i64,
+    pub today: DailyUsage,
+}
+
+/// Per client request rates, concurrent renders and daily render volume.
+pub struct RateLimiter {
+    key_rate: Rate,
+    ip_rate: Rate,
+    max_concurrent_renders: usize,
+    daily_page_limit: i64,
+    daily_byte_limit: i64,
+    keys: Mutex<HashMap<String, Bucket>>,
+    ips: Mutex<HashMap<IpAddr, Bucket>>,
+    renders: Mutex<HashMap<String, usize>>,
+}
+
+/// Held while a render runs; releases the client's render slot when dropped. A render that
+/// never gets recorded gives its daily reservation back as well.
+pub struct RenderPermit {
+    limiter: web::Data<RateLimiter>,
+    client: String,
+    reservation: Option<Reservation>,
+}
+
+// A render and a page booked on the client's daily usage before the render started
+struct Reservation {
+    pool: Pool,
+    day: NaiveDate,
+}
+
+impl RenderPermit {
+    /// Adds the finished render to the client's daily usage.
+    pub async fn record(
+        &mut self,
+        pool: &Pool,
+        output_path: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let bytes = std::fs::metadata(output_path)
+            .map(|m| m.len() as i64)
+            .unwrap_or_default();
+        let pages = count_pages(output_path);
+        match &self.reservation {
+            Some(reservation) => {
+                // The render and its first page were booked when it started
+                let client = reservation.pool.get().await?;
+                client
+                    .execute(
+                        "UPDATE daily_usage SET pages = pages + $3, bytes = bytes + $4
+                         WHERE client_id = $1 AND day = $2",
+                        &[&self.client, &reservation.day, &(pages.max(1) - 1), &bytes],
+                    )
+                    .await?;
+                self.reservation = None;
+            }
+            None => {
+                let client = pool.get().await?;
+                client
+                    .execute(
+                        "INSERT INTO daily_usage (client_id, day, renders, pages, bytes)
+                         VALUES ($1, $2, 1, $3, $4)
+                         ON CONFLICT (client_id, day) DO UPDATE SET
+                            renders = daily_usage.renders + 1,
+                            pages = daily_usage.pages + EXCLUDED.pages,
+                            bytes = daily_usage.bytes + EXCLUDED.bytes",
+                        &[&self.client, &Utc::now().date_naive(), &pages, &bytes],
+                    )
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for RenderPermit {
+    fn drop(&mut self) {
+        let mut renders = self.limiter.renders.lock().unwrap();
+        if let Some(count) = renders.get_mut(&self.client) {
+            *count -= 1;
+            if *count == 0 {
+                renders.remove(&self.client);
+            }
+        }
+        drop(renders);
+
+        if let Some(Reservation { pool, day }) = self.reservation.take() {
+            let client_id = std::mem::take(&mut self.client);
+            tokio::spawn(async move {
+                if let Err(err) = release(&pool, &client_id, day).await {
+                    error!("Failed to release reserved usage: {:?}", err);
+                }
+            });
+        }
+    }
+}
+
+impl RateLimiter {
+    pub fn from_settings() -> Self {
+        let config = config();
+        RateLimiter {
+            key_rate: Rate {
+                per_minute: config.rate_limit.key_per_minute,
+                burst: config.rate_limit.key_burst,
+            },
+            ip_rate: Rate {
+                per_minute: config.rate_limit.ip_per_minute,
+                burst: config.rate_limit.ip_burst,
+            },
+            max_concurrent_renders: config.limits.max_concurrent_renders,
+            daily_page_limit: config.limits.daily_page_limit,
+            daily_byte_limit: config.limits.daily_byte_limit,
+            keys: Mutex::new(HashMap::new()),
+            ips: Mutex::new(HashMap::new()),
+            renders: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Reserves a render slot for the client, checking its concurrency and daily limits.
+    pub async fn start_render(
+        limiter: &web::Data<RateLimiter>,
+        pool: &Pool,
+        client: &str,
+    ) -> Result<RenderPermit, AppError> {
+        let mut permit = {
+            let mut renders = limiter.renders.lock().unwrap();
+            let count = renders.entry(client.to_string()).or_insert(0);
+            if limiter.max_concurrent_renders > 0 && *count >= limiter.max_concurrent_renders {
+                return Err(AppError::RateLimited {
+                    message: format!(
+                        "Only {} concurrent renders are allowed!",
+                        limiter.max_concurrent_renders
+                    ),
+                    retry_after: RENDER_RETRY_AFTER,
+                });
+            }
+            *count += 1;
+            RenderPermit {
+                limiter: limiter.clone(),
+                client: client.to_string(),
+                reservation: None,
+            }
+        };
+
+        if limiter.daily_page_limit > 0 || limiter.daily_byte_limit > 0 {
+            let day = Utc::now().date_naive();
+            let reserved = reserve(
+                pool,
+                client,
+                day,
+                limiter.daily_page_limit,
+                limiter.daily_byte_limit,
+            )
+            .await
+            .map_err(AppError::internal)?;
+            if !reserved {
+                let today = get_daily_usage(pool, client)
+                    .await
+                    .map_err(AppError::internal)?;
+                let message =
+                    if limiter.daily_page_limit > 0 && today.pages >= limiter.daily_page_limit {
+                        format!("Daily limit of {} pages reached!", limiter.daily_page_limit)
+                    } else {
+                        format!("Daily limit of {} bytes reached!", limiter.daily_byte_limit)
+                    };
+                return Err(AppError::RateLimited {
+                    message,
+                    retry_after: seconds_until_tomorrow(),
+                });
+            }
+            permit.reservation = Some(Reservation {
+                pool: pool.clone(),
+                day,
+            });
+        }
+        Ok(permit)
+    }
+
+    pub async fn usage(
+        &self,
+        pool: &Pool,
+        client: &str,
+    ) -> Result<Usage, Box<dyn std::error::Error>> {
+        let today = get_daily_usage(pool, client).await?;
+        Ok(Usage {
+            client: client.to_string(),
+            requests_remaining: tokens_left(&self.keys, &client.to_string(), self.key_rate),
+            requests_per_minute: self.key_rate.per_minute,
+            concurrent_renders: self
+                .renders
+                .lock()
+                .unwrap()
+                .get(client)
+                .copied()
+                .unwrap_or_default(),
+            max_concurrent_renders: self.max_concurrent_renders,
+            daily_page_limit: self.daily_page_limit,
+            daily_byte_limit: self.daily_byte_limit,
+            today,
+        })
+    }
+}
+
+pub async fn init_schema(pool: &Pool) -> Result<(), Box<dyn std::error::Error>> {
+    let client = pool.get().await?;
+    client
+        .batch_execute(
+            "CREATE TABLE IF NOT EXISTS daily_usage (
+                client_id text NOT NULL,
+                day date NOT NULL,
+                renders bigint NOT NULL DEFAULT 0,
+                pages bigint NOT NULL DEFAULT 0,
+                bytes bigint NOT NULL DEFAULT 0,
+                PRIMARY KEY (client_id, day)
+            );",
+        )
+        .await?;
+    Ok(())
+}
+
+pub async fn get_daily_usage(
+    pool: &Pool,
+    client_id: &str,
+) -> Result<DailyUsage, Box<dyn std::error::Error>> {
+    let day = Utc::now().date_naive();
+    let client = pool.get().await?;
+    let row = client
+        .query_opt(
+            "SELECT renders, pages, bytes FROM daily_usage WHERE client_id = $1 AND day = $2",
+            &[&client_id, &day],
+        )
+        .await?;
+    Ok(match row {
+        Some(row) => DailyUsage {
+            day,
+            renders: row.get("renders"),
+            pages: row.get("pages"),
+            bytes: row.get("bytes"),
+        },
+        None => DailyUsage {
+            day,
+            renders: 0,
+            pages: 0,
+            bytes: 0,
+        },
+    })
+}
+
+// Books a render and its first page on the client's daily usage if the client is under its
+// limits, in one statement, so concurrent renders can't all pass the check. Running renders
+// count with one page each until they are recorded.
+async fn reserve(
+    pool: &Pool,
+    client_id: &str,
+    day: NaiveDate,
+    page_limit: i64,
+    byte_limit: i64,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let client = pool.get().await?;
+    let row = client
+        .query_opt(
+            "INSERT INTO daily_usage (client_id, day, renders, pages, bytes)
+             VALUES ($1, $2, 1, 1, 0)
+             ON CONFLICT (client_id, day) DO UPDATE SET
+                renders = daily_usage.renders + 1,
+                pages = daily_usage.pages + 1
+             WHERE ($3::bigint = 0 OR daily_usage.pages < $3)
+                AND ($4::bigint = 0 OR daily_usage.bytes < $4)
+             RETURNING pages",
+            &[&client_id, &day, &page_limit, &byte_limit],
+        )
+        .await?;
+    Ok(row.is_some())
+}
+
+// Gives back the reservation of a render that failed
+async fn release(
+    pool: &Pool,
+    client_id: &str,
+    day: NaiveDate,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let client = pool.get().await?;
+    client
+        .execute(
+            "UPDATE daily_usage SET renders = renders - 1, pages = pages - 1
+             WHERE client_id = $1 AND day = $2",
+            &[&client_id, &day],
+        )
+        .await?;
+    Ok(())
+}
+
+/// Middleware applying the token bucket limits. The per IP limit is wrapped around
+/// [`crate::utils::auth::ApiKeyAuth`], so failed authentication attempts are limited as well;
+/// the per key limit runs inside it, once the key of the request is known.
+#[derive(Clone, Copy)]
+pub enum RateLimit {
+    PerIp,
+    PerPrincipal,
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware {
+            service: Rc::new(service),
+            kind: *self,
+        }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<S>,
+    kind: RateLimit,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let kind = self.kind;
+
+        Box::pin(async move {
+            let limiter = match req.app_data::<web::Data<RateLimiter>>() {
+                Some(limiter) => limiter.clone(),
+                None => return Ok(service.call(req).await?.map_into_left_body()),
+            };
+
+            let limited = match kind {
+                RateLimit::PerIp => req
+                    .peer_addr()
+                    .and_then(|addr| take(&limiter.ips, addr.ip(), limiter.ip_rate).err()),
+                RateLimit::PerPrincipal => {
+                    let client = req.extensions().get::<Principal>().map(|p| p.id.clone());
+                    client.and_then(|client| take(&limiter.keys, client, limiter.key_rate).err())
+                }
+            };
+
+            match limited {
+                Some(retry_after) => Ok(req.into_response(
+                    AppError::RateLimited {
+                        message: String::from("Rate limit exceeded!"),
+                        retry_after,
+                    }
+                    .error_response()
+                    .map_into_right_body(),
+                )),
+                None => Ok(service.call(req).await?.map_into_left_body()),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::{header, StatusCode},
+        test::{self, TestRequest},
+        App, HttpResponse,
+    };
+
+    use super::*;
+    use crate::utils::setting::testing;
+
+    fn limiter(max_concurrent_renders: usize, daily_page_limit: i64) -> web::Data<RateLimiter> {
+        web::Data::new(RateLimiter {
+            key_rate: Rate {
+                per_minute: 0,
+                burst: 0,
+            },
+            ip_rate: Rate {
+                per_minute: 6,
+                burst: 2,
+            },
+            max_concurrent_renders,
+            daily_page_limit,
+            daily_byte_limit: 0,
+            keys: Mutex::new(HashMap::new()),
+            ips: Mutex::new(HashMap::new()),
+            renders: Mutex::new(HashMap::new()),
+        })
+    }
+
+    #[test]
+    fn buckets_allow_bursts_and_refill() {
+        let buckets = Mutex::new(HashMap::new());
+        let rate = Rate {
+            per_minute: 6,
+            burst: 2,
+        };
+        assert_eq!(take(&buckets, "a", rate), Ok(()));
+        assert_eq!(take(&buckets, "a", rate), Ok(()));
+        // One token every 10 seconds
+        assert_eq!(take(&buckets, "a", rate), Err(10));
+        assert_eq!(take(&buckets, "b", rate), Ok(()));
+        assert_eq!(tokens_left(&buckets, &"a", rate), 0);
+        assert_eq!(tokens_left(&buckets, &"b", rate), 1);
+
+        let fast = Rate {
+            per_minute: 6000,
+            burst: 1,
+        };
+        assert_eq!(take(&buckets, "c", fast), Ok(()));
+        assert_eq!(take(&buckets, "c", fast), Err(1));
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        assert_eq!(take(&buckets, "c", fast), Ok(()));
+
+        let unlimited = Rate {
+            per_minute: 0,
+            burst: 0,
+        };
+        assert!((0..100).all(|_| take(&buckets, "d", unlimited).is_ok()));
+    }
+
+    #[actix_web::test]
+    async fn limited_requests_get_retry_after() {
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimit::PerIp)
+                .app_data(limiter(0, 0))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let request = |ip: &str| {
+            TestRequest::get()
+                .peer_addr(format!("{ip}:1234").parse().unwrap())
+                .to_request()
+        };
+        for _ in 0..2 {
+            let response = test::call_service(&app, request("10.0.0.1")).await;
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let response = test::call_service(&app, request("10.0.0.1")).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers().get(header::RETRY_AFTER).unwrap(), "10");
+        let response = test::call_service(&app, request("10.0.0.2")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn limits_concurrent_renders() {
+        testing::config();
+        let limiter = limiter(1, 0);
+        // Without daily limits the database isn't used
+        let pool = crate::utils::setting::create_postgres_pool("host=/nonexistent").unwrap();
+
+        let permit = RateLimiter::start_render(&limiter, &pool, "a")
+            .await
+            .unwrap();
+        let rejected = RateLimiter::start_render(&limiter, &pool, "a").await;
+        assert!(matches!(
+            rejected,
+            Err(AppError::RateLimited {
+                retry_after: RENDER_RETRY_AFTER,
+                ..
+            })
+        ));
+        let other = RateLimiter::start_render(&limiter, &pool, "b").await;
+        assert!(other.is_ok());
+        drop(permit);
+        assert!(RateLimiter::start_render(&limiter, &pool, "a")
+            .await
+            .is_ok());
+    }
+
+    #[actix_web::test]
+    async fn reserves_daily_pages_atomically() {
+        let Some(pool) = testing::pool() else {
+            return;
+        };
+        init_schema(&pool).await.unwrap();
+        let client = format!("rate-limit-test-{}", uuid::Uuid::new_v4());
+        let limiter = limiter(0, 3);
+
+        let started = futures::future::join_all(
+            (0..8).map(|_| RateLimiter::start_render(&limiter, &pool, &client)),
+        )
+        .await;
+        let (mut permits, rejected): (Vec<_>, Vec<_>) =
+            started.into_iter().partition(Result::is_ok);
+        assert_eq!(permits.len(), 3);
+        for err in rejected {
+            let Err(AppError::RateLimited {
+                message,
+                retry_after,
+            }) = err
+            else {
+                panic!("expected a daily limit");
+            };
+            assert_eq!(message, "Daily limit of 3 pages reached!");
+            assert!(retry_after > 0 && retry_after <= 86_400);
+        }
+
+        // A failed render gives its reservation back, a recorded one keeps its pages
+        permits.pop();
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        assert_eq!(get_daily_usage(&pool, &client).await.unwrap().pages, 2);
+        let image = std::env::temp_dir().join(format!("{client}.png"));
+        std::fs::write(&image, b"png").unwrap();
+        let mut permit = permits.pop().unwrap().unwrap();
+        permit.record(&pool, image.to_str().unwrap()).await.unwrap();
+        drop(permit);
+        std::fs::remove_file(&image).unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        let usage = get_daily_usage(&pool, &client).await.unwrap();
+        assert_eq!((usage.renders, usage.pages, usage.bytes), (2, 2, 3));
+        assert!(RateLimiter::start_render(&limiter, &pool, &client)
+            .await
+            .is_ok());
+    }
+
+    #[test]
+    fn counts_pages_across_chunks() {
+        let pdf = b"<< /Type /Pages /Count 3 >> << /Type /Page >> <</Type/Page/Parent 1>> \
+                    << /Type\n/Page >>";
+        for chunk_size in [1, 7, 40, 4096] {
+            assert_eq!(count_page_objects(&pdf[..], chunk_size).unwrap(), 3);
+        }
+        assert_eq!(count_page_objects(&b"/Type /Pages"[..], 4).unwrap(), 0);
+    }
+}
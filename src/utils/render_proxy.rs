@@ -0,0 +1,299 @@
+use std::{io, process::Command, sync::Arc};
+
+use tokio::{
+    io::{copy_bidirectional, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task::{JoinHandle, JoinSet},
+};
+use tracing::{debug, warn, Instrument};
+use url::Url;
+
+use crate::utils::url_policy::UrlPolicy;
+
+// Larger request heads are rejected
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+
+/// A forward proxy the renderer is pointed at for a single render. Every request the
+/// browser makes, including redirects and subresources, goes through it and is checked
+/// against the `UrlPolicy` before a connection is opened. The proxy stops when dropped.
+pub struct RenderProxy {
+    port: u16,
+    task: JoinHandle<()>,
+}
+
+impl Drop for RenderProxy {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// A page the proxy answers itself from a local file, e.g. the processed template of a
+/// report. It isn't served anywhere else, so only the render it belongs to can load it.
+pub struct RenderPage {
+    pub url: String,
+    pub path: String,
+}
+
+impl RenderProxy {
+    /// Starts the proxy, answering requests for `page` from its file.
+    pub async fn start(policy: Arc<UrlPolicy>, page: Option<RenderPage>) -> io::Result<Self> {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await?;
+        let port = listener.local_addr()?.port();
+        let page = Arc::new(page);
+
+        let task = tokio::spawn(
+            async move {
+                // Connections are aborted together with the proxy
+                let mut connections = JoinSet::new();
+                loop {
+                    tokio::select! {
+                        accepted = listener.accept() => {
+                            let Ok((client, _)) = accepted else {
+                                continue;
+                            };
+                            let policy = policy.clone();
+                            let page = page.clone();
+                            connections.spawn(
+                                async move {
+                                    if let Err(err) =
+                                        handle(client, &policy, page.as_ref().as_ref()).await
+                                    {
+                                        debug!("Renderer connection failed: {:?}", err);
+                                    }
+                                }
+                                .in_current_span(),
+                            );
+                        }
+                        Some(_) = connections.join_next() => {}
+                    }
+                }
+            }
+            .in_current_span(),
+        );
+
+        Ok(RenderProxy { port, task })
+    }
+
+    /// Points the renderer's browser at the proxy.
+    pub fn apply(&self, command: &mut Command) {
+        let proxy = format!("http://127.0.0.1:{}", self.port);
+        for key in ["http_proxy", "https_proxy", "HTTP_PROXY", "HTTPS_PROXY"] {
+            command.env(key, &proxy);
+        }
+        // Chromium bypasses proxies for loopback addresses unless told otherwise
+        command.env("no_proxy", "<-loopback>");
+        command.env("NO_PROXY", "<-loopback>");
+    }
+}
+
+async fn deny(client: &mut TcpStream, message: &str) -> io::Result<()> {
+    warn!("Blocked renderer request: {}", message);
+    let response = format!(
+        "HTTP/1.1 403 Forbidden\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        message.len(),
+        message
+    );
+    client.write_all(response.as_bytes()).await
+}
+
+async fn serve_page(client: &mut TcpStream, method: &str, page: &RenderPage) -> io::Result<()> {
+    let contents = match tokio::fs::read(&page.path).await {
+        Ok(contents) => contents,
+        Err(err) => {
+            warn!("Render page could not be read: {}", err);
+            let response =
+                "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+            return client.write_all(response.as_bytes()).await;
+        }
+    };
+    let head = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+        contents.len()
+    );
+    client.write_all(head.as_bytes()).await?;
+    if method != "HEAD" {
+        client.write_all(&contents).await?;
+    }
+    client.flush().await
+}
+
+async fn connect(addrs: &[std::net::SocketAddr]) -> io::Result<TcpStream> {
+    let mut last_err = io::Error::new(io::ErrorKind::NotFound, "no address");
+    for addr in addrs {
+        match TcpStream::connect(addr).await {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = err,
+        }
+    }
+    Err(last_err)
+}
+
+async fn handle(
+    mut client: TcpStream,
+    policy: &UrlPolicy,
+    page: Option<&RenderPage>,
+) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(4096);
+    let head_len = loop {
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        if buf.len() > MAX_HEAD_SIZE {
+            return deny(&mut client, "Request head is too large").await;
+        }
+        let mut chunk = [0; 4096];
+        let read = client.read(&mut chunk).await?;
+        if read == 0 {
+            return Ok(());
+        }
+        buf.extend_from_slice(&chunk[..read]);
+    };
+
+    let head = String::from_utf8_lossy(&buf[..head_len]).to_string();
+    let mut lines = head.split("\r\n");
+    let request_line = lines.next().unwrap_or_default();
+    let mut parts = request_line.split(' ');
+    let (Some(method), Some(target), Some(version)) = (parts.next(), parts.next(), parts.next())
+    else {
+        return deny(&mut client, "Malformed request").await;
+    };
+
+    // HTTPS and websockets are tunnelled, only the host and port are visible
+    if method == "CONNECT" {
+        let Some((host, port)) = target.rsplit_once(':') else {
+            return deny(&mut client, "Malformed CONNECT target").await;
+        };
+        let Ok(port) = port.parse() else {
+            return deny(&mut client, "Malformed CONNECT target").await;
+        };
+        let addrs = match policy.resolve("https", host, port).await {
+            Ok(addrs) => addrs,
+            Err(message) => return deny(&mut client, &message).await,
+        };
+        let mut upstream = connect(&addrs).await?;
+        client
+            .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+            .await?;
+        upstream.write_all(&buf[head_len..]).await?;
+        copy_bidirectional(&mut client, &mut upstream).await?;
+        return Ok(());
+    }
+
+    let Ok(url) = Url::parse(target) else {
+        return deny(&mut client, "Malformed request target").await;
+    };
+    if url.host_str().is_none() {
+        return deny(&mut client, "Malformed request target").await;
+    }
+    if url.scheme() != "http" {
+        return deny(
+            &mut client,
+            &format!("Scheme {} is not allowed!", url.scheme()),
+        )
+        .await;
+    }
+
+    if let Some(page) = page.filter(|page| page.url == url.as_str()) {
+        if !matches!(method, "GET" | "HEAD") {
+            return deny(
+                &mut client,
+                "Only GET and HEAD are allowed for the render page",
+            )
+            .await;
+        }
+        return serve_page(&mut client, method, page).await;
+    }
+
+    let addrs = match policy.resolve_url(&url).await {
+        Ok(addrs) => addrs,
+        Err(message) => return deny(&mut client, &message).await,
+    };
+    let mut upstream = connect(&addrs).await?;
+
+    // Forward the request in origin form; the connection is closed after one response so
+    // a reused connection can't carry a request for another host
+    let path = &url[url::Position::BeforePath..url::Position::AfterQuery];
+    let mut request = format!("{} {} {}\r\n", method, path, version);
+    for line in lines.filter(|line| !line.is_empty()) {
+        let name = line.split(':').next().unwrap_or_default().to_lowercase();
+        if !matches!(
+            name.as_str(),
+            "proxy-connection" | "proxy-authorization" | "connection" | "keep-alive"
+        ) {
+            request.push_str(line);
+            request.push_str("\r\n");
+        }
+    }
+    request.push_str("Connection: close\r\n\r\n");
+    upstream.write_all(request.as_bytes()).await?;
+    upstream.write_all(&buf[head_len..]).await?;
+    copy_bidirectional(&mut client, &mut upstream).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::setting::UrlPolicyConfig;
+
+    // Fetches `url` with curl configured only through `RenderProxy::apply`, the way the
+    // renderer is, returning the status code and the body. This proves the proxy's checks,
+    // not that sitetopdf and its browser honor the proxy variables: that needs the real
+    // renderer, and is what the test render of `/readyz` checks, as its page is only served
+    // by the proxy.
+    async fn fetch(proxy: &RenderProxy, url: &str) -> (String, String) {
+        let mut command = Command::new("curl");
+        command.args(["-s", "-w", "\n%{http_code}", url]);
+        proxy.apply(&mut command);
+        let output = tokio::process::Command::from(command)
+            .output()
+            .await
+            .unwrap();
+        let output = String::from_utf8(output.stdout).unwrap();
+        let (body, status) = output.rsplit_once('\n').unwrap();
+        (status.to_string(), body.to_string())
+    }
+
+    #[actix_web::test]
+    async fn routes_the_renderer_through_the_policy() {
+        // A tiny server on a loopback address, standing in for this server and any
+        // internal service the page might try to reach
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let internal = format!("http://127.0.0.1:{}", listener.local_addr().unwrap().port());
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = [0; 4096];
+                let _ = stream.read(&mut buf).await;
+                let _ = stream
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nimage")
+                    .await;
+            }
+        });
+
+        let dir = std::env::temp_dir().join(format!("render-proxy-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("page.html");
+        std::fs::write(&path, "<p>report</p>").unwrap();
+
+        let policy = Arc::new(UrlPolicy::new(&UrlPolicyConfig::default(), &internal));
+        // The page only exists in the proxy, so a client ignoring it can't load it
+        let page = RenderPage {
+            url: String::from("http://render.invalid/page.html"),
+            path: path.to_string_lossy().to_string(),
+        };
+        let proxy = RenderProxy::start(policy, Some(page)).await.unwrap();
+
+        let (status, body) = fetch(&proxy, "http://render.invalid/page.html").await;
+        assert_eq!((status.as_str(), body.as_str()), ("200", "<p>report</p>"));
+
+        let (status, body) = fetch(&proxy, &format!("{internal}/images/logo.png")).await;
+        assert_eq!((status.as_str(), body.as_str()), ("200", "image"));
+
+        let (status, _) = fetch(&proxy, &format!("{internal}/api/template")).await;
+        assert_eq!(status, "403");
+        let (status, _) = fetch(&proxy, "http://[64:ff9b::7f00:1]/").await;
+        assert_eq!(status, "403");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,408 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    os::unix::process::CommandExt,
+    process::{self, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    process::Command,
+    sync::Notify,
+    task::JoinHandle,
+    time::timeout,
+};
+use tracing::{debug, error, info, warn};
+
+use crate::utils::{error::AppError, setting::config};
+
+// Most of stderr is the browser's noise, the error is at the end
+const STDERR_TAIL_BYTES: usize = 4096;
+
+/// Tracks the running renderer processes so they can be drained on shutdown. Every render
+/// runs in its own process group, so the browser processes it starts are killed with it.
+#[derive(Default)]
+pub struct RenderSupervisor {
+    draining: AtomicBool,
+    // Process group id -> output file of every running render
+    running: Mutex<HashMap<i32, String>>,
+    idle: Notify,
+}
+
+// Deregisters a render when it ends, however it ends
+struct RunningRender<'a> {
+    supervisor: &'a RenderSupervisor,
+    pgid: i32,
+    output_path: &'a str,
+    succeeded: bool,
+}
+
+impl Drop for RunningRender<'_> {
+    fn drop(&mut self) {
+        // Stray browser processes are left in the group even when the renderer exited
+        kill_group(self.pgid);
+        if !self.succeeded {
+            remove_output(self.output_path);
+        }
+        let mut running = self.supervisor.running.lock().unwrap();
+        running.remove(&self.pgid);
+        if running.is_empty() {
+            self.supervisor.idle.notify_waiters();
+        }
+    }
+}
+
+fn kill_group(pgid: i32) {
+    // kill(0) and kill(-1) would signal the server's own group or every process it may signal
+    if pgid <= 1 {
+        return;
+    }
+    // SAFETY: kill has no memory safety requirements; a negative pid addresses the group
+    unsafe {
+        libc::kill(-pgid, libc::SIGKILL);
+    }
+}
+
+// Applied in the forked child before the renderer starts. Resource limits are inherited by
+// every process the renderer starts but counted for each one separately, so they bound a
+// runaway browser process, not the render as a whole.
+#[derive(Clone, Copy)]
+struct ResourceLimits {
+    cpu_secs: u64,
+    memory_bytes: u64,
+}
+
+impl ResourceLimits {
+    fn apply(&self) -> io::Result<()> {
+        // The hard CPU limit is a little higher, so SIGXCPU comes before SIGKILL
+        if self.cpu_secs > 0 {
+            set_limit(libc::RLIMIT_CPU, self.cpu_secs, self.cpu_secs + 5)?;
+        }
+        // Not RLIMIT_AS: Chromium reserves terabytes of address space for its sandbox and
+        // V8 heaps up front and fails to start under any practical address space limit.
+        // RLIMIT_DATA only counts the writable memory it actually maps
+        if self.memory_bytes > 0 {
+            set_limit(libc::RLIMIT_DATA, self.memory_bytes, self.memory_bytes)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+type Resource = libc::__rlimit_resource_t;
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+type Resource = libc::c_int;
+
+fn set_limit(resource: Resource, soft: u64, hard: u64) -> io::Result<()> {
+    let limit = libc::rlimit {
+        rlim_cur: soft as libc::rlim_t,
+        rlim_max: hard as libc::rlim_t,
+    };
+    // SAFETY: `limit` is a valid rlimit for the duration of the call
+    if unsafe { libc::setrlimit(resource, &limit) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+// The renderer leads its own process group, so its pid is the group id. Tokio forgets the pid
+// once the child was reaped, and a group id of 0 would address the server's own group.
+fn process_group(child: &tokio::process::Child) -> Result<i32, AppError> {
+    match child.id().and_then(|pid| i32::try_from(pid).ok()) {
+        Some(pgid) if pgid > 1 => Ok(pgid),
+        _ => {
+            error!("sitetopdf exited before its process group could be tracked");
+            Err(AppError::Render {
+                message: String::from("Error executing sitetopdf command"),
+                stderr: None,
+            })
+        }
+    }
+}
+
+fn read_pipe(pipe: Option<impl AsyncRead + Unpin + Send + 'static>) -> JoinHandle<Vec<u8>> {
+    tokio::spawn(async move {
+        let mut output = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut output).await;
+        }
+        output
+    })
+}
+
+// Keeps the end of stderr, where the renderer reports what went wrong
+fn stderr_tail(stderr: &[u8]) -> Option<String> {
+    let stderr = String::from_utf8_lossy(stderr);
+    let stderr = stderr.trim();
+    if stderr.is_empty() {
+        return None;
+    }
+    let mut start = stderr.len().saturating_sub(STDERR_TAIL_BYTES);
+    while !stderr.is_char_boundary(start) {
+        start += 1;
+    }
+    Some(stderr[start..].to_string())
+}
+
+fn remove_output(output_path: &str) {
+    match fs::remove_file(output_path) {
+        Ok(()) => debug!(output = output_path, "Removed partial output"),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => warn!(output = output_path, "Error deleting file: {}", err),
+    }
+}
+
+impl RenderSupervisor {
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Runs the renderer until it exits or `renderer.timeout` passes. If it fails, times out
+    /// or the request is cancelled, its process group is killed and the partial file at
+    /// `output_path` is removed.
+    pub async fn run(&self, command: process::Command, output_path: &str) -> Result<(), AppError> {
+        let settings = &config().renderer;
+        let limits = ResourceLimits {
+            cpu_secs: settings.max_cpu_secs,
+            memory_bytes: settings.max_memory_mb * 1024 * 1024,
+        };
+        self.run_limited(command, output_path, settings.timeout, limits)
+            .await
+    }
+
+    async fn run_limited(
+        &self,
+        mut command: process::Command,
+        output_path: &str,
+        timeout_secs: u64,
+        limits: ResourceLimits,
+    ) -> Result<(), AppError> {
+        if self.is_draining() {
+            return Err(AppError::ShuttingDown(String::from(
+                "Server is shutting down, please retry",
+            )));
+        }
+
+        command
+            .process_group(0)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        // SAFETY: the hook only calls setrlimit, which is async-signal-safe
+        unsafe {
+            command.pre_exec(move || limits.apply());
+        }
+        let mut command = Command::from(command);
+        command.kill_on_drop(true);
+
+        let spawn_error = |err: io::Error| {
+            error!("Error executing sitetopdf command: {:?}", err);
+            AppError::Render {
+                message: String::from("Error executing sitetopdf command"),
+                stderr: None,
+            }
+        };
+        let mut child = command.spawn().map_err(spawn_error)?;
+        let pgid = process_group(&child)?;
+        self.running
+            .lock()
+            .unwrap()
+            .insert(pgid, output_path.to_string());
+        let mut render = RunningRender {
+            supervisor: self,
+            pgid,
+            output_path,
+            succeeded: false,
+        };
+
+        // The pipes are drained while the renderer runs, so it can't block on a full pipe
+        let stdout = read_pipe(child.stdout.take());
+        let stderr = read_pipe(child.stderr.take());
+        let waited = timeout(Duration::from_secs(timeout_secs), child.wait()).await;
+        // Browser processes keep the pipes open, so the group goes before they are read
+        kill_group(pgid);
+        let stdout = stdout.await.unwrap_or_default();
+        let stderr = stderr_tail(&stderr.await.unwrap_or_default());
+
+        // The renderer's output can quote the page, so it is only logged when debugging
+        debug!(stdout = %String::from_utf8_lossy(&stdout), "sitetopdf finished");
+        let status = match waited {
+            Ok(status) => status.map_err(spawn_error)?,
+            Err(_) => {
+                warn!(
+                    timeout = timeout_secs,
+                    stderr = stderr.as_deref().unwrap_or_default(),
+                    "sitetopdf timed out"
+                );
+                return Err(AppError::RenderTimeout {
+                    timeout: timeout_secs,
+                    stderr,
+                });
+            }
+        };
+        if !status.success() {
+            warn!(
+                status = %status,
+                stderr = stderr.as_deref().unwrap_or_default(),
+                "sitetopdf failed"
+            );
+            if self.is_draining() {
+                return Err(AppError::ShuttingDown(String::from(
+                    "Render was cancelled because the server is shutting down",
+                )));
+            }
+            return Err(AppError::Render {
+                message: format!("Failed to run sitetopdf command ({})", status),
+                stderr,
+            });
+        }
+        render.succeeded = true;
+        Ok(())
+    }
+
+    /// Rejects new renders and waits for the running ones. Renders still running after
+    /// `deadline` are killed and their partial output is removed.
+    pub async fn drain(&self, deadline: Duration) {
+        self.draining.store(true, Ordering::SeqCst);
+        let drained = timeout(deadline, async {
+            loop {
+                let idle = self.idle.notified();
+                let running = self.running.lock().unwrap().len();
+                if running == 0 {
+                    return;
+                }
+                info!(running, "Waiting for running renders");
+                idle.await;
+            }
+        })
+        .await;
+        if drained.is_err() {
+            self.kill_all();
+        }
+    }
+
+    /// Kills every running render and removes its partial output.
+    pub fn kill_all(&self) {
+        let running = std::mem::take(&mut *self.running.lock().unwrap());
+        for (pgid, output_path) in running {
+            warn!(output = %output_path, "Killing unfinished render");
+            kill_group(pgid);
+            remove_output(&output_path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NO_LIMITS: ResourceLimits = ResourceLimits {
+        cpu_secs: 0,
+        memory_bytes: 0,
+    };
+
+    // A render that starts a background process, like a browser does
+    fn render(output: &str) -> process::Command {
+        fs::write(output, "partial").unwrap();
+        let mut command = process::Command::new("sh");
+        command.args(["-c", "sleep 30 & sleep 30"]);
+        command
+    }
+
+    fn output_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("supervisor-{}-{name}", process::id()));
+        path.to_string_lossy().to_string()
+    }
+
+    // Whether any process of the group is still running; zombies don't count since an
+    // orphaned process may only be reaped by init later
+    fn group_alive(pgid: i32) -> bool {
+        fs::read_dir("/proc").unwrap().flatten().any(|entry| {
+            let Ok(stat) = fs::read_to_string(entry.path().join("stat")) else {
+                return false;
+            };
+            // The fields after the parenthesised command: state, ppid, pgrp
+            let Some((_, fields)) = stat.rsplit_once(") ") else {
+                return false;
+            };
+            let fields: Vec<&str> = fields.split(' ').collect();
+            fields[0] != "Z" && fields[2] == pgid.to_string()
+        })
+    }
+
+    fn running_group(supervisor: &RenderSupervisor) -> i32 {
+        *supervisor.running.lock().unwrap().keys().next().unwrap()
+    }
+
+    #[actix_web::test]
+    async fn rejects_children_without_a_pid() {
+        let mut child = Command::new("true").spawn().unwrap();
+        assert!(process_group(&child).is_ok_and(|pgid| pgid > 1));
+
+        // Once reaped the child has no pid, which must not turn into group 0
+        child.wait().await.unwrap();
+        assert!(matches!(
+            process_group(&child),
+            Err(AppError::Render { .. })
+        ));
+        kill_group(0);
+    }
+
+    #[actix_web::test]
+    async fn drain_kills_unfinished_renders() {
+        let supervisor = RenderSupervisor::default();
+        let output = output_path("drain");
+        let command = render(&output);
+
+        let (rendered, pgid) = tokio::join!(
+            supervisor.run_limited(command, &output, 60, NO_LIMITS),
+            async {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                let pgid = running_group(&supervisor);
+                assert!(group_alive(pgid));
+                supervisor.drain(Duration::from_millis(100)).await;
+                pgid
+            }
+        );
+
+        assert!(matches!(rendered, Err(AppError::ShuttingDown(_))));
+        assert!(!group_alive(pgid));
+        assert!(!std::path::Path::new(&output).exists());
+        assert!(supervisor.running.lock().unwrap().is_empty());
+        // Nothing new starts once draining
+        let rejected = supervisor
+            .run_limited(render(&output), &output, 60, NO_LIMITS)
+            .await;
+        assert!(matches!(rejected, Err(AppError::ShuttingDown(_))));
+        let _ = fs::remove_file(&output);
+    }
+
+    #[actix_web::test]
+    async fn times_out_renders() {
+        let supervisor = RenderSupervisor::default();
+        let output = output_path("timeout");
+        let command = render(&output);
+
+        let (rendered, pgid) = tokio::join!(
+            supervisor.run_limited(command, &output, 1, NO_LIMITS),
+            async {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                running_group(&supervisor)
+            }
+        );
+
+        assert!(matches!(
+            rendered,
+            Err(AppError::RenderTimeout { timeout: 1, .. })
+        ));
+        assert!(!group_alive(pgid));
+        assert!(!std::path::Path::new(&output).exists());
+        assert!(supervisor.running.lock().unwrap().is_empty());
+    }
+}
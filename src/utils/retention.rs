@@ -0,0 +1,109 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use tracing::{debug, info, warn};
+
+use crate::utils::{
+    setting::{PathsConfig, RetentionConfig},
+    storage::Storage,
+};
+
+/// Periodically deletes generated reports and leftover temp files older than the configured
+/// retention. Tenant sub directories are swept as well; directories themselves are kept.
+pub fn spawn(storage: Arc<dyn Storage>, paths: &PathsConfig, retention: &RetentionConfig) {
+    let reports_age = Duration::from_secs(retention.reports_hours * 3600);
+    let temp_age = Duration::from_secs(retention.temp_minutes * 60);
+    let temp_dir = PathBuf::from(&paths.temp);
+    if reports_age.is_zero() && temp_age.is_zero() {
+        return;
+    }
+
+    let interval = Duration::from_secs(retention.sweep_interval_minutes * 60);
+    actix_web::rt::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let mut removed = 0;
+            if !reports_age.is_zero() {
+                removed += sweep_reports(&*storage, reports_age).await;
+            }
+            if !temp_age.is_zero() {
+                let temp_dir = temp_dir.clone();
+                removed += actix_web::rt::task::spawn_blocking(move || sweep(&temp_dir, temp_age))
+                    .await
+                    .unwrap_or_default();
+            }
+            if removed > 0 {
+                info!(removed, "Removed expired files");
+            }
+        }
+    });
+}
+
+// Reports go through the storage backend, so the retention holds for S3 as well
+async fn sweep_reports(storage: &dyn Storage, max_age: Duration) -> usize {
+    let objects = match storage.list("reports/").await {
+        Ok(objects) => objects,
+        Err(err) => {
+            warn!("Failed to list reports: {}", err);
+            return 0;
+        }
+    };
+    let now = SystemTime::now();
+    let mut removed = 0;
+    for object in objects {
+        let expired = object
+            .last_modified
+            .and_then(|modified| now.duration_since(modified).ok())
+            .is_some_and(|age| age > max_age);
+        if !expired {
+            continue;
+        }
+        match storage.delete(&object.key).await {
+            Ok(()) => {
+                debug!(key = %object.key, "Removed expired report");
+                removed += 1;
+            }
+            Err(err) => warn!(key = %object.key, "Failed to remove expired report: {}", err),
+        }
+    }
+    removed
+}
+
+fn sweep(dir: &Path, max_age: Duration) -> usize {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return 0;
+    };
+    let now = SystemTime::now();
+    let mut removed = 0;
+    for entry in entries.flatten() {
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        let path = entry.path();
+        if metadata.is_dir() {
+            removed += sweep(&path, max_age);
+            continue;
+        }
+        let expired = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| now.duration_since(modified).ok())
+            .is_some_and(|age| age > max_age);
+        if !expired {
+            continue;
+        }
+        match fs::remove_file(&path) {
+            Ok(()) => {
+                debug!(path = %path.display(), "Removed expired file");
+                removed += 1;
+            }
+            Err(err) => warn!(path = %path.display(), "Failed to remove expired file: {}", err),
+        }
+    }
+    removed
+}
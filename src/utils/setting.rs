@@ -1,25 +1,1085 @@
-use std::{env, sync::Arc};
+use std::{
+    collections::BTreeMap, env, fmt, fs, net::IpAddr, path::Path, str::FromStr, sync::OnceLock,
+};
 
-use tokio_postgres::{Client, NoTls};
+use actix_web::http::Method;
+use deadpool_postgres::{Manager, ManagerConfig, Pool, RecyclingMethod};
+use serde::Deserialize;
+use tokio_postgres::NoTls;
+use tracing_subscriber::EnvFilter;
+use url::Url;
 
-pub fn get_port() -> u16 {
-    let port: u16 = env::var("PORT")
-        .unwrap_or(String::from("8080"))
-        .parse()
-        .expect("Port must be number");
-    port
+use crate::utils::{
+    html_parser::TemplateMode,
+    image::{is_valid_variant_name, ImageVariant, OutputFormat, ResizeMode},
+};
+
+// Read when `CONFIG_FILE` is not set and the file exists
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// Service configuration. Values come from the defaults below, then the file named by
+/// `CONFIG_FILE` (TOML or YAML, `config.toml` if present), then environment variables.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub paths: PathsConfig,
+    pub storage: StorageConfig,
+    pub database: DatabaseConfig,
+    pub auth: AuthConfig,
+    pub limits: LimitsConfig,
+    pub rate_limit: RateLimitConfig,
+    pub query_cache: QueryCacheConfig,
+    pub report_urls: ReportUrlsConfig,
+    pub url_policy: UrlPolicyConfig,
+    pub cors: CorsSettings,
+    pub templates: TemplatesConfig,
+    pub images: ImagesConfig,
+    pub renderer: RendererConfig,
+    pub retention: RetentionConfig,
+    pub logging: LoggingConfig,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: String,
+    pub port: u16,
+    // Seconds running renders get to finish after SIGTERM before they are killed
+    pub shutdown_timeout: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind: String::from("0.0.0.0"),
+            port: 8080,
+            shutdown_timeout: 30,
+        }
+    }
+}
+
+/// Storage roots. Templates, reports, images and datasets get a sub directory per tenant.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PathsConfig {
+    pub temp: String,
+    pub templates: String,
+    pub reports: String,
+    pub images: String,
+    pub datasets: String,
+}
+
+impl Default for PathsConfig {
+    fn default() -> Self {
+        PathsConfig {
+            temp: String::from("./temp"),
+            templates: String::from("./templates"),
+            reports: String::from("./reports"),
+            images: String::from("./images"),
+            datasets: String::from("./datasets"),
+        }
+    }
+}
+
+impl PathsConfig {
+    /// Every storage root with the name it is reported under.
+    pub fn all(&self) -> [(&'static str, &str); 5] {
+        [
+            ("temp", &self.temp),
+            ("templates", &self.templates),
+            ("reports", &self.reports),
+            ("images", &self.images),
+            ("datasets", &self.datasets),
+        ]
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    #[default]
+    Local,
+    S3,
+}
+
+impl FromStr for StorageBackend {
+    type Err = &'static str;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "local" => Ok(StorageBackend::Local),
+            "s3" => Ok(StorageBackend::S3),
+            _ => Err("must be local or s3"),
+        }
+    }
+}
+
+/// Where templates, images and reports are kept. Temp files and datasets are always local.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+    // Hand out presigned object URLs instead of URLs served by this service
+    pub presigned_urls: bool,
+    pub s3: S3Config,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct S3Config {
+    pub bucket: String,
+    pub region: String,
+    // For S3 compatible services such as MinIO
+    pub endpoint: Option<String>,
+    pub access_key_id: Option<String>,
+    pub secret_access_key: Option<String>,
+    // Prepended to every key, so a bucket can be shared
+    pub prefix: String,
+    pub force_path_style: bool,
+}
+
+impl Default for S3Config {
+    fn default() -> Self {
+        S3Config {
+            bucket: String::new(),
+            region: String::from("us-east-1"),
+            endpoint: None,
+            access_key_id: None,
+            secret_access_key: None,
+            prefix: String::new(),
+            force_path_style: false,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub connection: Option<String>,
+    // Used by `{{#sql}}` blocks of tenants without a database of their own. Its role must
+    // not be able to access the service tables.
+    pub template_connection: Option<String>,
+    pub pool_size: usize,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            connection: None,
+            template_connection: None,
+            pool_size: 8,
+        }
+    }
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    // Bootstraps the first admin key
+    pub admin_api_key: Option<String>,
+    // Enables HS256 JWT bearer tokens
+    pub jwt_secret: Option<String>,
+    pub url_signing_secret: Option<String>,
+    // How long the client (by IP address) that redeemed a single use URL may fetch it again,
+    // e.g. to resume a download; 0 allows one download only
+    pub single_use_grace_secs: u64,
+}
+
+impl AuthConfig {
+    pub fn url_signing_secret(&self) -> String {
+        match &self.url_signing_secret {
+            Some(secret) => secret.clone(),
+            None => {
+                // Signed URLs stop working after a restart and can't be shared between replicas
+                tracing::warn!("auth.url_signing_secret is not set, using a random secret");
+                format!(
+                    "{}{}",
+                    uuid::Uuid::new_v4().simple(),
+                    uuid::Uuid::new_v4().simple()
+                )
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    // Largest accepted JSON body
+    pub max_request_bytes: usize,
+    // Largest accepted uploads
+    pub max_template_bytes: u64,
+    pub max_image_bytes: u64,
+    pub max_dataset_bytes: u64,
+    pub max_concurrent_renders: usize,
+    // 0 disables the daily limits
+    pub daily_page_limit: i64,
+    pub daily_byte_limit: i64,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            max_request_bytes: 2 * 1024 * 1024,
+            max_template_bytes: 1024 * 1024,
+            max_image_bytes: 10 * 1024 * 1024,
+            max_dataset_bytes: 20 * 1024 * 1024,
+            max_concurrent_renders: 2,
+            daily_page_limit: 0,
+            daily_byte_limit: 0,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub key_per_minute: u32,
+    pub key_burst: u32,
+    pub ip_per_minute: u32,
+    pub ip_burst: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            key_per_minute: 120,
+            key_burst: 30,
+            ip_per_minute: 300,
+            ip_burst: 60,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueryCacheConfig {
+    pub max_entries: usize,
+    pub max_bytes: usize,
+}
+
+impl Default for QueryCacheConfig {
+    fn default() -> Self {
+        QueryCacheConfig {
+            max_entries: 1000,
+            max_bytes: 64 * 1024 * 1024,
+        }
+    }
+}
+
+/// Lifetime of signed report download URLs in seconds.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReportUrlsConfig {
+    pub ttl: u64,
+    pub max_ttl: u64,
+}
+
+impl Default for ReportUrlsConfig {
+    fn default() -> Self {
+        ReportUrlsConfig {
+            ttl: 3600,
+            max_ttl: 604800,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UrlPolicyConfig {
+    pub allowed_schemes: Vec<String>,
+    pub allowed_hosts: Vec<String>,
+    pub denied_hosts: Vec<String>,
+    pub allow_private_networks: bool,
+}
+
+impl Default for UrlPolicyConfig {
+    fn default() -> Self {
+        UrlPolicyConfig {
+            allowed_schemes: vec![String::from("http"), String::from("https")],
+            allowed_hosts: Vec::new(),
+            denied_hosts: Vec::new(),
+            allow_private_networks: false,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsSettings {
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    pub allow_credentials: bool,
+    pub max_age: usize,
+}
+
+impl Default for CorsSettings {
+    fn default() -> Self {
+        CorsSettings {
+            allowed_origins: Vec::new(),
+            allowed_methods: vec![
+                String::from("GET"),
+                String::from("POST"),
+                String::from("DELETE"),
+            ],
+            allowed_headers: vec![
+                String::from("Content-Type"),
+                String::from("Authorization"),
+                String::from("X-API-Key"),
+            ],
+            allow_credentials: false,
+            max_age: 3600,
+        }
+    }
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TemplatesConfig {
+    pub mode: TemplateMode,
+}
+
+/// How uploaded images are stored, and the variants stored for every one of them, by name.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ImagesConfig {
+    pub format: OutputFormat,
+    // 1-100, for JPEG and WebP
+    pub quality: u8,
+    // Re-encodes originals without EXIF and other metadata
+    pub strip_metadata: bool,
+    // Largest image that is decoded, in pixels and in MB of decoded pixel data
+    pub max_width: u32,
+    pub max_height: u32,
+    pub max_decode_mb: u64,
+    pub variants: BTreeMap<String, ImageVariant>,
+}
+
+impl Default for ImagesConfig {
+    fn default() -> Self {
+        ImagesConfig {
+            format: OutputFormat::Original,
+            quality: 85,
+            strip_metadata: true,
+            max_width: 12000,
+            max_height: 12000,
+            max_decode_mb: 512,
+            variants: BTreeMap::from([
+                (
+                    String::from("thumbnail"),
+                    ImageVariant {
+                        width: 150,
+                        height: 150,
+                        mode: ResizeMode::Fill,
+                    },
+                ),
+                (
+                    String::from("medium"),
+                    ImageVariant {
+                        width: 800,
+                        height: 800,
+                        mode: ResizeMode::Fit,
+                    },
+                ),
+            ]),
+        }
+    }
 }
 
-pub async fn get_postgres_connection() -> Arc<Client> {
-    let conn = env::var("DB_CONNECTION").expect("DB_CONNECTION must be set");
-    let (client, connection) = tokio_postgres::connect(conn.as_str(), NoTls).await.unwrap();
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RendererConfig {
+    pub command: String,
+    // Where the renderer reaches this service to load processed templates, defaults to
+    // `http://localhost:{port}`
+    pub internal_base_url: Option<String>,
+    // Seconds a render may run before its processes are killed
+    pub timeout: u64,
+    // Seconds the test render of `/readyz` may take, a cold browser start is slow
+    pub check_timeout: u64,
+    // Resource limits of every single renderer process, not of a render as a whole; 0 is
+    // unlimited. The memory limit caps the data segment, not the address space
+    pub max_cpu_secs: u64,
+    pub max_memory_mb: u64,
+}
+
+impl Default for RendererConfig {
+    fn default() -> Self {
+        RendererConfig {
+            command: String::from("sitetopdf"),
+            internal_base_url: None,
+            timeout: 120,
+            check_timeout: 30,
+            max_cpu_secs: 300,
+            max_memory_mb: 0,
+        }
+    }
+}
+
+/// How long generated files are kept. 0 keeps them forever.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionConfig {
+    pub reports_hours: u64,
+    pub temp_minutes: u64,
+    pub sweep_interval_minutes: u64,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        RetentionConfig {
+            reports_hours: 0,
+            temp_minutes: 60,
+            sweep_interval_minutes: 10,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = &'static str;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err("must be text or json"),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    // An `EnvFilter` directive such as `info` or `report_forge=debug,actix_web=warn`
+    pub level: String,
+    pub format: LogFormat,
+}
 
-    // The connection object performs the actual communication with the database,
-    // so spawn it off to run on its own.
-    tokio::spawn(async move {
-        if let Err(e) = connection.await {
-            eprintln!("connection error: {}", e);
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            level: String::from("info"),
+            format: LogFormat::Text,
         }
-    });
-    Arc::new(client)
+    }
+}
+
+/// Every problem found while loading the configuration, reported together at startup.
+pub struct ConfigError(Vec<String>);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Invalid configuration:")?;
+        for problem in &self.0 {
+            writeln!(f, "  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+// Applies environment variables on top of the file, collecting parse errors
+struct EnvOverrides {
+    errors: Vec<String>,
+}
+
+impl EnvOverrides {
+    fn value(key: &str) -> Option<String> {
+        env::var(key).ok().map(|value| value.trim().to_string())
+    }
+
+    fn parse<T>(&mut self, key: &str, target: &mut T)
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        if let Some(value) = Self::value(key) {
+            match value.parse() {
+                Ok(parsed) => *target = parsed,
+                Err(err) => self
+                    .errors
+                    .push(format!("{}: invalid value {:?} ({})", key, value, err)),
+            }
+        }
+    }
+
+    fn optional(&mut self, key: &str, target: &mut Option<String>) {
+        if let Some(value) = Self::value(key) {
+            *target = Some(value);
+        }
+    }
+
+    // Comma separated lists, e.g. `CORS_ALLOWED_ORIGINS=https://a.example.com,https://b.example.com`
+    fn list(&mut self, key: &str, target: &mut Vec<String>) {
+        if let Some(value) = Self::value(key) {
+            *target = value
+                .split(',')
+                .map(|item| item.trim().to_string())
+                .filter(|item| !item.is_empty())
+                .collect();
+        }
+    }
+
+    // Comma separated `name=value` pairs, e.g. `IMAGE_VARIANTS=thumbnail=150x150:fill`
+    fn map<T>(&mut self, key: &str, target: &mut BTreeMap<String, T>)
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        let Some(value) = Self::value(key) else {
+            return;
+        };
+        let mut parsed = BTreeMap::new();
+        for item in value
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+        {
+            let entry = item
+                .split_once('=')
+                .ok_or_else(|| String::from("expected name=value"));
+            match entry.and_then(|(name, value)| {
+                let value = value
+                    .trim()
+                    .parse()
+                    .map_err(|err: T::Err| err.to_string())?;
+                Ok((name.trim().to_string(), value))
+            }) {
+                Ok((name, value)) => {
+                    parsed.insert(name, value);
+                }
+                Err(err) => self
+                    .errors
+                    .push(format!("{}: invalid value {:?} ({})", key, item, err)),
+            }
+        }
+        *target = parsed;
+    }
+}
+
+impl Config {
+    /// Loads the configuration file and environment overrides and validates the result.
+    pub fn load() -> Result<Self, ConfigError> {
+        let mut config = match env::var("CONFIG_FILE") {
+            Ok(path) => Self::from_file(&path)?,
+            Err(_) if Path::new(DEFAULT_CONFIG_FILE).is_file() => {
+                Self::from_file(DEFAULT_CONFIG_FILE)?
+            }
+            Err(_) => Config::default(),
+        };
+        // Invalid variables and values are reported together
+        let mut errors = config.apply_env();
+        config.normalize();
+        errors.extend(config.validate());
+        if errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(ConfigError(errors))
+        }
+    }
+
+    fn from_file(path: &str) -> Result<Self, ConfigError> {
+        let contents = fs::read_to_string(path)
+            .map_err(|err| ConfigError(vec![format!("{}: {}", path, err)]))?;
+        let parsed = match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => toml::from_str(&contents).map_err(|err| err.to_string()),
+            Some("yaml" | "yml") => serde_yaml::from_str(&contents).map_err(|err| err.to_string()),
+            _ => Err(String::from(
+                "unsupported file type, use a .toml, .yaml or .yml file",
+            )),
+        };
+        parsed.map_err(|err| ConfigError(vec![format!("{}: {}", path, err.trim_end())]))
+    }
+
+    // The variable names predate the configuration file and are kept for existing deployments
+    fn apply_env(&mut self) -> Vec<String> {
+        let mut env = EnvOverrides { errors: Vec::new() };
+
+        env.parse("BIND_ADDRESS", &mut self.server.bind);
+        env.parse("PORT", &mut self.server.port);
+        env.parse("SHUTDOWN_TIMEOUT", &mut self.server.shutdown_timeout);
+
+        env.parse("TEMP_DIR", &mut self.paths.temp);
+        env.parse("TEMPLATES_DIR", &mut self.paths.templates);
+        env.parse("REPORTS_DIR", &mut self.paths.reports);
+        env.parse("IMAGES_DIR", &mut self.paths.images);
+        env.parse("DATASETS_DIR", &mut self.paths.datasets);
+
+        env.parse("STORAGE_BACKEND", &mut self.storage.backend);
+        env.parse("STORAGE_PRESIGNED_URLS", &mut self.storage.presigned_urls);
+        env.parse("S3_BUCKET", &mut self.storage.s3.bucket);
+        env.parse("S3_REGION", &mut self.storage.s3.region);
+        env.optional("S3_ENDPOINT", &mut self.storage.s3.endpoint);
+        env.optional("S3_ACCESS_KEY_ID", &mut self.storage.s3.access_key_id);
+        env.optional(
+            "S3_SECRET_ACCESS_KEY",
+            &mut self.storage.s3.secret_access_key,
+        );
+        env.parse("S3_PREFIX", &mut self.storage.s3.prefix);
+        env.parse("S3_FORCE_PATH_STYLE", &mut self.storage.s3.force_path_style);
+
+        env.optional("DB_CONNECTION", &mut self.database.connection);
+        env.optional(
+            "TEMPLATE_DB_CONNECTION",
+            &mut self.database.template_connection,
+        );
+        env.parse("DB_POOL_SIZE", &mut self.database.pool_size);
+
+        env.optional("ADMIN_API_KEY", &mut self.auth.admin_api_key);
+        env.optional("JWT_SECRET", &mut self.auth.jwt_secret);
+        env.optional("URL_SIGNING_SECRET", &mut self.auth.url_signing_secret);
+        env.parse(
+            "SINGLE_USE_GRACE_SECS",
+            &mut self.auth.single_use_grace_secs,
+        );
+
+        env.parse("MAX_REQUEST_BYTES", &mut self.limits.max_request_bytes);
+        env.parse("MAX_TEMPLATE_BYTES", &mut self.limits.max_template_bytes);
+        env.parse("MAX_IMAGE_BYTES", &mut self.limits.max_image_bytes);
+        env.parse("MAX_DATASET_BYTES", &mut self.limits.max_dataset_bytes);
+        env.parse(
+            "MAX_CONCURRENT_RENDERS",
+            &mut self.limits.max_concurrent_renders,
+        );
+        env.parse("DAILY_PAGE_LIMIT", &mut self.limits.daily_page_limit);
+        env.parse("DAILY_BYTE_LIMIT", &mut self.limits.daily_byte_limit);
+
+        env.parse(
+            "RATE_LIMIT_KEY_PER_MINUTE",
+            &mut self.rate_limit.key_per_minute,
+        );
+        env.parse("RATE_LIMIT_KEY_BURST", &mut self.rate_limit.key_burst);
+        env.parse(
+            "RATE_LIMIT_IP_PER_MINUTE",
+            &mut self.rate_limit.ip_per_minute,
+        );
+        env.parse("RATE_LIMIT_IP_BURST", &mut self.rate_limit.ip_burst);
+
+        env.parse("QUERY_CACHE_MAX_ENTRIES", &mut self.query_cache.max_entries);
+        env.parse("QUERY_CACHE_MAX_BYTES", &mut self.query_cache.max_bytes);
+
+        env.parse("REPORT_URL_TTL", &mut self.report_urls.ttl);
+        env.parse("REPORT_URL_MAX_TTL", &mut self.report_urls.max_ttl);
+
+        env.list("URL_ALLOWED_SCHEMES", &mut self.url_policy.allowed_schemes);
+        env.list("URL_ALLOWED_HOSTS", &mut self.url_policy.allowed_hosts);
+        env.list("URL_DENIED_HOSTS", &mut self.url_policy.denied_hosts);
+        env.parse(
+            "URL_ALLOW_PRIVATE_NETWORKS",
+            &mut self.url_policy.allow_private_networks,
+        );
+
+        env.list("CORS_ALLOWED_ORIGINS", &mut self.cors.allowed_origins);
+        env.list("CORS_ALLOWED_METHODS", &mut self.cors.allowed_methods);
+        env.list("CORS_ALLOWED_HEADERS", &mut self.cors.allowed_headers);
+        env.parse("CORS_ALLOW_CREDENTIALS", &mut self.cors.allow_credentials);
+        env.parse("CORS_MAX_AGE", &mut self.cors.max_age);
+
+        env.parse("TEMPLATE_MODE", &mut self.templates.mode);
+
+        env.parse("IMAGE_FORMAT", &mut self.images.format);
+        env.parse("IMAGE_QUALITY", &mut self.images.quality);
+        env.parse("IMAGE_STRIP_METADATA", &mut self.images.strip_metadata);
+        env.parse("IMAGE_MAX_WIDTH", &mut self.images.max_width);
+        env.parse("IMAGE_MAX_HEIGHT", &mut self.images.max_height);
+        env.parse("IMAGE_MAX_DECODE_MB", &mut self.images.max_decode_mb);
+        env.map("IMAGE_VARIANTS", &mut self.images.variants);
+
+        env.parse("RENDERER_COMMAND", &mut self.renderer.command);
+        env.optional(
+            "RENDERER_INTERNAL_BASE_URL",
+            &mut self.renderer.internal_base_url,
+        );
+        env.parse("RENDER_TIMEOUT", &mut self.renderer.timeout);
+        env.parse("RENDER_CHECK_TIMEOUT", &mut self.renderer.check_timeout);
+        env.parse("RENDER_MAX_CPU_SECS", &mut self.renderer.max_cpu_secs);
+        env.parse("RENDER_MAX_MEMORY_MB", &mut self.renderer.max_memory_mb);
+
+        env.parse("RETENTION_REPORTS_HOURS", &mut self.retention.reports_hours);
+        env.parse("RETENTION_TEMP_MINUTES", &mut self.retention.temp_minutes);
+        env.parse(
+            "RETENTION_SWEEP_INTERVAL_MINUTES",
+            &mut self.retention.sweep_interval_minutes,
+        );
+
+        env.parse("LOG_LEVEL", &mut self.logging.level);
+        env.parse("LOG_FORMAT", &mut self.logging.format);
+
+        env.errors
+    }
+
+    fn normalize(&mut self) {
+        let lowercase = |items: &mut Vec<String>| {
+            items
+                .iter_mut()
+                .for_each(|item| *item = item.to_lowercase());
+        };
+        lowercase(&mut self.url_policy.allowed_schemes);
+        lowercase(&mut self.url_policy.allowed_hosts);
+        lowercase(&mut self.url_policy.denied_hosts);
+        self.cors
+            .allowed_methods
+            .iter_mut()
+            .for_each(|method| *method = method.to_uppercase());
+
+        // Empty secrets are treated as unset
+        for secret in [
+            &mut self.database.connection,
+            &mut self.database.template_connection,
+            &mut self.auth.admin_api_key,
+            &mut self.auth.jwt_secret,
+            &mut self.auth.url_signing_secret,
+            &mut self.renderer.internal_base_url,
+            &mut self.storage.s3.endpoint,
+            &mut self.storage.s3.access_key_id,
+            &mut self.storage.s3.secret_access_key,
+        ] {
+            if secret.as_deref().is_some_and(str::is_empty) {
+                *secret = None;
+            }
+        }
+    }
+
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        let mut check = |ok: bool, message: &str| {
+            if !ok {
+                errors.push(message.to_string());
+            }
+        };
+
+        check(
+            self.server.bind.parse::<IpAddr>().is_ok(),
+            "server.bind (BIND_ADDRESS) must be an IP address such as 0.0.0.0",
+        );
+        check(self.server.port != 0, "server.port (PORT) must not be 0");
+
+        for (name, path) in self.paths.all() {
+            check(
+                !path.trim().is_empty(),
+                &format!("paths.{} must not be empty", name),
+            );
+        }
+
+        match self.storage.backend {
+            StorageBackend::S3 => {
+                let s3 = &self.storage.s3;
+                check(
+                    !s3.bucket.is_empty(),
+                    "storage.s3.bucket (S3_BUCKET) must be set for the s3 backend",
+                );
+                check(
+                    s3.access_key_id.is_some() == s3.secret_access_key.is_some(),
+                    "storage.s3.access_key_id and storage.s3.secret_access_key must be set together",
+                );
+                if let Some(endpoint) = &s3.endpoint {
+                    check(
+                        Url::parse(endpoint).is_ok(),
+                        "storage.s3.endpoint (S3_ENDPOINT) must be a URL",
+                    );
+                }
+            }
+            StorageBackend::Local => check(
+                !self.storage.presigned_urls,
+                "storage.presigned_urls (STORAGE_PRESIGNED_URLS) needs the s3 backend",
+            ),
+        }
+
+        match &self.database.connection {
+            Some(connection) => check(
+                connection.parse::<tokio_postgres::Config>().is_ok(),
+                "database.connection (DB_CONNECTION) is not a valid connection string",
+            ),
+            None => check(false, "database.connection (DB_CONNECTION) must be set"),
+        }
+        if let Some(connection) = &self.database.template_connection {
+            check(
+                connection.parse::<tokio_postgres::Config>().is_ok(),
+                "database.template_connection (TEMPLATE_DB_CONNECTION) is not a valid connection string",
+            );
+        }
+        check(
+            self.database.pool_size > 0,
+            "database.pool_size (DB_POOL_SIZE) must be at least 1",
+        );
+
+        check(
+            self.limits.max_request_bytes > 0,
+            "limits.max_request_bytes (MAX_REQUEST_BYTES) must be at least 1",
+        );
+        check(
+            self.limits.max_template_bytes > 0
+                && self.limits.max_image_bytes > 0
+                && self.limits.max_dataset_bytes > 0,
+            "limits.max_template_bytes, limits.max_image_bytes and limits.max_dataset_bytes must be at least 1",
+        );
+        check(
+            self.limits.daily_page_limit >= 0 && self.limits.daily_byte_limit >= 0,
+            "limits.daily_page_limit and limits.daily_byte_limit must not be negative",
+        );
+
+        check(
+            self.report_urls.ttl > 0 && self.report_urls.ttl <= self.report_urls.max_ttl,
+            "report_urls.ttl (REPORT_URL_TTL) must be between 1 and report_urls.max_ttl",
+        );
+
+        check(
+            !self.url_policy.allowed_schemes.is_empty(),
+            "url_policy.allowed_schemes (URL_ALLOWED_SCHEMES) must not be empty",
+        );
+
+        for origin in &self.cors.allowed_origins {
+            check(
+                origin == "*"
+                    || Url::parse(origin).is_ok_and(|url| {
+                        matches!(url.scheme(), "http" | "https") && url.path() == "/"
+                    }),
+                &format!(
+                    "cors.allowed_origins: {:?} must be * or an origin such as https://app.example.com",
+                    origin
+                ),
+            );
+        }
+        check(
+            !(self.cors.allow_credentials && self.cors.allowed_origins.iter().any(|o| o == "*")),
+            "cors.allow_credentials can't be combined with the * origin",
+        );
+        for method in &self.cors.allowed_methods {
+            check(
+                Method::from_str(method).is_ok(),
+                &format!("cors.allowed_methods: {:?} is not a HTTP method", method),
+            );
+        }
+
+        check(
+            (1..=100).contains(&self.images.quality),
+            "images.quality (IMAGE_QUALITY) must be between 1 and 100",
+        );
+        check(
+            self.images.max_width > 0 && self.images.max_height > 0,
+            "images.max_width and images.max_height (IMAGE_MAX_WIDTH, IMAGE_MAX_HEIGHT) must be at least 1",
+        );
+        check(
+            self.images.max_decode_mb > 0,
+            "images.max_decode_mb (IMAGE_MAX_DECODE_MB) must be at least 1",
+        );
+        for (name, variant) in &self.images.variants {
+            check(
+                is_valid_variant_name(name),
+                &format!(
+                    "images.variants: {:?} may only contain letters, numbers, '_' and '-' and can't be \"original\"",
+                    name
+                ),
+            );
+            check(
+                variant.is_valid(),
+                &format!(
+                    "images.variants.{}: width and height must be at least 1",
+                    name
+                ),
+            );
+        }
+
+        check(
+            !self.renderer.command.trim().is_empty(),
+            "renderer.command (RENDERER_COMMAND) must not be empty",
+        );
+        if let Some(base_url) = &self.renderer.internal_base_url {
+            check(
+                Url::parse(base_url).is_ok_and(|url| matches!(url.scheme(), "http" | "https")),
+                "renderer.internal_base_url (RENDERER_INTERNAL_BASE_URL) must be a http(s) URL",
+            );
+        }
+        check(
+            self.renderer.timeout > 0,
+            "renderer.timeout (RENDER_TIMEOUT) must be at least 1",
+        );
+        check(
+            self.renderer.check_timeout > 0,
+            "renderer.check_timeout (RENDER_CHECK_TIMEOUT) must be at least 1",
+        );
+
+        check(
+            self.retention.sweep_interval_minutes > 0,
+            "retention.sweep_interval_minutes (RETENTION_SWEEP_INTERVAL_MINUTES) must be at least 1",
+        );
+
+        if let Err(err) = EnvFilter::try_new(&self.logging.level) {
+            check(
+                false,
+                &format!("logging.level (LOG_LEVEL) is invalid: {}", err),
+            );
+        }
+
+        errors
+    }
+
+    /// Base URL the renderer uses to fetch processed templates from this service.
+    pub fn internal_base_url(&self) -> String {
+        match &self.renderer.internal_base_url {
+            Some(base_url) => base_url.trim_end_matches('/').to_string(),
+            None => format!("http://localhost:{}", self.server.port),
+        }
+    }
+}
+
+/// Loads the configuration once at startup. Later calls return the same configuration.
+pub fn init() -> Result<&'static Config, ConfigError> {
+    if let Some(config) = CONFIG.get() {
+        return Ok(config);
+    }
+    let config = Config::load()?;
+    Ok(CONFIG.get_or_init(|| config))
+}
+
+/// The configuration loaded by [`init`].
+pub fn config() -> &'static Config {
+    CONFIG.get().expect("configuration is not loaded")
+}
+
+/// Fixed configuration and database access for tests that go through [`config`].
+#[cfg(test)]
+pub mod testing {
+    use super::*;
+
+    pub const ADMIN_API_KEY: &str = "test-admin-key";
+    pub const JWT_SECRET: &str = "test-jwt-secret";
+
+    /// Loads the test configuration, which every test shares.
+    pub fn config() -> &'static Config {
+        CONFIG.get_or_init(|| {
+            let mut config = Config::default();
+            config.database.connection = env::var("TEST_DB_CONNECTION").ok();
+            config.auth.admin_api_key = Some(ADMIN_API_KEY.to_string());
+            config.auth.jwt_secret = Some(JWT_SECRET.to_string());
+            config.auth.url_signing_secret = Some(String::from("test-signing-secret"));
+            config.normalize();
+            config
+        })
+    }
+
+    /// Pool on `TEST_DB_CONNECTION`. Tests that need Postgres are skipped when it isn't set.
+    pub fn pool() -> Option<Pool> {
+        let conn = config().database.connection.as_deref()?;
+        Some(create_postgres_pool(conn).expect("TEST_DB_CONNECTION is invalid"))
+    }
+}
+
+pub fn create_postgres_pool(conn: &str) -> Result<Pool, Box<dyn std::error::Error>> {
+    let pg_config: tokio_postgres::Config = conn.parse()?;
+    let manager = Manager::from_config(
+        pg_config,
+        NoTls,
+        ManagerConfig {
+            recycling_method: RecyclingMethod::Fast,
+        },
+    );
+
+    // Connections are opened lazily and re-established if the database goes away
+    Ok(Pool::builder(manager)
+        .max_size(config().database.pool_size)
+        .build()?)
+}
+
+pub fn get_postgres_pool() -> Pool {
+    let conn = config().database.connection.as_deref().unwrap_or_default();
+    create_postgres_pool(conn).expect("database.connection is invalid")
+}
+
+/// The pool `{{#sql}}` blocks use when the tenant has no database of its own.
+pub fn get_template_pool() -> Option<Pool> {
+    let conn = config().database.template_connection.as_deref()?;
+    Some(create_postgres_pool(conn).expect("database.template_connection is invalid"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(contents: &str) -> Result<Config, toml::de::Error> {
+        toml::from_str(contents)
+    }
+
+    #[test]
+    fn missing_sections_use_defaults() {
+        let config = parse("[database]\nconnection = \"host=localhost user=postgres\"\n").unwrap();
+        assert_eq!(config.server.port, 8080);
+        assert_eq!(config.paths.reports, "./reports");
+        assert!(config.validate().is_empty());
+        assert_eq!(config.internal_base_url(), "http://localhost:8080");
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        let err = parse("[server]\nprot = 8080\n").err().unwrap();
+        assert!(err.to_string().contains("unknown field `prot`"));
+    }
+
+    #[test]
+    fn every_problem_is_reported() {
+        let mut config = parse(
+            "[server]\nport = 0\n[cors]\nallowed_origins = [\"*\"]\nallow_credentials = true\n",
+        )
+        .unwrap();
+        config.normalize();
+        let errors = config.validate();
+        assert_eq!(errors.len(), 3, "{:?}", errors);
+        assert!(errors[0].starts_with("server.port"));
+        assert!(errors[1].starts_with("database.connection"));
+        assert!(errors[2].starts_with("cors.allow_credentials"));
+    }
+
+    #[test]
+    fn loads_yaml_files() {
+        let path = env::temp_dir().join(format!("report-forge-{}.yaml", std::process::id()));
+        fs::write(
+            &path,
+            "server:\n  port: 9090\nretention:\n  reports_hours: 24\n  sweep_interval_minutes: 5\n",
+        )
+        .unwrap();
+        let config = Config::from_file(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        let config = config.map_err(|err| err.0).unwrap();
+        assert_eq!(config.server.port, 9090);
+        assert_eq!(config.retention.reports_hours, 24);
+        assert_eq!(config.retention.sweep_interval_minutes, 5);
+        assert_eq!(config.retention.temp_minutes, 60);
+    }
+
+    #[test]
+    fn environment_overrides_the_file() {
+        // Only read by `apply_env`, which no other test calls
+        env::set_var("RETENTION_SWEEP_INTERVAL_MINUTES", " 3 ");
+        env::set_var("RETENTION_REPORTS_HOURS", "soon");
+        env::set_var(
+            "CORS_ALLOWED_ORIGINS",
+            "https://a.example.com, ,https://b.example.com",
+        );
+        let mut config =
+            parse("[retention]\nsweep_interval_minutes = 5\nreports_hours = 24\n").unwrap();
+        let errors = config.apply_env();
+        env::remove_var("RETENTION_SWEEP_INTERVAL_MINUTES");
+        env::remove_var("RETENTION_REPORTS_HOURS");
+        env::remove_var("CORS_ALLOWED_ORIGINS");
+
+        assert_eq!(config.retention.sweep_interval_minutes, 3);
+        assert_eq!(config.retention.reports_hours, 24);
+        assert_eq!(
+            config.cors.allowed_origins,
+            ["https://a.example.com", "https://b.example.com"]
+        );
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert!(errors[0].starts_with("RETENTION_REPORTS_HOURS: invalid value \"soon\""));
+    }
 }
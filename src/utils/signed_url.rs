@@ -0,0 +1,234 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use deadpool_postgres::Pool;
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Query parameters carried by a signed download URL.
+#[derive(Deserialize)]
+pub struct SignatureQuery {
+    pub expires: Option<u64>,
+    #[serde(default)]
+    pub single_use: bool,
+    pub signature: Option<String>,
+}
+
+/// Signs and verifies download URLs for files served without an API key.
+pub struct UrlSigner {
+    secret: Vec<u8>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+impl UrlSigner {
+    pub fn new(secret: &[u8]) -> Self {
+        UrlSigner {
+            secret: secret.to_vec(),
+        }
+    }
+
+    fn mac(&self, path: &str, expires: u64, single_use: bool) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.secret).expect("HMAC accepts any key size");
+        mac.update(format!("{}\n{}\n{}", path, expires, single_use).as_bytes());
+        mac
+    }
+
+    /// Returns `path` with an expiry timestamp, the single use flag and their signature appended.
+    pub fn sign(&self, path: &str, expires_in: u64, single_use: bool) -> String {
+        self.sign_until(path, now() + expires_in, single_use)
+    }
+
+    fn sign_until(&self, path: &str, expires: u64, single_use: bool) -> String {
+        let signature = hex::encode(self.mac(path, expires, single_use).finalize().into_bytes());
+        let mut url = format!("{}?expires={}", path, expires);
+        if single_use {
+            url.push_str("&single_use=true");
+        }
+        format!("{}&signature={}", url, signature)
+    }
+
+    /// Checks the signature of a request for `path`, returning why it was rejected.
+    pub fn verify(&self, path: &str, query: &SignatureQuery) -> Result<(), &'static str> {
+        let (Some(expires), Some(signature)) = (query.expires, &query.signature) else {
+            return Err("Download URL is not signed!");
+        };
+        let Ok(signature) = hex::decode(signature) else {
+            return Err("Download URL signature is invalid!");
+        };
+        // `verify_slice` compares in constant time
+        if self
+            .mac(path, expires, query.single_use)
+            .verify_slice(&signature)
+            .is_err()
+        {
+            return Err("Download URL signature is invalid!");
+        }
+        if expires < now() {
+            return Err("Download URL has expired!");
+        }
+        Ok(())
+    }
+}
+
+pub async fn init_schema(pool: &Pool) -> Result<(), Box<dyn std::error::Error>> {
+    let client = pool.get().await?;
+    client
+        .batch_execute(
+            "CREATE TABLE IF NOT EXISTS used_signatures (
+                signature text PRIMARY KEY,
+                expires_at timestamptz NOT NULL
+            );
+            ALTER TABLE used_signatures
+                ADD COLUMN IF NOT EXISTS client text,
+                ADD COLUMN IF NOT EXISTS redeemed_at timestamptz NOT NULL DEFAULT now();",
+        )
+        .await?;
+    Ok(())
+}
+
+/// Whether a single use signature can still be used by `client`: it wasn't redeemed yet, or
+/// was redeemed by the same client less than `grace_secs` ago. Doesn't redeem it.
+pub async fn is_usable(
+    pool: &Pool,
+    signature: &str,
+    client: &str,
+    grace_secs: u64,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let client_conn = pool.get().await?;
+    let row = client_conn
+        .query_opt(
+            "SELECT client IS NOT DISTINCT FROM $2
+                AND redeemed_at > now() - make_interval(secs => $3)
+             FROM used_signatures WHERE signature = $1",
+            &[&signature, &client, &(grace_secs as f64)],
+        )
+        .await?;
+    Ok(row.is_none_or(|row| row.get(0)))
+}
+
+/// Marks a single use signature as redeemed by `client`, returning `false` if another client
+/// used it before, or the `grace_secs` of this client are over.
+pub async fn redeem(
+    pool: &Pool,
+    signature: &str,
+    expires: u64,
+    client: &str,
+    grace_secs: u64,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let client_conn = pool.get().await?;
+    // Expired signatures are rejected before lookup, so their rows are no longer needed
+    client_conn
+        .execute("DELETE FROM used_signatures WHERE expires_at < now()", &[])
+        .await?;
+    // The update only touches rows of the same client in its grace period, it leaves
+    // `redeemed_at` alone so the period isn't extended
+    let redeemed = client_conn
+        .execute(
+            "INSERT INTO used_signatures (signature, expires_at, client)
+             VALUES ($1, to_timestamp($2::float8), $3)
+             ON CONFLICT (signature) DO UPDATE SET client = EXCLUDED.client
+             WHERE used_signatures.client IS NOT DISTINCT FROM EXCLUDED.client
+                AND used_signatures.redeemed_at > now() - make_interval(secs => $4)",
+            &[&signature, &(expires as f64), &client, &(grace_secs as f64)],
+        )
+        .await?;
+    Ok(redeemed == 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::utils::setting::testing;
+
+    use super::*;
+
+    const PATH: &str = "/reports/acme/report.pdf";
+
+    // Splits a signed URL into its path and query
+    fn parse(url: &str) -> (String, SignatureQuery) {
+        let (path, query) = url.split_once('?').unwrap();
+        let query = actix_web::web::Query::<SignatureQuery>::from_query(query).unwrap();
+        (path.to_string(), query.into_inner())
+    }
+
+    #[test]
+    fn accepts_signed_urls() {
+        let signer = UrlSigner::new(b"secret");
+        for single_use in [false, true] {
+            let (path, query) = parse(&signer.sign(PATH, 60, single_use));
+            assert_eq!(path, PATH);
+            assert_eq!(query.single_use, single_use);
+            assert_eq!(signer.verify(PATH, &query), Ok(()));
+        }
+    }
+
+    #[test]
+    fn rejects_tampered_urls() {
+        let signer = UrlSigner::new(b"secret");
+        let invalid = Err("Download URL signature is invalid!");
+        let (_, mut query) = parse(&signer.sign(PATH, 60, true));
+
+        assert_eq!(signer.verify("/reports/other/report.pdf", &query), invalid);
+        query.single_use = false;
+        assert_eq!(signer.verify(PATH, &query), invalid);
+        query.single_use = true;
+        query.expires = query.expires.map(|expires| expires + 3600);
+        assert_eq!(signer.verify(PATH, &query), invalid);
+        query.expires = query.expires.map(|expires| expires - 3600);
+        assert_eq!(UrlSigner::new(b"other").verify(PATH, &query), invalid);
+        query.signature = Some(String::from("not hex"));
+        assert_eq!(signer.verify(PATH, &query), invalid);
+        query.signature = None;
+        assert_eq!(
+            signer.verify(PATH, &query),
+            Err("Download URL is not signed!")
+        );
+    }
+
+    #[test]
+    fn rejects_expired_urls() {
+        let signer = UrlSigner::new(b"secret");
+        let (_, query) = parse(&signer.sign_until(PATH, now() - 1, false));
+        assert_eq!(
+            signer.verify(PATH, &query),
+            Err("Download URL has expired!")
+        );
+    }
+
+    #[actix_web::test]
+    async fn single_use_urls_are_redeemed_once() {
+        let Some(pool) = testing::pool() else {
+            return;
+        };
+        init_schema(&pool).await.unwrap();
+        let signature = uuid::Uuid::new_v4().to_string();
+        let expires = now() + 60;
+
+        assert!(is_usable(&pool, &signature, "10.0.0.1", 0).await.unwrap());
+        assert!(redeem(&pool, &signature, expires, "10.0.0.1", 0)
+            .await
+            .unwrap());
+        // Without a grace period not even the same client may fetch it again
+        assert!(!is_usable(&pool, &signature, "10.0.0.1", 0).await.unwrap());
+        assert!(!redeem(&pool, &signature, expires, "10.0.0.1", 0)
+            .await
+            .unwrap());
+
+        // With one, only the client that redeemed it may
+        assert!(is_usable(&pool, &signature, "10.0.0.1", 300).await.unwrap());
+        assert!(redeem(&pool, &signature, expires, "10.0.0.1", 300)
+            .await
+            .unwrap());
+        assert!(!is_usable(&pool, &signature, "10.0.0.2", 300).await.unwrap());
+        assert!(!redeem(&pool, &signature, expires, "10.0.0.2", 300)
+            .await
+            .unwrap());
+    }
+}
@@ -0,0 +1,27 @@
+use std::{fs, io};
+
+use tracing::{debug, warn};
+
+/// Files written to temp while a request is processed, removed once it is done, however it
+/// ends. Files already moved into storage are gone by then.
+#[derive(Default)]
+pub struct StagedFiles(Vec<String>);
+
+impl StagedFiles {
+    pub fn add(&mut self, path: String) -> String {
+        self.0.push(path.clone());
+        path
+    }
+}
+
+impl Drop for StagedFiles {
+    fn drop(&mut self) {
+        for path in &self.0 {
+            match fs::remove_file(path) {
+                Ok(()) => debug!("Deleted {}", path),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => warn!("Error deleting file: {}", err),
+            }
+        }
+    }
+}
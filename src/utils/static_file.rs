@@ -0,0 +1,312 @@
+use std::time::{Duration, UNIX_EPOCH};
+
+use actix_web::{
+    http::{
+        header::{
+            self, ContentRangeSpec, EntityTag, Header, HttpDate, IfModifiedSince, IfNoneMatch,
+            IfRange, Range,
+        },
+        Method, StatusCode,
+    },
+    web::Bytes,
+    HttpRequest, HttpResponse,
+};
+use futures::stream;
+
+use crate::utils::{
+    error::AppError,
+    storage::{ObjectMeta, Storage},
+};
+
+// Uploaded images never change, every upload gets a new name
+pub const CACHE_IMMUTABLE: &str = "public, max-age=31536000, immutable";
+// Report URLs are signed, so shared caches must not keep them
+pub const CACHE_PRIVATE: &str = "private, no-cache";
+
+/// Rejects empty, hidden and relative segments in a requested path.
+pub fn is_valid_path(path: &str) -> bool {
+    !path
+        .split('/')
+        .any(|part| part.is_empty() || part.starts_with('.'))
+}
+
+/// Serves a stored object like a static file server: with its content type, `ETag` and
+/// `Last-Modified`, answering conditional requests with `304` and single `Range` requests
+/// with `206`. The body is streamed from storage. Returns `None` if there is no object
+/// under `key`.
+pub async fn serve(
+    req: &HttpRequest,
+    storage: &dyn Storage,
+    key: &str,
+    cache_control: &str,
+) -> Result<Option<HttpResponse>, AppError> {
+    let Some(meta) = storage.head(key).await? else {
+        return Ok(None);
+    };
+    let etag = meta
+        .etag
+        .as_deref()
+        .and_then(|etag| etag.parse::<EntityTag>().ok());
+    // HTTP dates have whole seconds, comparisons with the file's time must as well
+    let last_modified = meta
+        .last_modified
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|modified| HttpDate::from(UNIX_EPOCH + Duration::from_secs(modified.as_secs())));
+
+    let mut response = HttpResponse::Ok();
+    response
+        .content_type(meta.content_type.as_str())
+        .insert_header((header::ACCEPT_RANGES, "bytes"))
+        .insert_header((header::CACHE_CONTROL, cache_control));
+    if let Some(etag) = &etag {
+        response.insert_header(header::ETag(etag.clone()));
+    }
+    if let Some(last_modified) = last_modified {
+        response.insert_header(header::LastModified(last_modified));
+    }
+
+    if !is_modified(req, etag.as_ref(), last_modified) {
+        return Ok(Some(response.status(StatusCode::NOT_MODIFIED).finish()));
+    }
+
+    let range = match requested_range(req, &meta, etag.as_ref(), last_modified) {
+        Ok(range) => range,
+        Err(()) => {
+            return Ok(Some(
+                response
+                    .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                    .insert_header(header::ContentRange(ContentRangeSpec::Bytes {
+                        range: None,
+                        instance_length: Some(meta.size),
+                    }))
+                    .finish(),
+            ))
+        }
+    };
+    let length = match range {
+        Some((start, end)) => {
+            response
+                .status(StatusCode::PARTIAL_CONTENT)
+                .insert_header(header::ContentRange(ContentRangeSpec::Bytes {
+                    range: Some((start, end)),
+                    instance_length: Some(meta.size),
+                }));
+            end - start + 1
+        }
+        None => meta.size,
+    };
+    response.no_chunking(length);
+
+    // A HEAD request only needs the headers, the body isn't fetched at all. It is still a
+    // stream, an empty body would replace the content length.
+    if req.method() == Method::HEAD {
+        return Ok(Some(
+            response.streaming(stream::empty::<Result<Bytes, AppError>>()),
+        ));
+    }
+    let Some(body) = storage.get(key, range).await? else {
+        return Ok(None);
+    };
+    Ok(Some(response.streaming(body)))
+}
+
+// `If-None-Match` takes precedence over `If-Modified-Since`, as in RFC 9110
+fn is_modified(
+    req: &HttpRequest,
+    etag: Option<&EntityTag>,
+    last_modified: Option<HttpDate>,
+) -> bool {
+    // A missing list header parses as an empty list, so its presence is checked first
+    if req.headers().contains_key(header::IF_NONE_MATCH) {
+        return match (IfNoneMatch::parse(req), etag) {
+            (Ok(IfNoneMatch::Any), _) => false,
+            (Ok(IfNoneMatch::Items(items)), Some(etag)) => {
+                !items.iter().any(|item| item.weak_eq(etag))
+            }
+            _ => true,
+        };
+    }
+    match (IfModifiedSince::parse(req), last_modified) {
+        (Ok(IfModifiedSince(since)), Some(last_modified)) => last_modified > since,
+        _ => true,
+    }
+}
+
+// The satisfiable byte range asked for, `None` for the whole object. Multiple ranges are
+// answered with the whole object, which clients have to accept. `Err` means the range
+// lies outside the object.
+fn requested_range(
+    req: &HttpRequest,
+    meta: &ObjectMeta,
+    etag: Option<&EntityTag>,
+    last_modified: Option<HttpDate>,
+) -> Result<Option<(u64, u64)>, ()> {
+    let Ok(Range::Bytes(ranges)) = Range::parse(req) else {
+        return Ok(None);
+    };
+    let [range] = ranges.as_slice() else {
+        return Ok(None);
+    };
+    // A range of an outdated version would corrupt a resumed download
+    if let Ok(if_range) = IfRange::parse(req) {
+        let unchanged = match (if_range, etag, last_modified) {
+            (IfRange::EntityTag(tag), Some(etag), _) => tag.strong_eq(etag),
+            (IfRange::Date(date), _, Some(last_modified)) => date == last_modified,
+            _ => false,
+        };
+        if !unchanged {
+            return Ok(None);
+        }
+    }
+    range.to_satisfiable_range(meta.size).map(Some).ok_or(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use actix_web::{body, http::header::HeaderValue, test::TestRequest};
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::utils::{setting::PathsConfig, storage::LocalStorage};
+
+    const KEY: &str = "reports/a.pdf";
+    const CONTENTS: &[u8] = b"0123456789";
+
+    // A storage holding `CONTENTS` under `KEY`, and the object's ETag
+    async fn storage() -> (LocalStorage, String, PathBuf) {
+        let base = std::env::temp_dir().join(format!("static-file-{}", Uuid::new_v4()));
+        let dir = |name: &str| base.join(name).to_string_lossy().to_string();
+        let paths = PathsConfig {
+            temp: dir("temp"),
+            templates: dir("templates"),
+            reports: dir("reports"),
+            images: dir("images"),
+            datasets: dir("datasets"),
+        };
+        std::fs::create_dir_all(&paths.temp).unwrap();
+        let upload = base.join("temp/a.pdf");
+        std::fs::write(&upload, CONTENTS).unwrap();
+        let storage = LocalStorage::new(&paths);
+        storage.put(KEY, &upload).await.unwrap();
+        let etag = storage.head(KEY).await.unwrap().unwrap().etag.unwrap();
+        (storage, etag, base)
+    }
+
+    async fn send(storage: &LocalStorage, req: TestRequest) -> (StatusCode, HttpResponse) {
+        let response = serve(&req.to_http_request(), storage, KEY, CACHE_PRIVATE)
+            .await
+            .unwrap()
+            .unwrap();
+        (response.status(), response)
+    }
+
+    async fn body_of(response: HttpResponse) -> Bytes {
+        body::to_bytes(response.into_body()).await.unwrap()
+    }
+
+    fn header(response: &HttpResponse, name: header::HeaderName) -> &HeaderValue {
+        response.headers().get(name).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn if_none_match_takes_precedence_over_if_modified_since() {
+        let (storage, etag, base) = storage().await;
+        let past = "Mon, 01 Jan 2001 00:00:00 GMT";
+        let future = "Fri, 01 Jan 2100 00:00:00 GMT";
+
+        let req = TestRequest::get()
+            .insert_header((header::IF_NONE_MATCH, etag.as_str()))
+            .insert_header((header::IF_MODIFIED_SINCE, past));
+        assert_eq!(send(&storage, req).await.0, StatusCode::NOT_MODIFIED);
+
+        let req = TestRequest::get()
+            .insert_header((header::IF_NONE_MATCH, "\"other\""))
+            .insert_header((header::IF_MODIFIED_SINCE, future));
+        let (status, response) = send(&storage, req).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body_of(response).await, CONTENTS);
+
+        // Weak comparison, as RFC 9110 requires for If-None-Match
+        let req = TestRequest::get().insert_header((header::IF_NONE_MATCH, format!("W/{etag}")));
+        assert_eq!(send(&storage, req).await.0, StatusCode::NOT_MODIFIED);
+
+        let req = TestRequest::get().insert_header((header::IF_MODIFIED_SINCE, future));
+        assert_eq!(send(&storage, req).await.0, StatusCode::NOT_MODIFIED);
+        std::fs::remove_dir_all(base).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn if_range_needs_a_strong_match() {
+        let (storage, etag, base) = storage().await;
+
+        let req = TestRequest::get()
+            .insert_header((header::RANGE, "bytes=2-5"))
+            .insert_header((header::IF_RANGE, etag.as_str()));
+        let (status, response) = send(&storage, req).await;
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(header(&response, header::CONTENT_RANGE), "bytes 2-5/10");
+        assert_eq!(body_of(response).await, &CONTENTS[2..6]);
+
+        let req = TestRequest::get()
+            .insert_header((header::RANGE, "bytes=2-5"))
+            .insert_header((header::IF_RANGE, format!("W/{etag}")));
+        let (status, response) = send(&storage, req).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body_of(response).await, CONTENTS);
+
+        let req = TestRequest::get()
+            .insert_header((header::RANGE, "bytes=2-5"))
+            .insert_header((header::IF_RANGE, "\"outdated\""));
+        assert_eq!(send(&storage, req).await.0, StatusCode::OK);
+        std::fs::remove_dir_all(base).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn rejects_ranges_outside_the_object() {
+        let (storage, _, base) = storage().await;
+
+        let req = TestRequest::get().insert_header((header::RANGE, "bytes=10-20"));
+        let (status, response) = send(&storage, req).await;
+        assert_eq!(status, StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(header(&response, header::CONTENT_RANGE), "bytes */10");
+
+        // The end of a range is capped at the object's size
+        let req = TestRequest::get().insert_header((header::RANGE, "bytes=8-20"));
+        let (status, response) = send(&storage, req).await;
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(body_of(response).await, &CONTENTS[8..]);
+        std::fs::remove_dir_all(base).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn answers_multiple_ranges_with_the_whole_object() {
+        let (storage, _, base) = storage().await;
+
+        let req = TestRequest::get().insert_header((header::RANGE, "bytes=0-1,4-5"));
+        let (status, response) = send(&storage, req).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(response.headers().get(header::CONTENT_RANGE).is_none());
+        assert_eq!(body_of(response).await, CONTENTS);
+        std::fs::remove_dir_all(base).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn head_keeps_the_content_length() {
+        let (storage, _, base) = storage().await;
+
+        let (status, response) = send(&storage, TestRequest::default().method(Method::HEAD)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(header(&response, header::CONTENT_LENGTH), "10");
+        assert!(body_of(response).await.is_empty());
+
+        let req = TestRequest::default()
+            .method(Method::HEAD)
+            .insert_header((header::RANGE, "bytes=0-3"));
+        let (status, response) = send(&storage, req).await;
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(header(&response, header::CONTENT_LENGTH), "4");
+        std::fs::remove_dir_all(base).unwrap();
+    }
+}
@@ -0,0 +1,105 @@
+use std::{io, path::Path, sync::Arc, time::Duration, time::SystemTime};
+
+use actix_web::web::Bytes;
+use futures::{future::BoxFuture, stream::BoxStream, TryStreamExt};
+
+use crate::utils::setting::{config, StorageBackend};
+
+mod local;
+mod s3;
+
+pub use local::LocalStorage;
+pub use s3::S3Storage;
+
+/// What is known about a stored object without reading it.
+pub struct ObjectMeta {
+    pub size: u64,
+    pub content_type: String,
+    pub last_modified: Option<SystemTime>,
+    // Quoted, as sent in the `ETag` header
+    pub etag: Option<String>,
+}
+
+/// The contents of a stored object, streamed to the client.
+pub type ObjectBody = BoxStream<'static, io::Result<Bytes>>;
+
+pub struct ObjectInfo {
+    pub key: String,
+    pub size: u64,
+    pub last_modified: Option<SystemTime>,
+}
+
+/// Where templates, images, datasets and generated reports are kept. Keys look like
+/// `reports/{storage_prefix}/{name}`, the first segment names one of the storage roots.
+pub trait Storage: Send + Sync {
+    /// Moves the local file at `path` into storage under `key`.
+    fn put<'a>(&'a self, key: &'a str, path: &'a Path) -> BoxFuture<'a, io::Result<()>>;
+
+    /// Returns `None` if there is no object under `key`.
+    fn head<'a>(&'a self, key: &'a str) -> BoxFuture<'a, io::Result<Option<ObjectMeta>>>;
+
+    /// Streams the object under `key`, or only the bytes `start..=end` of `range`. The range
+    /// must lie within the object. Returns `None` if there is no object under `key`.
+    fn get<'a>(
+        &'a self,
+        key: &'a str,
+        range: Option<(u64, u64)>,
+    ) -> BoxFuture<'a, io::Result<Option<ObjectBody>>>;
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, io::Result<()>>;
+
+    /// Lists every object whose key starts with `prefix`.
+    fn list<'a>(&'a self, prefix: &'a str) -> BoxFuture<'a, io::Result<Vec<ObjectInfo>>>;
+
+    /// A URL that gives direct, temporary access to the object, if the backend has them.
+    fn presigned_url<'a>(
+        &'a self,
+        key: &'a str,
+        expires_in: Duration,
+    ) -> BoxFuture<'a, io::Result<Option<String>>>;
+
+    /// Checks that the storage can be reached, for the readiness probe.
+    fn check(&self) -> BoxFuture<'_, Result<(), String>>;
+
+    fn exists<'a>(&'a self, key: &'a str) -> BoxFuture<'a, io::Result<bool>> {
+        Box::pin(async move { Ok(self.head(key).await?.is_some()) })
+    }
+
+    /// Reads a whole object into memory, meant for small objects like templates.
+    fn read<'a>(&'a self, key: &'a str) -> BoxFuture<'a, io::Result<Option<Vec<u8>>>> {
+        Box::pin(async move {
+            let Some(body) = self.get(key, None).await? else {
+                return Ok(None);
+            };
+            let contents = body
+                .try_fold(Vec::new(), |mut contents, chunk| async move {
+                    contents.extend_from_slice(&chunk);
+                    Ok(contents)
+                })
+                .await?;
+            Ok(Some(contents))
+        })
+    }
+}
+
+/// Creates the configured storage backend.
+pub async fn from_settings() -> Arc<dyn Storage> {
+    let config = config();
+    match config.storage.backend {
+        StorageBackend::Local => Arc::new(LocalStorage::new(&config.paths)),
+        StorageBackend::S3 => Arc::new(S3Storage::new(&config.storage.s3).await),
+    }
+}
+
+/// The URL an uploaded image is served from. It never expires, so templates can embed it;
+/// presigned URLs would stop working after their lifetime.
+pub fn image_url(key: &str) -> String {
+    format!("/{}", key)
+}
+
+pub fn content_type(key: &str) -> String {
+    mime_guess::from_path(key)
+        .first_or_octet_stream()
+        .essence_str()
+        .to_string()
+}
@@ -0,0 +1,337 @@
+use std::{
+    fs::Metadata,
+    io::{self, SeekFrom},
+    path::{Component, Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use actix_web::web::Bytes;
+use futures::{future::BoxFuture, stream};
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncSeekExt},
+};
+use uuid::Uuid;
+
+use super::{content_type, ObjectBody, ObjectInfo, ObjectMeta, Storage};
+use crate::utils::setting::PathsConfig;
+
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Keeps objects in the local storage roots, e.g. `reports/acme/x.pdf` in
+/// `{paths.reports}/acme/x.pdf`.
+pub struct LocalStorage {
+    roots: Vec<(&'static str, PathBuf)>,
+}
+
+impl LocalStorage {
+    pub fn new(paths: &PathsConfig) -> Self {
+        LocalStorage {
+            roots: vec![
+                ("templates", PathBuf::from(&paths.templates)),
+                ("images", PathBuf::from(&paths.images)),
+                ("reports", PathBuf::from(&paths.reports)),
+                ("datasets", PathBuf::from(&paths.datasets)),
+            ],
+        }
+    }
+
+    // Maps a key to its path, refusing anything that could leave the storage root
+    fn resolve(&self, key: &str) -> io::Result<PathBuf> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, format!("invalid key {key}"));
+        let (root, rest) = key.split_once('/').unwrap_or((key, ""));
+        let (_, dir) = self
+            .roots
+            .iter()
+            .find(|(name, _)| *name == root)
+            .ok_or_else(invalid)?;
+        let rest = Path::new(rest);
+        if !rest
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(invalid());
+        }
+        Ok(dir.join(rest))
+    }
+}
+
+fn object_meta(key: &str, metadata: &Metadata) -> ObjectMeta {
+    let last_modified = metadata.modified().ok();
+    // Files are only ever replaced as a whole, so the time and size identify a version
+    let etag = last_modified
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|modified| format!("\"{:x}-{:x}\"", modified.as_secs(), metadata.len()));
+    ObjectMeta {
+        size: metadata.len(),
+        content_type: content_type(key),
+        last_modified,
+        etag,
+    }
+}
+
+async fn list_dir(dir: PathBuf, key: String, objects: &mut Vec<ObjectInfo>) -> io::Result<()> {
+    let mut entries = match fs::read_dir(&dir).await {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        // Partial copies and probes; keys never start with a dot
+        if name.starts_with('.') {
+            continue;
+        }
+        let metadata = entry.metadata().await?;
+        let entry_key = if key.is_empty() {
+            name
+        } else {
+            format!("{}/{}", key, name)
+        };
+        if metadata.is_dir() {
+            Box::pin(list_dir(entry.path(), entry_key, objects)).await?;
+        } else {
+            objects.push(ObjectInfo {
+                key: entry_key,
+                size: metadata.len(),
+                last_modified: metadata.modified().ok(),
+            });
+        }
+    }
+    Ok(())
+}
+
+impl Storage for LocalStorage {
+    fn put<'a>(&'a self, key: &'a str, path: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let target = self.resolve(key)?;
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent).await?;
+            }
+            // Renaming fails across file systems, e.g. when temp is a separate volume. The copy
+            // goes to a hidden sibling first, so readers never see a partly copied object
+            if fs::rename(path, &target).await.is_err() {
+                let partial = target.with_file_name(format!(".{}.partial", Uuid::new_v4()));
+                let copied = match fs::copy(path, &partial).await {
+                    Ok(_) => fs::rename(&partial, &target).await,
+                    Err(err) => Err(err),
+                };
+                if let Err(err) = copied {
+                    let _ = fs::remove_file(&partial).await;
+                    return Err(err);
+                }
+                fs::remove_file(path).await?;
+            }
+            Ok(())
+        })
+    }
+
+    fn head<'a>(&'a self, key: &'a str) -> BoxFuture<'a, io::Result<Option<ObjectMeta>>> {
+        Box::pin(async move {
+            match fs::metadata(self.resolve(key)?).await {
+                Ok(metadata) if metadata.is_file() => Ok(Some(object_meta(key, &metadata))),
+                Ok(_) => Ok(None),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+                Err(err) => Err(err),
+            }
+        })
+    }
+
+    fn get<'a>(
+        &'a self,
+        key: &'a str,
+        range: Option<(u64, u64)>,
+    ) -> BoxFuture<'a, io::Result<Option<ObjectBody>>> {
+        Box::pin(async move {
+            let path = self.resolve(key)?;
+            let mut file = match fs::File::open(&path).await {
+                Ok(file) => file,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+                Err(err) => return Err(err),
+            };
+            let metadata = file.metadata().await?;
+            if !metadata.is_file() {
+                return Ok(None);
+            }
+            let (start, end) = range.unwrap_or((0, metadata.len().saturating_sub(1)));
+            file.seek(SeekFrom::Start(start)).await?;
+            let remaining = if metadata.len() == 0 {
+                0
+            } else {
+                end - start + 1
+            };
+            let body = stream::try_unfold(
+                (file.take(remaining), remaining),
+                |(mut file, remaining)| async move {
+                    if remaining == 0 {
+                        return Ok(None);
+                    }
+                    let mut buffer = vec![0; READ_CHUNK_SIZE.min(remaining as usize)];
+                    let read = file.read(&mut buffer).await?;
+                    if read == 0 {
+                        return Ok(None);
+                    }
+                    buffer.truncate(read);
+                    Ok(Some((Bytes::from(buffer), (file, remaining - read as u64))))
+                },
+            );
+            Ok(Some(Box::pin(body) as ObjectBody))
+        })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            match fs::remove_file(self.resolve(key)?).await {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+                _ => Ok(()),
+            }
+        })
+    }
+
+    fn list<'a>(&'a self, prefix: &'a str) -> BoxFuture<'a, io::Result<Vec<ObjectInfo>>> {
+        Box::pin(async move {
+            // Prefixes are directories here, e.g. `templates/acme/`
+            let key = prefix.trim_end_matches('/');
+            let mut objects = Vec::new();
+            list_dir(self.resolve(key)?, key.to_string(), &mut objects).await?;
+            Ok(objects)
+        })
+    }
+
+    fn presigned_url<'a>(
+        &'a self,
+        _key: &'a str,
+        _expires_in: Duration,
+    ) -> BoxFuture<'a, io::Result<Option<String>>> {
+        Box::pin(async { Ok(None) })
+    }
+
+    fn check(&self) -> BoxFuture<'_, Result<(), String>> {
+        Box::pin(async move {
+            let mut failed = Vec::new();
+            for (_, dir) in &self.roots {
+                let probe = dir.join(format!(".readyz-{}", Uuid::new_v4()));
+                match fs::write(&probe, b"ok").await {
+                    Ok(()) => {
+                        let _ = fs::remove_file(&probe).await;
+                    }
+                    Err(err) => failed.push(format!("{} is not writable: {}", dir.display(), err)),
+                }
+            }
+            if failed.is_empty() {
+                Ok(())
+            } else {
+                Err(failed.join("; "))
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Storage roots in a fresh directory, removed again by `cleanup`
+    fn test_storage() -> (LocalStorage, PathBuf) {
+        let base = std::env::temp_dir().join(format!("local-storage-{}", Uuid::new_v4()));
+        let dir = |name: &str| base.join(name).to_string_lossy().to_string();
+        let paths = PathsConfig {
+            temp: dir("temp"),
+            templates: dir("templates"),
+            reports: dir("reports"),
+            images: dir("images"),
+            datasets: dir("datasets"),
+        };
+        std::fs::create_dir_all(&paths.temp).unwrap();
+        (LocalStorage::new(&paths), base)
+    }
+
+    async fn put_bytes(storage: &LocalStorage, base: &Path, key: &str, bytes: &[u8]) {
+        let upload = base.join("temp").join(Uuid::new_v4().to_string());
+        fs::write(&upload, bytes).await.unwrap();
+        storage.put(key, &upload).await.unwrap();
+        assert!(!upload.exists());
+    }
+
+    fn keys(objects: Vec<ObjectInfo>) -> Vec<String> {
+        let mut keys: Vec<String> = objects.into_iter().map(|object| object.key).collect();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn resolves_keys_inside_their_root_only() {
+        let (storage, base) = test_storage();
+        assert_eq!(
+            storage.resolve("reports/acme/x.pdf").unwrap(),
+            base.join("reports/acme/x.pdf")
+        );
+        for key in [
+            "reports/../templates/x.html",
+            "reports/acme/../../x",
+            "reports/./x.pdf",
+            "reports//etc/passwd",
+            "/reports/x.pdf",
+            "/etc/passwd",
+            "secrets/x",
+            "..",
+        ] {
+            let err = storage.resolve(key).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{key}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn put_replaces_objects() {
+        let (storage, base) = test_storage();
+        put_bytes(&storage, &base, "templates/acme/a.html", b"<p>old</p>").await;
+        put_bytes(&storage, &base, "templates/acme/a.html", b"<p>new</p>").await;
+
+        assert_eq!(
+            storage.read("templates/acme/a.html").await.unwrap(),
+            Some(b"<p>new</p>".to_vec())
+        );
+        assert_eq!(
+            keys(storage.list("templates/acme/").await.unwrap()),
+            ["templates/acme/a.html"]
+        );
+        std::fs::remove_dir_all(base).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn lists_and_deletes_by_prefix() {
+        let (storage, base) = test_storage();
+        put_bytes(&storage, &base, "images/acme/1/original.png", b"a").await;
+        put_bytes(&storage, &base, "images/acme/1/thumbnail.png", b"bb").await;
+        put_bytes(&storage, &base, "images/acme2/2/original.png", b"c").await;
+        put_bytes(&storage, &base, "images/top.png", b"d").await;
+        // Left behind by an interrupted copy
+        std::fs::write(base.join("images/acme/.x.partial"), b"p").unwrap();
+
+        let listed = storage.list("images/acme/").await.unwrap();
+        assert_eq!(listed.iter().map(|object| object.size).sum::<u64>(), 3);
+        assert_eq!(
+            keys(listed),
+            ["images/acme/1/original.png", "images/acme/1/thumbnail.png"]
+        );
+        assert_eq!(keys(storage.list("images/acme").await.unwrap()).len(), 2);
+        assert_eq!(keys(storage.list("images/").await.unwrap()).len(), 4);
+        assert!(storage.list("images/missing/").await.unwrap().is_empty());
+        assert!(storage.list("images/../reports/").await.is_err());
+
+        storage.delete("images/acme/1/original.png").await.unwrap();
+        assert!(storage
+            .head("images/acme/1/original.png")
+            .await
+            .unwrap()
+            .is_none());
+        // Deleting what isn't there is fine, deleting a directory is not a thing
+        storage.delete("images/acme/1/original.png").await.unwrap();
+        assert!(storage.head("images/acme/1").await.unwrap().is_none());
+        assert_eq!(
+            keys(storage.list("images/acme/").await.unwrap()),
+            ["images/acme/1/thumbnail.png"]
+        );
+        std::fs::remove_dir_all(base).unwrap();
+    }
+}
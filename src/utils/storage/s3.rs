@@ -0,0 +1,236 @@
+use std::{io, path::Path, time::Duration, time::SystemTime};
+
+use aws_config::{BehaviorVersion, Region};
+use aws_sdk_s3::{
+    config::Credentials, error::ProvideErrorMetadata, presigning::PresigningConfig,
+    primitives::ByteStream, Client,
+};
+use futures::{future::BoxFuture, stream};
+use tokio::fs;
+
+use super::{content_type, ObjectBody, ObjectInfo, ObjectMeta, Storage};
+use crate::utils::setting::S3Config;
+
+/// Keeps objects in an S3 compatible bucket, e.g. AWS S3 or MinIO.
+pub struct S3Storage {
+    client: Client,
+    bucket: String,
+    prefix: String,
+}
+
+impl S3Storage {
+    pub async fn new(settings: &S3Config) -> Self {
+        // Without explicit keys the usual AWS sources are used: the environment, profiles
+        // and instance or task roles
+        let mut loader = aws_config::defaults(BehaviorVersion::latest())
+            .region(Region::new(settings.region.clone()));
+        if let (Some(access_key_id), Some(secret_access_key)) =
+            (&settings.access_key_id, &settings.secret_access_key)
+        {
+            loader = loader.credentials_provider(Credentials::new(
+                access_key_id,
+                secret_access_key,
+                None,
+                None,
+                "report_forge",
+            ));
+        }
+        if let Some(endpoint) = &settings.endpoint {
+            loader = loader.endpoint_url(endpoint);
+        }
+        let shared = loader.load().await;
+        let client_config = aws_sdk_s3::config::Builder::from(&shared)
+            .force_path_style(settings.force_path_style)
+            .build();
+
+        S3Storage {
+            client: Client::from_conf(client_config),
+            bucket: settings.bucket.clone(),
+            prefix: settings.prefix.trim_matches('/').to_string(),
+        }
+    }
+
+    fn object_key(&self, key: &str) -> String {
+        if self.prefix.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}/{}", self.prefix, key)
+        }
+    }
+}
+
+// The SDK's errors only say what failed at the top, the cause is further down the chain
+fn s3_error(err: impl std::error::Error + Send + Sync + 'static) -> io::Error {
+    let mut message = err.to_string();
+    let mut source = err.source();
+    while let Some(err) = source {
+        message = format!("{}: {}", message, err);
+        source = err.source();
+    }
+    io::Error::other(message)
+}
+
+impl Storage for S3Storage {
+    fn put<'a>(&'a self, key: &'a str, path: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let body = ByteStream::from_path(path).await.map_err(s3_error)?;
+            self.client
+                .put_object()
+                .bucket(&self.bucket)
+                .key(self.object_key(key))
+                .content_type(content_type(key))
+                .body(body)
+                .send()
+                .await
+                .map_err(s3_error)?;
+            fs::remove_file(path).await
+        })
+    }
+
+    fn head<'a>(&'a self, key: &'a str) -> BoxFuture<'a, io::Result<Option<ObjectMeta>>> {
+        Box::pin(async move {
+            match self
+                .client
+                .head_object()
+                .bucket(&self.bucket)
+                .key(self.object_key(key))
+                .send()
+                .await
+            {
+                Ok(output) => Ok(Some(ObjectMeta {
+                    size: output.content_length.unwrap_or_default().max(0) as u64,
+                    content_type: output.content_type.unwrap_or_else(|| content_type(key)),
+                    last_modified: output
+                        .last_modified
+                        .and_then(|modified| SystemTime::try_from(modified).ok()),
+                    etag: output.e_tag,
+                })),
+                Err(err) if err.as_service_error().is_some_and(|err| err.is_not_found()) => {
+                    Ok(None)
+                }
+                Err(err) => Err(s3_error(err)),
+            }
+        })
+    }
+
+    fn get<'a>(
+        &'a self,
+        key: &'a str,
+        range: Option<(u64, u64)>,
+    ) -> BoxFuture<'a, io::Result<Option<ObjectBody>>> {
+        Box::pin(async move {
+            let output = match self
+                .client
+                .get_object()
+                .bucket(&self.bucket)
+                .key(self.object_key(key))
+                .set_range(range.map(|(start, end)| format!("bytes={}-{}", start, end)))
+                .send()
+                .await
+            {
+                Ok(output) => output,
+                Err(err)
+                    if err
+                        .as_service_error()
+                        .is_some_and(|err| err.is_no_such_key()) =>
+                {
+                    return Ok(None)
+                }
+                Err(err) => return Err(s3_error(err)),
+            };
+            let body = stream::try_unfold(output.body, |mut body| async move {
+                match body.try_next().await {
+                    Ok(Some(chunk)) => Ok(Some((chunk, body))),
+                    Ok(None) => Ok(None),
+                    Err(err) => Err(s3_error(err)),
+                }
+            });
+            Ok(Some(Box::pin(body) as ObjectBody))
+        })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            self.client
+                .delete_object()
+                .bucket(&self.bucket)
+                .key(self.object_key(key))
+                .send()
+                .await
+                .map_err(s3_error)?;
+            Ok(())
+        })
+    }
+
+    fn list<'a>(&'a self, prefix: &'a str) -> BoxFuture<'a, io::Result<Vec<ObjectInfo>>> {
+        Box::pin(async move {
+            let strip = if self.prefix.is_empty() {
+                0
+            } else {
+                self.prefix.len() + 1
+            };
+            let mut pages = self
+                .client
+                .list_objects_v2()
+                .bucket(&self.bucket)
+                .prefix(self.object_key(prefix))
+                .into_paginator()
+                .send();
+            let mut objects = Vec::new();
+            while let Some(page) = pages.next().await {
+                for object in page.map_err(s3_error)?.contents() {
+                    let Some(key) = object.key() else {
+                        continue;
+                    };
+                    objects.push(ObjectInfo {
+                        key: key[strip..].to_string(),
+                        size: object.size().unwrap_or_default().max(0) as u64,
+                        last_modified: object
+                            .last_modified()
+                            .and_then(|modified| SystemTime::try_from(*modified).ok()),
+                    });
+                }
+            }
+            Ok(objects)
+        })
+    }
+
+    fn presigned_url<'a>(
+        &'a self,
+        key: &'a str,
+        expires_in: Duration,
+    ) -> BoxFuture<'a, io::Result<Option<String>>> {
+        Box::pin(async move {
+            let presigning = PresigningConfig::expires_in(expires_in).map_err(s3_error)?;
+            let request = self
+                .client
+                .get_object()
+                .bucket(&self.bucket)
+                .key(self.object_key(key))
+                .presigned(presigning)
+                .await
+                .map_err(s3_error)?;
+            Ok(Some(request.uri().to_string()))
+        })
+    }
+
+    fn check(&self) -> BoxFuture<'_, Result<(), String>> {
+        Box::pin(async move {
+            self.client
+                .head_bucket()
+                .bucket(&self.bucket)
+                .send()
+                .await
+                .map_err(|err| match err.as_service_error() {
+                    // HEAD responses have no body, so the code is all there is
+                    Some(err) => format!(
+                        "bucket {} is not available: {}",
+                        self.bucket,
+                        err.code().unwrap_or("unknown error")
+                    ),
+                    None => s3_error(err).to_string(),
+                })?;
+            Ok(())
+        })
+    }
+}
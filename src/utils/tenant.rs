@@ -0,0 +1,306 @@
+use std::{collections::HashMap, error::Error, sync::Mutex};
+
+use deadpool_postgres::Pool;
+use serde::{Deserialize, Serialize};
+
+use crate::utils::{error::AppError, setting::create_postgres_pool, storage::Storage};
+
+// Storage roots that are split per tenant
+const TENANT_ROOTS: [&str; 4] = ["templates", "images", "reports", "datasets"];
+
+// Source name used for the query cache when a tenant has no data source of its own
+pub const DEFAULT_SOURCE: &str = "default";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Tenant {
+    pub id: String,
+    pub name: String,
+    pub storage_prefix: String,
+    // Connection string of the tenant's own database, `None` uses the default one
+    #[serde(skip_serializing)]
+    pub db_connection: Option<String>,
+    pub max_templates: Option<i32>,
+    pub max_storage_bytes: Option<i64>,
+}
+
+// Tenant ids and storage prefixes are used as directory names
+pub fn is_valid_tenant_id(id: &str) -> bool {
+    !id.is_empty()
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Returns the storage key of a tenant's object under `root`, e.g. `reports/acme/x.pdf`.
+pub fn tenant_key(tenant: Option<&Tenant>, root: &str, name: &str) -> String {
+    match tenant {
+        Some(tenant) => format!("{}/{}/{}", root, tenant.storage_prefix, name),
+        None => format!("{}/{}", root, name),
+    }
+}
+
+/// Qualifies a name, e.g. a template name, with the tenant it belongs to.
+pub fn scoped_name(tenant: Option<&Tenant>, name: &str) -> String {
+    match tenant {
+        Some(tenant) => format!("{}/{}", tenant.id, name),
+        None => name.to_string(),
+    }
+}
+
+/// Checks the tenant's storage quota, which counts every object under the tenant's prefixes.
+pub async fn check_storage_quota(
+    storage: &dyn Storage,
+    tenant: Option<&Tenant>,
+) -> Result<(), AppError> {
+    let Some(tenant) = tenant else {
+        return Ok(());
+    };
+    if let Some(max_bytes) = tenant.max_storage_bytes {
+        let mut used = 0;
+        for root in TENANT_ROOTS {
+            let prefix = format!("{}/{}/", root, tenant.storage_prefix);
+            used += storage
+                .list(&prefix)
+                .await?
+                .iter()
+                .map(|object| object.size)
+                .sum::<u64>();
+        }
+        if used >= max_bytes as u64 {
+            return Err(AppError::QuotaExceeded(format!(
+                "Storage quota of {} bytes exceeded for tenant {}!",
+                max_bytes, tenant.id
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Checks the tenant's template count quota before another template is added.
+pub async fn check_template_quota(
+    storage: &dyn Storage,
+    tenant: Option<&Tenant>,
+) -> Result<(), AppError> {
+    let Some(tenant) = tenant else {
+        return Ok(());
+    };
+    if let Some(max_templates) = tenant.max_templates {
+        let prefix = format!("templates/{}/", tenant.storage_prefix);
+        let count = storage.list(&prefix).await?.len();
+        if count >= max_templates as usize {
+            return Err(AppError::QuotaExceeded(format!(
+                "Template quota of {} exceeded for tenant {}!",
+                max_templates, tenant.id
+            )));
+        }
+    }
+    check_storage_quota(storage, Some(tenant)).await
+}
+
+pub async fn init_schema(pool: &Pool) -> Result<(), Box<dyn Error>> {
+    let client = pool.get().await?;
+    client
+        .batch_execute(
+            "CREATE TABLE IF NOT EXISTS tenants (
+                id text PRIMARY KEY,
+                name text NOT NULL,
+                storage_prefix text NOT NULL UNIQUE,
+                db_connection text,
+                max_templates integer,
+                max_storage_bytes bigint,
+                created_at timestamptz NOT NULL DEFAULT now()
+            )",
+        )
+        .await?;
+    Ok(())
+}
+
+pub async fn get_tenant(pool: &Pool, id: &str) -> Result<Option<Tenant>, Box<dyn Error>> {
+    let client = pool.get().await?;
+    let row = client
+        .query_opt(
+            "SELECT id, name, storage_prefix, db_connection, max_templates, max_storage_bytes
+             FROM tenants WHERE id = $1",
+            &[&id],
+        )
+        .await?;
+    Ok(row.map(|row| Tenant {
+        id: row.get("id"),
+        name: row.get("name"),
+        storage_prefix: row.get("storage_prefix"),
+        db_connection: row.get("db_connection"),
+        max_templates: row.get("max_templates"),
+        max_storage_bytes: row.get("max_storage_bytes"),
+    }))
+}
+
+// Tables of the service itself, which template SQL must never reach
+const SERVICE_TABLES: [&str; 5] = [
+    "api_keys",
+    "tenants",
+    "used_signatures",
+    "audit_log",
+    "daily_usage",
+];
+
+/// Returns the service tables the role of `pool` can read, change or alter. Template SQL runs
+/// with that role, so it has to be empty.
+pub async fn accessible_service_tables(pool: &Pool) -> Result<Vec<String>, Box<dyn Error>> {
+    let client = pool.get().await?;
+    let rows = client
+        .query(
+            "SELECT DISTINCT c.relname::text FROM pg_class c
+             WHERE c.relkind IN ('r', 'p') AND c.relname = ANY($1)
+               AND has_table_privilege(c.oid, 'SELECT, INSERT, UPDATE, DELETE, TRUNCATE, TRIGGER')
+             ORDER BY 1",
+            &[&SERVICE_TABLES.as_slice()],
+        )
+        .await?;
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+}
+
+/// Connection pools for `{{#sql}}` blocks: the template connection shared by tenants without
+/// a database of their own, and one per tenant that has one. The service's own pool is never
+/// used, its tables must stay out of reach of templates.
+pub struct DataSources {
+    default: Option<Pool>,
+    tenants: Mutex<HashMap<String, (String, Pool)>>,
+}
+
+impl DataSources {
+    pub fn new(default: Option<Pool>) -> Self {
+        DataSources {
+            default,
+            tenants: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the data source name and pool the tenant's templates query. The pool is `None`
+    /// if there is no data source for the tenant, `{{#sql}}` blocks then fail.
+    pub fn get(&self, tenant: Option<&Tenant>) -> Result<(String, Option<Pool>), AppError> {
+        let (tenant, conn) = match tenant {
+            Some(tenant) => match &tenant.db_connection {
+                Some(conn) => (tenant, conn),
+                None => return Ok((DEFAULT_SOURCE.to_string(), self.default.clone())),
+            },
+            None => return Ok((DEFAULT_SOURCE.to_string(), self.default.clone())),
+        };
+
+        let mut tenants = self.tenants.lock().unwrap();
+        // A changed connection string replaces the tenant's pool
+        if let Some((cached_conn, pool)) = tenants.get(&tenant.id) {
+            if cached_conn == conn {
+                return Ok((tenant.id.clone(), Some(pool.clone())));
+            }
+        }
+        let pool = create_postgres_pool(conn).map_err(|err| {
+            AppError::DataSource(format!(
+                "Data source of tenant {} is not configured correctly: {}",
+                tenant.id, err
+            ))
+        })?;
+        tenants.insert(tenant.id.clone(), (conn.clone(), pool.clone()));
+        Ok((tenant.id.clone(), Some(pool)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path, path::PathBuf};
+
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::utils::{setting::PathsConfig, storage::LocalStorage};
+
+    struct Fixture {
+        storage: LocalStorage,
+        paths: PathsConfig,
+        base: PathBuf,
+    }
+
+    impl Fixture {
+        fn new() -> Self {
+            let base = std::env::temp_dir().join(format!("tenant-{}", Uuid::new_v4()));
+            let dir = |name: &str| base.join(name).to_string_lossy().to_string();
+            let paths = PathsConfig {
+                temp: dir("temp"),
+                templates: dir("templates"),
+                reports: dir("reports"),
+                images: dir("images"),
+                datasets: dir("datasets"),
+            };
+            fs::create_dir_all(&paths.temp).unwrap();
+            Fixture {
+                storage: LocalStorage::new(&paths),
+                paths,
+                base,
+            }
+        }
+
+        async fn put(&self, key: &str, size: usize) {
+            let upload = format!("{}/{}", self.paths.temp, Uuid::new_v4());
+            fs::write(&upload, vec![b'x'; size]).unwrap();
+            self.storage.put(key, Path::new(&upload)).await.unwrap();
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.base);
+        }
+    }
+
+    fn tenant(max_templates: Option<i32>, max_storage_bytes: Option<i64>) -> Tenant {
+        Tenant {
+            id: String::from("acme"),
+            name: String::from("Acme"),
+            storage_prefix: String::from("acme"),
+            db_connection: None,
+            max_templates,
+            max_storage_bytes,
+        }
+    }
+
+    #[actix_web::test]
+    async fn counts_only_the_tenants_templates() {
+        let fixture = Fixture::new();
+        let tenant = tenant(Some(2), None);
+        let check = || check_template_quota(&fixture.storage, Some(&tenant));
+
+        fixture.put("templates/acme/a.html", 1).await;
+        fixture.put("templates/acme2/b.html", 1).await;
+        fixture.put("templates/c.html", 1).await;
+        assert!(check().await.is_ok());
+
+        fixture.put("templates/acme/b.html", 1).await;
+        assert!(matches!(check().await, Err(AppError::QuotaExceeded(_))));
+
+        fixture
+            .storage
+            .delete("templates/acme/b.html")
+            .await
+            .unwrap();
+        assert!(check().await.is_ok());
+        // Requests without a tenant have no quota
+        assert!(check_template_quota(&fixture.storage, None).await.is_ok());
+    }
+
+    #[actix_web::test]
+    async fn counts_stored_objects_and_datasets_against_the_storage_quota() {
+        let fixture = Fixture::new();
+        let tenant = tenant(None, Some(100));
+        let check = || check_storage_quota(&fixture.storage, Some(&tenant));
+
+        fixture.put("reports/acme/a.pdf", 40).await;
+        fixture.put("images/acme/1/original.png", 30).await;
+        fixture.put("reports/acme2/b.pdf", 500).await;
+        assert!(check().await.is_ok());
+
+        fixture.put("datasets/acme/sales.json", 30).await;
+        assert!(matches!(check().await, Err(AppError::QuotaExceeded(_))));
+
+        fixture.storage.delete("reports/acme/a.pdf").await.unwrap();
+        assert!(check().await.is_ok());
+    }
+}
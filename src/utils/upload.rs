@@ -0,0 +1,120 @@
+use std::io::Write;
+
+use actix_multipart::Field;
+use actix_web::web;
+use futures::StreamExt;
+
+use crate::utils::error::AppError;
+
+// Longer names can't be stored on most file systems once they get an id prefix
+const MAX_FILENAME_LEN: usize = 200;
+
+/// Returns the client supplied file name of an upload if it is safe to use as the last
+/// segment of a storage key: no path separators, control characters or leading dots.
+pub fn sanitize_filename(name: Option<&str>) -> Result<String, AppError> {
+    let name = name
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .ok_or_else(|| AppError::InvalidRequest(String::from("Uploaded file has no name!")))?;
+    if name.contains(['/', '\\']) || name.chars().any(char::is_control) {
+        return Err(AppError::InvalidRequest(String::from(
+            "File name may not contain path separators or control characters!",
+        )));
+    }
+    if name.starts_with('.') {
+        return Err(AppError::InvalidRequest(String::from(
+            "File name may not start with a dot!",
+        )));
+    }
+    if name.len() > MAX_FILENAME_LEN {
+        return Err(AppError::InvalidRequest(format!(
+            "File name may be at most {} bytes long!",
+            MAX_FILENAME_LEN
+        )));
+    }
+    Ok(name.to_string())
+}
+
+/// Streams an uploaded field into a new file at `path`, failing with `413` as soon as it
+/// grows beyond `limit` bytes. A partially written file is left for the caller to remove.
+pub async fn write_field(field: &mut Field, path: &str, limit: u64) -> Result<u64, AppError> {
+    let path = path.to_string();
+    let mut file = web::block(move || std::fs::File::create(path)).await??;
+    let mut written = 0u64;
+    while let Some(chunk) = field.next().await {
+        let data = chunk?;
+        written += data.len() as u64;
+        if written > limit {
+            return Err(AppError::PayloadTooLarge { limit });
+        }
+        file = web::block(move || file.write_all(&data).map(|_| file)).await??;
+    }
+    Ok(written)
+}
+
+/// Reads an uploaded field into memory, failing with `413` beyond `limit` bytes.
+pub async fn read_field(field: &mut Field, limit: u64) -> Result<Vec<u8>, AppError> {
+    let mut bytes = Vec::new();
+    while let Some(chunk) = field.next().await {
+        let data = chunk?;
+        if (bytes.len() + data.len()) as u64 > limit {
+            return Err(AppError::PayloadTooLarge { limit });
+        }
+        bytes.extend_from_slice(&data);
+    }
+    Ok(bytes)
+}
+
+/// Checks that an uploaded template is an HTML file: named `.html` or `.htm`, UTF-8 and
+/// containing markup.
+pub fn validate_template(name: &str, contents: &[u8]) -> Result<(), AppError> {
+    let lower = name.to_lowercase();
+    if !(lower.ends_with(".html") || lower.ends_with(".htm")) {
+        return Err(AppError::InvalidRequest(String::from(
+            "Templates must be .html or .htm files!",
+        )));
+    }
+    let text = std::str::from_utf8(contents)
+        .map_err(|err| AppError::InvalidRequest(format!("Template is not valid UTF-8: {}", err)))?;
+    if text.contains('\0') || !looks_like_html(text) {
+        return Err(AppError::InvalidRequest(String::from(
+            "Template does not contain HTML!",
+        )));
+    }
+    Ok(())
+}
+
+// Some tag, comment or doctype: `<` followed by a letter, `/` or `!`
+fn looks_like_html(text: &str) -> bool {
+    text.as_bytes().windows(2).any(|pair| {
+        pair[0] == b'<' && (pair[1].is_ascii_alphabetic() || matches!(pair[1], b'/' | b'!'))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_unsafe_file_names() {
+        assert_eq!(
+            sanitize_filename(Some("report.html")).unwrap(),
+            "report.html"
+        );
+        assert!(sanitize_filename(None).is_err());
+        assert!(sanitize_filename(Some("  ")).is_err());
+        assert!(sanitize_filename(Some("../etc/passwd")).is_err());
+        assert!(sanitize_filename(Some("a\\b.html")).is_err());
+        assert!(sanitize_filename(Some("a\nb.html")).is_err());
+        assert!(sanitize_filename(Some(".hidden.html")).is_err());
+    }
+
+    #[test]
+    fn accepts_only_utf8_html_templates() {
+        assert!(validate_template("a.html", b"<h1>{{title}}</h1>").is_ok());
+        assert!(validate_template("a.HTM", b"<!DOCTYPE html>").is_ok());
+        assert!(validate_template("a.txt", b"<p>hi</p>").is_err());
+        assert!(validate_template("a.html", b"just text").is_err());
+        assert!(validate_template("a.html", b"<p>\xff</p>").is_err());
+    }
+}
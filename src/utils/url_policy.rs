@@ -0,0 +1,238 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tokio::net::lookup_host;
+use url::Url;
+
+use crate::utils::setting::{config, UrlPolicyConfig};
+
+// Paths of the server itself a rendered page may load, the public image and file urls
+const INTERNAL_PATH_PREFIXES: [&str; 2] = ["/images/", "/storage/"];
+
+/// Decides which URLs the renderer may load, both for the page itself and its subresources.
+pub struct UrlPolicy {
+    allowed_schemes: Vec<String>,
+    // An empty list allows every host that isn't denied
+    allowed_hosts: Vec<String>,
+    denied_hosts: Vec<String>,
+    allow_private_networks: bool,
+    // Scheme, host and port of `renderer.internal_base_url`, usually on a private address
+    internal_origin: Option<(String, String, u16)>,
+}
+
+// Patterns are either exact host names or `*.example.com` for every subdomain
+fn host_matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host
+            .strip_suffix(domain)
+            .is_some_and(|sub| sub.ends_with('.')),
+        None => pattern == host,
+    }
+}
+
+fn is_private_ipv4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        || a == 0
+        // Carrier grade NAT, 100.64.0.0/10
+        || (a == 100 && (64..128).contains(&b))
+        // Benchmarking, 198.18.0.0/15
+        || (a == 198 && (18..20).contains(&b))
+        || a >= 240
+}
+
+// The IPv4 address in the last 32 bits of an IPv6 address
+fn last_ipv4(ip: Ipv6Addr) -> Ipv4Addr {
+    let octets = ip.octets();
+    Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15])
+}
+
+fn is_private_ipv6(ip: Ipv6Addr) -> bool {
+    if ip.is_loopback() || ip.is_unspecified() {
+        return true;
+    }
+    let segments = ip.segments();
+    // IPv4 mapped and the deprecated IPv4 compatible addresses, ::ffff:0:0/96 and ::/96
+    if let Some(ipv4) = ip.to_ipv4() {
+        return is_private_ipv4(ipv4);
+    }
+    // NAT64 with the well known prefix reaches the embedded address, 64:ff9b::/96
+    if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+        return is_private_ipv4(last_ipv4(ip));
+    }
+    // 6to4 embeds the IPv4 address of the relay in the second and third segment, 2002::/16
+    if segments[0] == 0x2002 {
+        let [a, b] = segments[1].to_be_bytes();
+        let [c, d] = segments[2].to_be_bytes();
+        return is_private_ipv4(Ipv4Addr::new(a, b, c, d));
+    }
+    let first = segments[0];
+    ip.is_multicast()
+        // NAT64 for local networks, 64:ff9b:1::/48
+        || segments[..3] == [0x64, 0xff9b, 1]
+        // Unique local, fc00::/7
+        || (first & 0xfe00) == 0xfc00
+        // Link local and the deprecated site local ranges, fe80::/10 and fec0::/10
+        || (first & 0xffc0) == 0xfe80
+        || (first & 0xffc0) == 0xfec0
+}
+
+pub fn is_private_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_private_ipv4(ip),
+        IpAddr::V6(ip) => is_private_ipv6(ip),
+    }
+}
+
+impl UrlPolicy {
+    pub fn from_settings() -> Self {
+        UrlPolicy::new(&config().url_policy, &config().internal_base_url())
+    }
+
+    pub fn new(settings: &UrlPolicyConfig, internal_base_url: &str) -> Self {
+        let internal_origin = Url::parse(internal_base_url).ok().and_then(|url| {
+            let host = url.host_str()?.to_lowercase();
+            let port = url.port_or_known_default()?;
+            Some((url.scheme().to_string(), host, port))
+        });
+        UrlPolicy {
+            allowed_schemes: settings.allowed_schemes.clone(),
+            allowed_hosts: settings.allowed_hosts.clone(),
+            denied_hosts: settings.denied_hosts.clone(),
+            allow_private_networks: settings.allow_private_networks,
+            internal_origin,
+        }
+    }
+
+    /// Whether `url` is one of the public image or file urls of this server, which the
+    /// renderer may load from the internal base url even when it is on a private address.
+    fn is_internal(&self, url: &Url) -> bool {
+        let Some((scheme, host, port)) = &self.internal_origin else {
+            return false;
+        };
+        url.scheme() == scheme
+            && url.host_str().is_some_and(|h| h.eq_ignore_ascii_case(host))
+            && url.port_or_known_default() == Some(*port)
+            && INTERNAL_PATH_PREFIXES
+                .iter()
+                .any(|prefix| url.path().starts_with(prefix))
+    }
+
+    fn check_host(&self, scheme: &str, host: &str) -> Result<(), String> {
+        if !self.allowed_schemes.iter().any(|s| s == scheme) {
+            return Err(format!("Scheme {} is not allowed!", scheme));
+        }
+        let host = host.trim_end_matches('.').to_lowercase();
+        if self.denied_hosts.iter().any(|p| host_matches(p, &host)) {
+            return Err(format!("Host {} is denied!", host));
+        }
+        if !self.allowed_hosts.is_empty()
+            && !self.allowed_hosts.iter().any(|p| host_matches(p, &host))
+        {
+            return Err(format!("Host {} is not allowed!", host));
+        }
+        Ok(())
+    }
+
+    /// Checks the scheme and host, then resolves the host and checks every address it
+    /// resolves to. The returned addresses are the ones that must be connected to, so a
+    /// second lookup can't be answered with a different, private address.
+    pub async fn resolve(
+        &self,
+        scheme: &str,
+        host: &str,
+        port: u16,
+    ) -> Result<Vec<SocketAddr>, String> {
+        self.check_host(scheme, host)?;
+
+        // IPv6 literals keep their brackets in URLs
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let addrs = lookup(host, port).await?;
+        if !self.allow_private_networks {
+            if let Some(addr) = addrs.iter().find(|addr| is_private_ip(addr.ip())) {
+                return Err(format!(
+                    "Host {} resolves to the private address {}!",
+                    host,
+                    addr.ip()
+                ));
+            }
+        }
+        Ok(addrs)
+    }
+
+    /// Like `resolve`, but also allows the public paths of the internal base url.
+    pub async fn resolve_url(&self, url: &Url) -> Result<Vec<SocketAddr>, String> {
+        let (Some(host), Some(port)) = (url.host_str(), url.port_or_known_default()) else {
+            return Err(String::from("Url must have a host!"));
+        };
+        if self.is_internal(url) {
+            return lookup(host.trim_start_matches('[').trim_end_matches(']'), port).await;
+        }
+        self.resolve(url.scheme(), host, port).await
+    }
+
+    pub async fn check_url(&self, url: &str) -> Result<(), String> {
+        let url = Url::parse(url).map_err(|_| String::from("Url is invalid!"))?;
+        self.resolve_url(&url).await.map(|_| ())
+    }
+}
+
+async fn lookup(host: &str, port: u16) -> Result<Vec<SocketAddr>, String> {
+    let addrs: Vec<SocketAddr> = match lookup_host((host, port)).await {
+        Ok(addrs) => addrs.collect(),
+        Err(_) => return Err(format!("Host {} could not be resolved!", host)),
+    };
+    if addrs.is_empty() {
+        return Err(format!("Host {} could not be resolved!", host));
+    }
+    Ok(addrs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn private(ip: &str) -> bool {
+        is_private_ip(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn detects_private_addresses_embedded_in_ipv6() {
+        assert!(private("127.0.0.1"));
+        assert!(private("::1"));
+        assert!(private("::ffff:10.0.0.1"));
+        assert!(private("::10.0.0.1"));
+        assert!(private("64:ff9b::7f00:1"));
+        assert!(private("64:ff9b::a9fe:a9fe"));
+        assert!(private("64:ff9b:1::808:808"));
+        assert!(private("2002:c0a8:101::1"));
+        assert!(private("2002:7f00:1::"));
+        assert!(private("fd00::1"));
+        assert!(private("fe80::1"));
+        assert!(private("ff02::1"));
+
+        assert!(!private("8.8.8.8"));
+        assert!(!private("::ffff:8.8.8.8"));
+        assert!(!private("64:ff9b::808:808"));
+        assert!(!private("2002:808:808::1"));
+        assert!(!private("2606:4700::1111"));
+    }
+
+    #[test]
+    fn allows_only_public_paths_of_the_internal_origin() {
+        let policy = UrlPolicy::new(&UrlPolicyConfig::default(), "http://localhost:8080");
+        let internal = |url: &str| policy.is_internal(&Url::parse(url).unwrap());
+        assert!(internal("http://localhost:8080/images/logo.png"));
+        assert!(internal("http://LOCALHOST:8080/storage/a.css"));
+        assert!(!internal("http://localhost:8080/api/report"));
+        assert!(!internal("http://localhost:8080/reports/a.pdf"));
+        assert!(!internal("http://localhost:8081/images/logo.png"));
+        assert!(!internal("https://localhost:8080/images/logo.png"));
+        assert!(!internal("http://127.0.0.1:8080/images/logo.png"));
+    }
+}